@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use shared::types as t;
 
 #[derive(Debug, Subcommand)]
@@ -10,17 +10,87 @@ pub(crate) enum Commands {
     /// Brief status and summary of EkaCI
     Status,
     /// Ask server to attempt to build a drv
-    Build(t::BuildRequest),
+    ///
+    /// With --wait, exits with 0 if the build succeeded, 2 if it failed and 3 if it was
+    /// interrupted.
+    Build(BuildCommand),
+    /// Ask server to evaluate a jobset and build the drvs it exposes
+    ///
+    /// With --wait, exits with 0 if all builds succeeded, 2 if the evaluation or a build failed
+    /// and 3 if a build was interrupted.
+    Job(JobCommand),
 
-    Job(t::JobRequest),
+    /// Start a new build attempt of a failed or interrupted drv
+    Restart(t::DrvRequest),
+    /// Cancel the latest build attempt of a drv, whether it is running or not
+    Cancel(t::DrvRequest),
+    /// Mark the result of a completed build as invalid and build the drv again
+    Invalidate(t::InvalidateRequest),
+    /// Restart all failed and interrupted builds of an evaluation
+    RestartFailures(t::RestartFailuresRequest),
+    /// Print the build log of a drv
+    Log(t::LogRequest),
+    /// Follow a drv or job until its builds end
+    ///
+    /// Exits with 0 if all builds succeeded, 2 if one failed and 3 if one was interrupted.
+    Watch(t::WatchRequest),
+    /// Show the dependencies that made a drv fail or block, with the end of their logs
+    WhyFailed(t::WhyFailedRequest),
 }
 
+#[derive(clap::Args, Debug)]
+pub(crate) struct BuildCommand {
+    #[command(flatten)]
+    pub request: t::BuildRequest,
+
+    /// Follow the build until it ends, returns right away if the drv was built before
+    #[arg(long)]
+    pub wait: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct JobCommand {
+    #[command(flatten)]
+    pub request: t::JobRequest,
+
+    /// Follow the evaluation and the builds of the drvs it exposes until they end
+    #[arg(long)]
+    pub wait: bool,
+}
+
+/// Exit codes shared by all commands, see [`crate::watch::exit_code`].
+const EXIT_STATUS: &str = "Exit status: 0 on success, 1 if the request failed, 2 if a build or \
+evaluation waited for failed and 3 if a build waited for was interrupted.";
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None, arg_required_else_help = true)]
+#[command(version, about, long_about = None, arg_required_else_help = true, after_help = EXIT_STATUS)]
 pub(crate) struct Args {
     #[command(subcommand)]
     pub command: Option<Commands>,
 
-    #[arg(short, long)]
+    /// Socket of a server on this machine. Defaults to $XDG_RUNTIME_DIR/ekaci/ekaci.socket.
+    #[arg(short, long, conflicts_with = "server")]
     pub socket: Option<PathBuf>,
+
+    /// URL of the web service of a server on another machine, e.g. https://ci.example.org.
+    /// Its token is read from the config file or $EKACI_TOKEN.
+    #[arg(long)]
+    pub server: Option<String>,
+
+    /// Path of the config file. Defaults to $XDG_CONFIG_HOME/ekaci/client.toml.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Format of everything printed to stdout
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Human readable text, which may change between versions
+    Text,
+    /// One JSON object per line, each a response of the server as sent over the socket. A
+    /// failure is reported as a last `{"type":"Error","message":...}` object.
+    Json,
 }
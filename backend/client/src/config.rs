@@ -0,0 +1,71 @@
+//! Settings from the client config file, `$XDG_CONFIG_HOME/ekaci/client.toml` by default.
+//!
+//! ```toml
+//! # Talk to the shared server instead of the local socket
+//! server = "https://ci.example.org"
+//! token = "..."
+//! ```
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+use shared::dirs::eka_dirs;
+use tracing::{debug, warn};
+
+const CONFIG_FILE: &str = "client.toml";
+
+/// Environment variable overriding the token of the config file.
+pub const TOKEN_ENV: &str = "EKACI_TOKEN";
+
+/// Every setting can be overridden on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Socket of a server on this machine
+    pub socket: Option<PathBuf>,
+    /// URL of the web service of a server on another machine, used instead of a socket
+    pub server: Option<String>,
+    /// Token the server's operators handed out, sent as bearer token to `server`
+    pub token: Option<String>,
+}
+
+impl ClientConfig {
+    /// Read the config file at `path`, or the default one if it exists.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match eka_dirs().find_config_file(CONFIG_FILE) {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        debug!("Loading configuration file from {}", path.display());
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let config: Self = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+        if config.token.is_some() {
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                warn!(
+                    "{} contains a token, but can be read by other users, consider `chmod 600`",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The token to authenticate with, the environment takes precedence over the file.
+    pub fn token(&self) -> Option<String> {
+        std::env::var(TOKEN_ENV)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .or_else(|| self.token.clone())
+    }
+}
@@ -1,19 +1,30 @@
 mod cli;
+mod config;
+mod remote;
 mod requests;
+mod status;
+mod watch;
+mod why_failed;
 
 use anyhow::Context;
 use clap::Parser;
 use cli::Commands;
-use requests::send_request;
+use config::ClientConfig;
+use remote::Remote;
+use requests::{send_request, Server};
 use shared::dirs::eka_dirs;
 use shared::types as t;
 use shared::types::ClientRequest;
+use std::path::Path;
+use std::process::ExitCode;
 use tracing::debug;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+    // Keep stdout free for the output of commands, which may be parsed by scripts
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_env_filter(
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::INFO.into())
@@ -26,40 +37,99 @@ fn main() -> anyhow::Result<()> {
         .init();
 
     let args = cli::Args::parse();
-    let socket = args.socket.map_or_else(
-        || {
-            eka_dirs().get_runtime_file("ekaci.socket").context(
-                "failed to determine default path for unix socket, consider setting it explicitly",
-            )
-        },
-        Result::Ok,
-    )?;
+    let output = args.output;
+    requests::exit_code(output, run(args))
+}
+
+fn run(args: cli::Args) -> anyhow::Result<ExitCode> {
+    let output = args.output;
+    let config = ClientConfig::load(args.config.as_deref())?;
+    let server = if let Some(socket) = args.socket {
+        Server::Socket(socket)
+    } else if let Some(url) = args.server.as_ref().or(config.server.as_ref()) {
+        Server::Remote(Remote::new(url, config.token())?)
+    } else {
+        Server::Socket(config.socket.map_or_else(
+            || {
+                eka_dirs().get_runtime_file("ekaci.socket").context(
+                    "failed to determine default path for unix socket, consider setting it \
+                     explicitly",
+                )
+            },
+            Result::Ok,
+        )?)
+    };
 
     match args.command {
         Some(Commands::Info) => {
-            send_request(&socket, ClientRequest::Info)
+            send_request(&server, ClientRequest::Info, output)
                 .context("failed to send info request to server")?;
         }
-        Some(Commands::Status) => {}
-        Some(Commands::Build(build_req)) => {
-            send_request(&socket, ClientRequest::Build(build_req))
-                .context("failed to send info request to server")?;
+        Some(Commands::Status) => {
+            send_request(&server, ClientRequest::Status, output)
+                .context("failed to send status request to server")?;
+        }
+        Some(Commands::Build(cmd)) => {
+            return watch::build(&server, cmd.request, cmd.wait, output)
+                .context("failed to send build request to server");
         }
-        Some(Commands::Job(req)) => {
-            let abs_file_path = std::fs::canonicalize(req.file_path)?
-                .as_path()
-                .to_str()
-                .unwrap()
-                .to_string();
+        Some(Commands::Job(cmd)) => {
             let abs_req = t::JobRequest {
-                file_path: abs_file_path,
+                file_path: jobset_path(&server, &cmd.request.file_path)?,
+                pr: cmd.request.pr,
             };
             debug!("Requesting job eval: {:?}", &abs_req);
-            send_request(&socket, ClientRequest::Job(abs_req))
-                .context("failed to send info request to server")?;
+            return watch::job(&server, abs_req, cmd.wait, output)
+                .context("failed to send job request to server");
+        }
+        Some(Commands::Restart(req)) => {
+            send_request(&server, ClientRequest::Restart(req), output)
+                .context("failed to send restart request to server")?;
+        }
+        Some(Commands::Cancel(req)) => {
+            send_request(&server, ClientRequest::Cancel(req), output)
+                .context("failed to send cancel request to server")?;
+        }
+        Some(Commands::Invalidate(req)) => {
+            send_request(&server, ClientRequest::Invalidate(req), output)
+                .context("failed to send invalidate request to server")?;
+        }
+        Some(Commands::RestartFailures(req)) => {
+            send_request(&server, ClientRequest::RestartFailures(req), output)
+                .context("failed to send restart request to server")?;
+        }
+        Some(Commands::Log(req)) => {
+            send_request(&server, ClientRequest::Log(req), output)
+                .context("failed to send log request to server")?;
+        }
+        Some(Commands::Watch(mut req)) => {
+            if let Some(job) = &req.job {
+                req.job = Some(jobset_path(&server, job)?);
+            }
+            return watch::watch(&server, req, output).context("failed to watch builds");
+        }
+        Some(Commands::WhyFailed(req)) => {
+            send_request(&server, ClientRequest::WhyFailed(req), output)
+                .context("failed to send why-failed request to server")?;
         }
         None => {}
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Jobsets are identified by the absolute path they were evaluated from, on the machine of the
+/// server.
+fn jobset_path(server: &Server, path: &str) -> anyhow::Result<String> {
+    match server {
+        Server::Socket(_) => Ok(std::fs::canonicalize(path)
+            .with_context(|| format!("failed to resolve jobset {path}"))?
+            .to_string_lossy()
+            .into_owned()),
+        // Local files say nothing about the file system of the server
+        Server::Remote(_) if Path::new(path).is_absolute() => Ok(path.to_owned()),
+        Server::Remote(_) => {
+            anyhow::bail!("jobset {path} has to be the absolute path of the jobset on the server")
+        }
+    }
 }
@@ -0,0 +1,148 @@
+//! Requests to a server on another machine, through `POST /v1/requests` of its web API.
+
+use std::io::{BufReader, Read};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::config::TOKEN_ENV;
+
+/// Unreachable servers should fail quickly, answers may take arbitrarily long though.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The web service of a server, every request is sent separately.
+#[derive(Clone)]
+pub struct Remote {
+    agent: ureq::Agent,
+    endpoint: String,
+    token: Option<String>,
+}
+
+impl Remote {
+    pub fn new(url: &str, token: Option<String>) -> anyhow::Result<Self> {
+        let url = url.trim_end_matches('/');
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            anyhow::bail!("server URL {url} has to start with https:// or http://");
+        }
+        // Anyone on the network path could read the token otherwise
+        if let Some(host) = url.strip_prefix("http://").map(url_host) {
+            if token.is_some() && !is_loopback(host) {
+                anyhow::bail!(
+                    "refusing to send the token to {url} unencrypted, use https:// instead"
+                );
+            }
+        }
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .build(),
+            endpoint: format!("{url}/v1/requests"),
+            token,
+        })
+    }
+
+    /// Send a single request frame and return the reader of its response frames.
+    ///
+    /// The `timeout` covers the whole exchange, without one a streamed response is read for as
+    /// long as it lasts.
+    pub fn send(
+        &self,
+        message: &str,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<BufReader<Box<dyn Read + Send + Sync>>> {
+        let mut request = self
+            .agent
+            .post(&self.endpoint)
+            .set("Content-Type", "application/json");
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+
+        match request.send_string(message) {
+            Ok(response) => Ok(BufReader::new(response.into_reader())),
+            Err(ureq::Error::Status(401, _)) if self.token.is_none() => anyhow::bail!(
+                "server requires a token, set `token` in the config file or ${TOKEN_ENV}"
+            ),
+            Err(ureq::Error::Status(401, _)) => anyhow::bail!("server did not accept the token"),
+            Err(ureq::Error::Status(404, _)) => anyhow::bail!(
+                "server does not accept requests at {}, it is likely older than ekaci {}, please \
+                 upgrade the server",
+                self.endpoint,
+                env!("CARGO_PKG_VERSION")
+            ),
+            Err(ureq::Error::Status(status, response)) => {
+                let reason = response.into_string().unwrap_or_default();
+                anyhow::bail!("server answered with status {status}: {reason}")
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to send request to {}", self.endpoint))
+            }
+        }
+    }
+}
+
+/// The host of a URL without its scheme, stripped of user info and port.
+fn url_host(url: &str) -> &str {
+    let authority = url.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+/// Whether `host` is only reachable from this machine.
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<IpAddr>()
+            .is_ok_and(|address| address.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts() {
+        assert_eq!(url_host("ci.example.org"), "ci.example.org");
+        assert_eq!(url_host("ci.example.org:8080/ci?a=b#c"), "ci.example.org");
+        assert_eq!(url_host("user:pass@ci.example.org:8080"), "ci.example.org");
+        assert_eq!(url_host("localhost@ci.example.org"), "ci.example.org");
+        assert_eq!(url_host("[::1]"), "::1");
+        assert_eq!(url_host("user@[::1]:8080/ci"), "::1");
+        assert_eq!(url_host("127.0.0.1:3000"), "127.0.0.1");
+
+        assert!(is_loopback("localhost"));
+        assert!(is_loopback("LocalHost"));
+        assert!(is_loopback("127.0.0.1"));
+        assert!(is_loopback("127.1.2.3"));
+        assert!(is_loopback("::1"));
+        assert!(!is_loopback("ci.example.org"));
+        assert!(!is_loopback("localhost.example.org"));
+        assert!(!is_loopback("10.0.0.1"));
+        assert!(!is_loopback("::2"));
+    }
+
+    #[test]
+    fn plain_http_token() {
+        let token = || Some("secret".to_owned());
+
+        assert!(Remote::new("ci.example.org", None).is_err());
+        assert!(Remote::new("https://ci.example.org", token()).is_ok());
+        assert!(Remote::new("http://ci.example.org", None).is_ok());
+        assert!(Remote::new("http://ci.example.org", token()).is_err());
+        assert!(Remote::new("http://ci.example.org:80/", token()).is_err());
+        assert!(Remote::new("http://localhost@ci.example.org", token()).is_err());
+        assert!(Remote::new("http://[::1]@ci.example.org", token()).is_err());
+        assert!(Remote::new("http://ci.example.org@localhost:3000", token()).is_ok());
+        assert!(Remote::new("http://localhost:3000/", token()).is_ok());
+        assert!(Remote::new("http://127.0.0.1:3000", token()).is_ok());
+        assert!(Remote::new("http://user:pass@[::1]:3000", token()).is_ok());
+    }
+}
@@ -1,63 +1,389 @@
+use crate::cli::OutputFormat;
+use crate::remote::Remote;
 use anyhow::Context;
 use shared::types as t;
-use shared::types::{ClientRequest, ClientResponse};
-use std::io::{Read, Write};
-use std::net::Shutdown;
+use shared::types::{
+    ClientRequest, ClientResponse, RequestFrame, ResponseFrame, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 use tracing::debug;
 
-pub fn send_request(socket: &std::path::Path, request: ClientRequest) -> anyhow::Result<()> {
-    // attempt to connect to socket
-    debug!("Attempting to connect to {}", &socket.display());
-
-    let mut stream = UnixStream::connect(socket).context("failed to connect to server socket")?;
-
-    // send request
-    let request_message =
-        serde_json::to_string(&request).expect("Our types should always be serializable");
-    stream
-        .write_all(request_message.as_bytes())
-        .context("failed to write request data")?;
-    stream.flush().context("failed to flush request data")?;
-    // TODO: Figure out why the write side of the stream
-    // needs to be shutdown in order to read without
-    // blocking both streams
-    stream
-        .shutdown(Shutdown::Write)
-        .context("failed to shutdown connection with server socket")?;
-
-    debug!("Attempting to read response message");
-    let mut response_message = String::new();
-    stream
-        .read_to_string(&mut response_message)
-        .context("failed to read server response")?;
-
-    let response: ClientResponse =
-        serde_json::from_str(&response_message).context("failed to interpret server response")?;
-
-    // render response
-    handle_response(response);
+/// Servers older than the handshake never answer it, as they wait for the client to stop writing.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
-    Ok(())
+/// Where requests are sent to.
+pub enum Server {
+    /// The unix socket of a server on this machine
+    Socket(PathBuf),
+    /// The web service of a server on another machine
+    Remote(Remote),
+}
+
+enum Transport {
+    /// All requests share a single connection
+    Socket {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    },
+    Remote(Remote),
+}
+
+/// A connection to the server, see [`RequestFrame`] for the protocol.
+pub struct Connection {
+    transport: Transport,
+    next_id: u64,
+    server: t::HelloResponse,
+}
+
+impl Connection {
+    /// Connect and exchange versions with the server.
+    pub fn connect(server: &Server) -> anyhow::Result<Self> {
+        let transport = match server {
+            Server::Socket(socket) => {
+                debug!("Attempting to connect to {}", &socket.display());
+
+                let writer =
+                    UnixStream::connect(socket).context("failed to connect to server socket")?;
+                let reader = BufReader::new(
+                    writer
+                        .try_clone()
+                        .context("failed to clone server socket handle")?,
+                );
+                Transport::Socket { reader, writer }
+            }
+            Server::Remote(remote) => Transport::Remote(remote.clone()),
+        };
+
+        let mut connection = Self {
+            transport,
+            next_id: 1,
+            server: t::HelloResponse {
+                protocol: MIN_PROTOCOL_VERSION,
+                version: String::new(),
+                features: Vec::new(),
+            },
+        };
+        connection.handshake()?;
+
+        Ok(connection)
+    }
+
+    fn handshake(&mut self) -> anyhow::Result<()> {
+        let hello = ClientRequest::Hello(t::HelloRequest {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            features: t::features::ALL.iter().map(|&f| f.to_owned()).collect(),
+        });
+
+        let mut server = None;
+        let result = self.send(hello, Some(HANDSHAKE_TIMEOUT), |response| match response {
+            ClientResponse::Hello(hello) => {
+                server = Some(hello);
+                Ok(())
+            }
+            ClientResponse::Error(err) => anyhow::bail!("server rejected client: {}", err.message),
+            response => anyhow::bail!("unexpected handshake response: {response:?}"),
+        });
+        if let Err(err) = result {
+            // Timeouts surface as `WouldBlock` or `TimedOut`, depending on the platform. Remote
+            // servers of that age do not accept requests at all.
+            let timed_out = matches!(self.transport, Transport::Socket { .. })
+                && err.chain().any(|cause| {
+                    cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+                        matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        )
+                    })
+                });
+            if timed_out {
+                anyhow::bail!(
+                    "server did not answer the version handshake, it is likely older than ekaci \
+                     {}, please upgrade the server",
+                    env!("CARGO_PKG_VERSION")
+                );
+            }
+            return Err(err.context("version handshake failed"));
+        }
+
+        let server = server.expect("a successful handshake response is a hello");
+        if server.protocol < MIN_PROTOCOL_VERSION {
+            anyhow::bail!(
+                "server {} speaks protocol {}, but ekaci {} requires at least protocol {}, \
+                 please upgrade the server",
+                server.version,
+                server.protocol,
+                env!("CARGO_PKG_VERSION"),
+                MIN_PROTOCOL_VERSION
+            );
+        }
+        if server.version != env!("CARGO_PKG_VERSION") {
+            debug!(
+                "Server version {} differs from client version {}",
+                server.version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        self.server = server;
+
+        Ok(())
+    }
+
+    /// Send a request and pass each response frame to `handle`, until the last one arrived.
+    pub fn request(
+        &mut self,
+        request: ClientRequest,
+        handle: impl FnMut(ClientResponse) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if let Some(feature) = request.feature() {
+            if !self.server.features.iter().any(|f| f == feature) {
+                anyhow::bail!(
+                    "server {} does not support {feature} requests, please upgrade the server",
+                    self.server.version
+                );
+            }
+        }
+
+        self.send(request, None, handle)
+    }
+
+    /// Without a `timeout`, responses are awaited for as long as it takes.
+    fn send(
+        &mut self,
+        request: ClientRequest,
+        timeout: Option<Duration>,
+        mut handle: impl FnMut(ClientResponse) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request_message = serde_json::to_string(&RequestFrame { id, request })
+            .expect("Our types should always be serializable");
+        request_message.push('\n');
+        let mut body;
+        let reader: &mut dyn BufRead = match &mut self.transport {
+            Transport::Socket { reader, writer } => {
+                writer
+                    .set_read_timeout(timeout)
+                    .context("failed to set socket timeout")?;
+                writer
+                    .write_all(request_message.as_bytes())
+                    .context("failed to write request data")?;
+                writer.flush().context("failed to flush request data")?;
+                reader
+            }
+            Transport::Remote(remote) => {
+                body = remote.send(&request_message, timeout)?;
+                &mut body
+            }
+        };
+
+        loop {
+            debug!("Attempting to read response message");
+            let mut response_message = String::new();
+            let read = reader
+                .read_line(&mut response_message)
+                .context("failed to read server response")?;
+            if read == 0 {
+                anyhow::bail!("server closed the connection before responding");
+            }
+
+            let frame: ResponseFrame = serde_json::from_str(&response_message)
+                .context("failed to interpret server response")?;
+            // Frames without an id answer a request the server could not parse, which can only be
+            // this one
+            if frame.id.is_some_and(|frame_id| frame_id != id) {
+                anyhow::bail!("server answered unknown request {:?}", frame.id);
+            }
+
+            handle(frame.response)?;
+            if !frame.more {
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub fn send_request(
+    server: &Server,
+    request: ClientRequest,
+    output: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut connection = Connection::connect(server)?;
+
+    connection.request(request, |response| print_response(output, response))
+}
+
+/// Render a response in the requested format. Error responses fail regardless of the format.
+pub fn print_response(output: OutputFormat, response: ClientResponse) -> anyhow::Result<()> {
+    match output {
+        OutputFormat::Text => handle_response(response),
+        OutputFormat::Json => {
+            // Failures are printed once the error reaches `main`, together with client errors
+            if let ClientResponse::Error(err) = response {
+                anyhow::bail!("server rejected request: {}", err.message);
+            }
+            println!("{}", json_line(&response));
+            Ok(())
+        }
+    }
+}
+
+/// The exit code of an invocation, reporting its error if it failed.
+///
+/// JSON output then ends with an `Error` response, so that scripts see server and client errors
+/// the same way.
+pub fn exit_code(output: OutputFormat, result: anyhow::Result<ExitCode>) -> ExitCode {
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            match output {
+                OutputFormat::Text => eprintln!("Error: {err:?}"),
+                OutputFormat::Json => println!("{}", json_line(&error_response(&err))),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn error_response(err: &anyhow::Error) -> ClientResponse {
+    ClientResponse::Error(t::ErrorResponse {
+        message: format!("{err:#}"),
+    })
+}
+
+fn json_line(response: &ClientResponse) -> String {
+    serde_json::to_string(response).expect("Our types should always be serializable")
 }
 
-fn handle_response(response: ClientResponse) {
+pub fn handle_response(response: ClientResponse) -> anyhow::Result<()> {
     use shared::types::ClientResponse as r;
 
     match response {
+        r::Hello(hello) => {
+            println!("Server version: {}", hello.version);
+        }
         r::Info(info) => {
             print_info(info);
         }
-        r::Build(info) => {
-            println!("Queued Successfully: {}", &info.enqueued);
+        r::Status(status) => {
+            crate::status::print_status(status);
+        }
+        r::Build(build) => {
+            match (build.build_attempt, build.state) {
+                (Some(attempt), Some(state)) => println!(
+                    "{} attempt {}: {}",
+                    build.drv_path,
+                    attempt,
+                    crate::status::state_label(state)
+                ),
+                _ => println!("Queued {}", build.drv_path),
+            }
+            println!("{}", build.url);
+        }
+        r::Job(job) => {
+            println!("Queued evaluation {} of {}", job.evaluation, job.file_path);
+            println!("{}", job.url);
         }
-        r::Job(info) => {
-            println!("Queued Successfully: {}", &info.enqueued);
+        r::Admin(info) => {
+            if info.builds.is_empty() {
+                println!("No builds changed");
+            }
+            for build in info.builds {
+                println!(
+                    "{} attempt {}: {:?}",
+                    build.drv_path, build.build_attempt, build.state
+                );
+            }
+        }
+        r::Log(log) => {
+            if log.skipped > 0 {
+                eprintln!("... skipped {} lines ...", log.skipped);
+            }
+            for line in log.lines {
+                println!("{line}");
+            }
+        }
+        r::Watch(event) => {
+            crate::watch::print_event(event);
+        }
+        r::WhyFailed(response) => {
+            crate::why_failed::print_why_failed(response);
+        }
+        r::Error(err) => {
+            anyhow::bail!("server rejected request: {}", err.message);
         }
     }
+
+    Ok(())
 }
 
 fn print_info(info: t::InfoResponse) {
     println!("Server status: {:?}", &info.status);
     println!("EkaCI server version: {:?}", &info.version);
+    println!("EkaCI client version: {:?}", env!("CARGO_PKG_VERSION"));
+    for component in &info.components {
+        println!(
+            "  {}: {:?} ({})",
+            component.name, component.status, component.detail
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_output() -> anyhow::Result<()> {
+        let build = ClientResponse::Build(t::BuildResponse {
+            drv_path: shared::store::DrvId::new(
+                "jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv",
+            )?,
+            build_attempt: None,
+            state: None,
+            url: "https://ci.example.org/drv/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"
+                .to_owned(),
+        });
+        assert_eq!(
+            json_line(&build),
+            r#"{"type":"Build","drv_path":"jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv","build_attempt":null,"state":null,"url":"https://ci.example.org/drv/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"}"#
+        );
+        let done = ClientResponse::Watch(t::WatchEvent::Done(t::BuildOutcome::Failed));
+        assert_eq!(
+            json_line(&done),
+            r#"{"type":"Watch","event":"Done","outcome":"Failed"}"#
+        );
+
+        // Errors of the server end the output like those of the client
+        let rejected = ClientResponse::Error(t::ErrorResponse {
+            message: "unknown derivation".to_owned(),
+        });
+        let err = print_response(OutputFormat::Json, rejected)
+            .context("failed to watch builds")
+            .unwrap_err();
+        assert_eq!(
+            json_line(&error_response(&err)),
+            r#"{"type":"Error","message":"failed to watch builds: server rejected request: unknown derivation"}"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn exit_codes() {
+        for output in [OutputFormat::Text, OutputFormat::Json] {
+            assert_eq!(exit_code(output, Ok(ExitCode::SUCCESS)), ExitCode::SUCCESS);
+            assert_eq!(exit_code(output, Ok(ExitCode::from(2))), ExitCode::from(2));
+            assert_eq!(
+                exit_code(output, Err(anyhow::anyhow!("no server"))),
+                ExitCode::FAILURE
+            );
+        }
+    }
 }
@@ -0,0 +1,141 @@
+//! Rendering of the server status summary as compact tables.
+
+use shared::build::{DrvBuildResult, DrvBuildState};
+use shared::types as t;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn print_status(status: t::StatusResponse) {
+    let mut states = status.states;
+    states.sort_by_key(|count| state_order(count.state));
+    let states: Vec<_> = states
+        .iter()
+        .map(|count| format!("{} {}", state_label(count.state), count.count))
+        .collect();
+    if states.is_empty() {
+        println!("Builds: none scheduled");
+    } else {
+        println!("Builds: {}", states.join(", "));
+    }
+    println!(
+        "Builders: {:?} ({})",
+        status.builders.status, status.builders.detail
+    );
+    println!("Evaluation queue: {} tasks", status.eval_queue);
+
+    if !status.running.is_empty() {
+        println!();
+        print_table(
+            &["RUNNING", "ATTEMPT", "SYSTEM", "ELAPSED"],
+            status
+                .running
+                .into_iter()
+                .map(|build| {
+                    vec![
+                        build.drv_path.to_string(),
+                        build.build_attempt.to_string(),
+                        build.system.unwrap_or_else(|| "-".to_owned()),
+                        format_duration(build.elapsed),
+                    ]
+                })
+                .collect(),
+        );
+    }
+
+    if !status.evaluations.is_empty() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        println!();
+        print_table(
+            &[
+                "EVALUATION",
+                "JOBSET",
+                "PR",
+                "QUEUED",
+                "DURATION",
+                "OUTCOME",
+            ],
+            status
+                .evaluations
+                .into_iter()
+                .map(|evaluation| {
+                    let outcome = match evaluation.outcome {
+                        t::EvaluationOutcome::Queued => "queued".to_owned(),
+                        t::EvaluationOutcome::Running => "running".to_owned(),
+                        t::EvaluationOutcome::Succeeded { drvs } => format!("{drvs} drvs"),
+                        t::EvaluationOutcome::Failed { error } => {
+                            // Errors may span many lines, the first one has to do here
+                            format!("failed: {}", error.lines().next().unwrap_or_default())
+                        }
+                    };
+                    vec![
+                        evaluation.id.to_string(),
+                        evaluation.jobset,
+                        evaluation.pr.map_or("-".to_owned(), |pr| pr.to_string()),
+                        format!(
+                            "{} ago",
+                            format_duration((now - evaluation.queued).max(0) as u64)
+                        ),
+                        evaluation.duration.map_or("-".to_owned(), format_duration),
+                        outcome,
+                    ]
+                })
+                .collect(),
+        );
+    }
+}
+
+/// Print left aligned columns, separated by two spaces.
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|column| column.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header = header.iter().map(|column| column.to_string()).collect();
+    for row in std::iter::once(header).chain(rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+pub fn state_label(state: DrvBuildState) -> String {
+    match state {
+        DrvBuildState::Queued => "queued".to_owned(),
+        DrvBuildState::Buildable => "buildable".to_owned(),
+        DrvBuildState::Building => "building".to_owned(),
+        DrvBuildState::Completed(DrvBuildResult::Success) => "succeeded".to_owned(),
+        DrvBuildState::Completed(DrvBuildResult::Failure) => "failed".to_owned(),
+        DrvBuildState::Interrupted(kind) => format!("interrupted ({kind:?})").to_lowercase(),
+        DrvBuildState::TransitiveFailure => "transitive failure".to_owned(),
+        DrvBuildState::Blocked => "blocked".to_owned(),
+    }
+}
+
+/// Order states roughly by how far along the pipeline they are.
+fn state_order(state: DrvBuildState) -> u8 {
+    match state {
+        DrvBuildState::Queued => 0,
+        DrvBuildState::Blocked => 1,
+        DrvBuildState::Buildable => 2,
+        DrvBuildState::Building => 3,
+        DrvBuildState::Completed(DrvBuildResult::Success) => 4,
+        DrvBuildState::Completed(DrvBuildResult::Failure) => 5,
+        DrvBuildState::TransitiveFailure => 6,
+        DrvBuildState::Interrupted(_) => 7,
+    }
+}
@@ -0,0 +1,355 @@
+//! Following builds until they end.
+
+use std::process::ExitCode;
+
+use anyhow::Context;
+use shared::types::{self as t, BuildOutcome, ClientRequest, ClientResponse, WatchEvent};
+
+use crate::cli::OutputFormat;
+use crate::requests::{print_response, Connection, Server};
+use crate::status::state_label;
+
+/// Exit code of commands waiting for builds: 0 if they succeeded, 2 if one failed and 3 if one
+/// was interrupted. Errors of the client itself exit with 1.
+pub fn exit_code(outcome: BuildOutcome) -> ExitCode {
+    match outcome {
+        BuildOutcome::Succeeded => ExitCode::SUCCESS,
+        BuildOutcome::Failed => ExitCode::from(2),
+        BuildOutcome::Interrupted => ExitCode::from(3),
+    }
+}
+
+pub fn watch(
+    server: &Server,
+    request: t::WatchRequest,
+    output: OutputFormat,
+) -> anyhow::Result<ExitCode> {
+    let mut connection = Connection::connect(server)?;
+    follow(&mut connection, request, output)
+}
+
+/// Queue a build, and with `wait` follow it until it ends.
+pub fn build(
+    server: &Server,
+    request: t::BuildRequest,
+    wait: bool,
+    output: OutputFormat,
+) -> anyhow::Result<ExitCode> {
+    let mut connection = Connection::connect(server)?;
+
+    let mut queued = None;
+    connection.request(ClientRequest::Build(request), |response| {
+        if let ClientResponse::Build(build) = &response {
+            queued = Some((build.drv_path.clone(), build.state));
+        }
+        print_response(output, response)
+    })?;
+    if !wait {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let (drv_path, state) = queued.context("server did not answer with the queued build")?;
+    // Drvs which were built before are not built again, their result is final
+    if let Some(outcome) = state.and_then(BuildOutcome::of) {
+        return Ok(exit_code(outcome));
+    }
+    let request = t::WatchRequest {
+        drv_path: Some(drv_path),
+        job: None,
+        pr: None,
+        evaluation: None,
+        log: false,
+    };
+    follow(&mut connection, request, output)
+}
+
+/// Queue an evaluation, and with `wait` follow it and the builds it causes until they end.
+pub fn job(
+    server: &Server,
+    request: t::JobRequest,
+    wait: bool,
+    output: OutputFormat,
+) -> anyhow::Result<ExitCode> {
+    let mut connection = Connection::connect(server)?;
+
+    let mut queued = None;
+    connection.request(ClientRequest::Job(request), |response| {
+        if let ClientResponse::Job(job) = &response {
+            queued = Some((job.file_path.clone(), job.pr, job.evaluation));
+        }
+        print_response(output, response)
+    })?;
+    if !wait {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let (file_path, pr, evaluation) =
+        queued.context("server did not answer with the queued evaluation")?;
+    let request = t::WatchRequest {
+        drv_path: None,
+        job: Some(file_path),
+        pr,
+        evaluation: Some(evaluation),
+        log: false,
+    };
+    follow(&mut connection, request, output)
+}
+
+/// Print the events of a watch, returning the exit code matching its outcome.
+fn follow(
+    connection: &mut Connection,
+    request: t::WatchRequest,
+    output: OutputFormat,
+) -> anyhow::Result<ExitCode> {
+    let mut outcome = None;
+    connection.request(ClientRequest::Watch(request), |response| {
+        if let ClientResponse::Watch(WatchEvent::Done(done)) = response {
+            outcome = Some(done);
+        }
+        print_response(output, response)
+    })?;
+
+    let outcome = outcome.context("server ended the watch without an outcome")?;
+    Ok(exit_code(outcome))
+}
+
+pub fn print_event(event: WatchEvent) {
+    match event {
+        WatchEvent::Build(change) => {
+            println!(
+                "{} attempt {}: {}",
+                change.drv_path,
+                change.build_attempt,
+                state_label(change.state)
+            );
+        }
+        WatchEvent::Evaluation(t::EvaluationOutcome::Queued) => {
+            println!("Waiting for the evaluator")
+        }
+        WatchEvent::Evaluation(t::EvaluationOutcome::Running) => println!("Evaluating"),
+        WatchEvent::Evaluation(t::EvaluationOutcome::Succeeded { drvs }) => {
+            println!("Evaluation found {drvs} drvs");
+        }
+        WatchEvent::Evaluation(t::EvaluationOutcome::Failed { error }) => {
+            println!("Evaluation failed: {error}");
+        }
+        WatchEvent::Log(log) => {
+            if log.skipped > 0 {
+                eprintln!("... skipped {} lines ...", log.skipped);
+            }
+            for line in log.lines {
+                println!("{line}");
+            }
+        }
+        WatchEvent::Done(outcome) => {
+            let outcome = match outcome {
+                BuildOutcome::Succeeded => "succeeded",
+                BuildOutcome::Failed => "failed",
+                BuildOutcome::Interrupted => "was interrupted",
+            };
+            println!("Build {outcome}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread::JoinHandle;
+
+    use shared::build::{DrvBuildResult, DrvBuildState};
+    use shared::store::DrvId;
+
+    use super::*;
+    use crate::requests;
+
+    const DRV: &str = "jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv";
+
+    /// A server accepting a single connection, which answers the handshake and then each request
+    /// with the next of its responses.
+    struct FakeServer {
+        _dir: tempfile::TempDir,
+        server: Server,
+        handle: JoinHandle<anyhow::Result<Vec<ClientRequest>>>,
+    }
+
+    impl FakeServer {
+        fn new(responses: Vec<Vec<ClientResponse>>) -> anyhow::Result<Self> {
+            let dir = tempfile::tempdir()?;
+            let socket = dir.path().join("ekaci.socket");
+            let listener = UnixListener::bind(&socket)?;
+
+            let handle = std::thread::spawn(move || {
+                let (stream, _) = listener.accept()?;
+                let mut reader = BufReader::new(stream.try_clone()?);
+                let mut writer = stream;
+                let hello = ClientResponse::Hello(t::HelloResponse {
+                    protocol: t::PROTOCOL_VERSION,
+                    version: "test".to_owned(),
+                    features: t::features::ALL.iter().map(|&f| f.to_owned()).collect(),
+                });
+
+                let mut requests = Vec::new();
+                for responses in std::iter::once(vec![hello]).chain(responses) {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line)? == 0 {
+                        break;
+                    }
+                    let frame: t::RequestFrame = serde_json::from_str(&line)?;
+                    requests.push(frame.request);
+
+                    let last = responses.len() - 1;
+                    for (i, response) in responses.into_iter().enumerate() {
+                        let frame = t::ResponseFrame {
+                            id: Some(frame.id),
+                            more: i < last,
+                            response,
+                        };
+                        writeln!(writer, "{}", serde_json::to_string(&frame)?)?;
+                    }
+                }
+                Ok(requests)
+            });
+
+            Ok(Self {
+                _dir: dir,
+                server: Server::Socket(socket),
+                handle,
+            })
+        }
+
+        /// The requests the server received, once the client hung up.
+        fn requests(self) -> anyhow::Result<Vec<ClientRequest>> {
+            self.handle.join().expect("fake server panicked")
+        }
+    }
+
+    fn watch_request() -> anyhow::Result<t::WatchRequest> {
+        Ok(t::WatchRequest {
+            drv_path: Some(DrvId::new(DRV)?),
+            job: None,
+            pr: None,
+            evaluation: None,
+            log: false,
+        })
+    }
+
+    fn queued(state: Option<DrvBuildState>) -> anyhow::Result<Vec<ClientResponse>> {
+        Ok(vec![ClientResponse::Build(t::BuildResponse {
+            drv_path: DrvId::new(DRV)?,
+            build_attempt: state.map(|_| 1),
+            state,
+            url: format!("https://ci.example.org/drv/{DRV}"),
+        })])
+    }
+
+    fn done(outcome: BuildOutcome) -> Vec<ClientResponse> {
+        vec![
+            ClientResponse::Watch(WatchEvent::Build(t::BuildStateChange {
+                drv_path: DrvId::new(DRV).unwrap(),
+                build_attempt: 1,
+                state: DrvBuildState::Building,
+            })),
+            ClientResponse::Watch(WatchEvent::Done(outcome)),
+        ]
+    }
+
+    #[test]
+    fn watch_exit_codes() -> anyhow::Result<()> {
+        let outcomes = [
+            (BuildOutcome::Succeeded, ExitCode::SUCCESS),
+            (BuildOutcome::Failed, ExitCode::from(2)),
+            (BuildOutcome::Interrupted, ExitCode::from(3)),
+        ];
+        for (outcome, code) in outcomes {
+            let server = FakeServer::new(vec![done(outcome)])?;
+            let result = watch(&server.server, watch_request()?, OutputFormat::Json);
+            assert_eq!(requests::exit_code(OutputFormat::Json, result), code);
+            server.requests()?;
+        }
+
+        // A watch ending without an outcome, or rejected by the server, is an error of the client
+        let error = ClientResponse::Error(t::ErrorResponse {
+            message: "unknown derivation".to_owned(),
+        });
+        for responses in [vec![done(BuildOutcome::Succeeded).remove(0)], vec![error]] {
+            let server = FakeServer::new(vec![responses])?;
+            let result = watch(&server.server, watch_request()?, OutputFormat::Json);
+            assert_eq!(
+                requests::exit_code(OutputFormat::Json, result),
+                ExitCode::FAILURE
+            );
+            server.requests()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_exit_codes() -> anyhow::Result<()> {
+        let build = || t::BuildRequest {
+            drv_path: DrvId::new(DRV).unwrap(),
+        };
+
+        // Without waiting, queueing the build is all that counts
+        let server = FakeServer::new(vec![queued(None)?])?;
+        let code = super::build(&server.server, build(), false, OutputFormat::Json)?;
+        assert_eq!(code, ExitCode::SUCCESS);
+        assert_eq!(server.requests()?.len(), 2);
+
+        // Final states are not watched
+        let built = [
+            (DrvBuildResult::Success, ExitCode::SUCCESS),
+            (DrvBuildResult::Failure, ExitCode::from(2)),
+        ];
+        for (result, code) in built {
+            let state = Some(DrvBuildState::Completed(result));
+            let server = FakeServer::new(vec![queued(state)?])?;
+            assert_eq!(
+                super::build(&server.server, build(), true, OutputFormat::Json)?,
+                code
+            );
+            assert_eq!(server.requests()?.len(), 2);
+        }
+
+        let server = FakeServer::new(vec![
+            queued(Some(DrvBuildState::Queued))?,
+            done(BuildOutcome::Interrupted),
+        ])?;
+        let code = super::build(&server.server, build(), true, OutputFormat::Json)?;
+        assert_eq!(code, ExitCode::from(3));
+        let received = server.requests()?;
+        assert!(matches!(
+            &received[2],
+            ClientRequest::Watch(t::WatchRequest { drv_path: Some(drv), .. }) if drv.to_string() == DRV
+        ));
+
+        // Jobs are followed by their evaluation
+        let job = ClientResponse::Job(t::JobResponse {
+            evaluation: 7,
+            file_path: "/src/ci.nix".to_owned(),
+            pr: Some(3),
+            url: "https://ci.example.org/evaluations/7".to_owned(),
+        });
+        let server = FakeServer::new(vec![vec![job], done(BuildOutcome::Failed)])?;
+        let request = t::JobRequest {
+            file_path: "/src/ci.nix".to_owned(),
+            pr: Some(3),
+        };
+        let code = super::job(&server.server, request, true, OutputFormat::Json)?;
+        assert_eq!(code, ExitCode::from(2));
+        let received = server.requests()?;
+        assert!(matches!(
+            &received[2],
+            ClientRequest::Watch(t::WatchRequest {
+                job: Some(job),
+                pr: Some(3),
+                evaluation: Some(7),
+                ..
+            }) if job == "/src/ci.nix"
+        ));
+
+        Ok(())
+    }
+}
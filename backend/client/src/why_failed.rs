@@ -0,0 +1,36 @@
+//! Rendering of the dependencies a failed derivation is waiting on.
+
+use shared::build::DrvBuildState;
+use shared::types as t;
+
+use crate::status::state_label;
+
+pub fn print_why_failed(response: t::WhyFailedResponse) {
+    let Some(state) = response.state else {
+        println!("{} was never scheduled for building", response.drv_path);
+        return;
+    };
+    println!("{}: {}", response.drv_path, state_label(state));
+
+    if response.root_failures.is_empty() {
+        if matches!(
+            state,
+            DrvBuildState::TransitiveFailure | DrvBuildState::Blocked
+        ) {
+            println!("None of its dependencies is known to have failed or been interrupted");
+        }
+        return;
+    }
+
+    for root in response.root_failures {
+        println!();
+        let chain: Vec<String> = root.chain.iter().map(ToString::to_string).collect();
+        println!("{}: {}", chain.join(" -> "), state_label(root.state));
+        if root.log_tail.is_empty() {
+            println!("    (no log)");
+        }
+        for line in root.log_tail {
+            println!("    {line}");
+        }
+    }
+}
@@ -0,0 +1,26 @@
+-- The realized outputs of a derivation, keyed by output name (e.g. "out", "dev", "lib").
+-- Paths are stored without the store directory prefix, the same as the drv paths in Drv.
+-- For more documentation, see the corresponding Rust struct.
+CREATE TABLE IF NOT EXISTS DrvOutput (
+    derivation TEXT NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    -- Outputs of a derivation never change, so re-inserting the same derivation can be ignored.
+    UNIQUE (derivation, name) ON CONFLICT IGNORE,
+    FOREIGN KEY (derivation) REFERENCES Drv(drv_path) ON DELETE CASCADE
+);
+
+-- Used to answer "which derivation produced this store path?"
+CREATE INDEX IF NOT EXISTS DrvOutputPath ON DrvOutput (path);
+
+-- Attribute paths under which an evaluation exposed a derivation. A derivation can be reachable
+-- through several attributes (e.g. python3.pkgs.setuptools vs python3Packages.setuptools), and
+-- derivations only discovered through traversal have no attribute at all.
+CREATE TABLE IF NOT EXISTS DrvAttr (
+    derivation TEXT NOT NULL,
+    attr TEXT NOT NULL,
+    UNIQUE (derivation, attr) ON CONFLICT IGNORE,
+    FOREIGN KEY (derivation) REFERENCES Drv(drv_path) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS DrvAttrDerivation ON DrvAttr (derivation);
@@ -0,0 +1,4 @@
+-- 20250426_drv.sql tried to create this index under the name DrvReferrer, which was already taken
+-- by the index on the referrer column. Because of IF NOT EXISTS the second statement was silently
+-- skipped and the reference column was never indexed.
+CREATE INDEX IF NOT EXISTS DrvReference ON DrvRefs (reference);
@@ -0,0 +1,17 @@
+-- Derivations exposed by evaluations of a jobset (the attributes nix-eval-jobs returned, not their
+-- dependencies). Used to tell which jobsets and pull requests a build is relevant for.
+CREATE TABLE IF NOT EXISTS JobsetDrv (
+    jobset TEXT NOT NULL, -- the evaluated Nix file
+    pr INTEGER, -- pull request the evaluation was done for, NULL outside of pull requests
+    evaluation INTEGER NOT NULL, -- the evaluation which exposed the derivation
+    derivation TEXT NOT NULL,
+    FOREIGN KEY (evaluation) REFERENCES Evaluation(id) ON DELETE CASCADE,
+    FOREIGN KEY (derivation) REFERENCES Drv(drv_path) ON DELETE CASCADE
+);
+
+-- Inserts use INSERT OR IGNORE, an evaluation may expose a derivation under several attributes.
+CREATE UNIQUE INDEX IF NOT EXISTS JobsetDrvUnique ON JobsetDrv (evaluation, derivation);
+
+CREATE INDEX IF NOT EXISTS JobsetDrvJobset ON JobsetDrv (jobset, pr);
+
+CREATE INDEX IF NOT EXISTS JobsetDrvDerivation ON JobsetDrv (derivation);
@@ -0,0 +1,11 @@
+-- Build attempts whose result a maintainer marked as invalid, e.g. because a misbehaving builder
+-- reported the wrong result. The attempt keeps its state, a new attempt is queued instead.
+CREATE TABLE IF NOT EXISTS DrvBuildInvalidation (
+    derivation TEXT NOT NULL,
+    build_attempt INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    timestamp INTEGER NOT NULL DEFAULT (unixepoch()),
+    PRIMARY KEY (derivation, build_attempt),
+    FOREIGN KEY (derivation, build_attempt)
+        REFERENCES DrvBuildMetadata(derivation, build_attempt) ON DELETE CASCADE
+);
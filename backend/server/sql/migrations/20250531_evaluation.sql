@@ -0,0 +1,16 @@
+-- Evaluations of jobsets, to tell what the evaluator has been doing and how it went. Evaluations
+-- are recorded as soon as they are queued, so that clients learn their id right away.
+CREATE TABLE IF NOT EXISTS Evaluation (
+    id INTEGER PRIMARY KEY,
+    jobset TEXT NOT NULL, -- the evaluated Nix file
+    pr INTEGER, -- pull request the evaluation was done for, NULL outside of pull requests
+    queued INTEGER NOT NULL DEFAULT (unixepoch()),
+    started INTEGER, -- NULL while the evaluation is waiting for the evaluator
+    finished INTEGER, -- NULL while the evaluation is queued or running
+    drvs INTEGER, -- number of derivations a successful evaluation found
+    error TEXT -- why the evaluation failed, NULL if it did not
+);
+
+CREATE INDEX IF NOT EXISTS EvaluationStarted ON Evaluation (started);
+
+CREATE INDEX IF NOT EXISTS EvaluationJobset ON Evaluation (jobset, pr);
@@ -1,18 +1,61 @@
+mod access;
+mod watch;
+
+use crate::config::{ConfigUnix, SocketAccess};
+use crate::db::{admin::AdminError, jobset::Evaluation, model::build::StoredBuildEvent, DbService};
+use crate::health::Health;
+use crate::logs::LogStore;
 use crate::nix::EvalTask;
 use anyhow::{Context, Result};
-use shared::types::{ClientRequest, ClientResponse};
-use std::path::Path;
-use tokio::sync::mpsc::Sender;
+use chrono::{TimeDelta, Utc};
+use futures_util::StreamExt;
+use shared::types::{ClientRequest, ClientResponse, RequestFrame, ResponseFrame};
+use std::collections::VecDeque;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::Semaphore;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{unix::SocketAddr, UnixListener, UnixStream},
+    io::{unix::AsyncFd, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Interest},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener, UnixStream,
+    },
 };
 use tracing::{debug, info, warn};
 
+use access::Peer;
+pub use access::Permission;
+
+/// Response frames that may be queued for a slow client, before request handlers have to wait.
+const FRAME_BUFFER: usize = 64;
+
+/// Number of evaluations included in the status summary.
+const RECENT_EVALUATIONS: u32 = 10;
+
+/// Maximum number of log lines sent in a single frame.
+const LOG_CHUNK_LINES: usize = 256;
+
+/// Longest message accepted from a client. Requests are tiny, anything longer is broken.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Connections are closed once the client sent nothing for this long and waits for no responses.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Clients not reading their responses for this long are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections served at the same time, further clients are turned away.
+const MAX_CONNECTIONS: usize = 256;
+
 pub struct UnixService {
     listener: UnixListener,
-    /// Channel to emit drvs to be evaluated
-    dispatch: DispatchChannels,
+    socket_path: PathBuf,
+    context: RequestContext,
 }
 
 /// Channels which can be used to communicate actions to other services
@@ -21,23 +64,61 @@ struct DispatchChannels {
     eval_sender: Sender<EvalTask>,
 }
 
+/// Everything needed to handle a request, shared by all connections.
+#[derive(Clone)]
+struct RequestContext {
+    /// Channel to emit drvs to be evaluated
+    dispatch: DispatchChannels,
+    db_service: DbService,
+    health: Health,
+    logs: LogStore,
+    /// Base URL of the web service, for links in responses
+    public_url: String,
+    /// Without rules, other users than the server's own only have read access
+    access: Option<Arc<SocketAccess>>,
+}
+
 impl UnixService {
+    /// Listen on the configured socket, whose file mode and group are applied before it becomes
+    /// reachable. Clients are served as far as the access rules allow.
     // TODO: We should probably use a builder pattern to pass eval channel and other items
-    pub async fn bind_to_path(socket_path: &Path, eval_sender: Sender<EvalTask>) -> Result<Self> {
-        prepare_path(socket_path)?;
+    pub async fn bind(
+        config: ConfigUnix,
+        eval_sender: Sender<EvalTask>,
+        db_service: DbService,
+        health: Health,
+        logs: LogStore,
+        public_url: String,
+    ) -> Result<Self> {
+        prepare_path(&config.socket_path)?;
+
+        let listener = bind_private(&config.socket_path, config.mode, config.group)?;
+        let context = RequestContext {
+            dispatch: DispatchChannels { eval_sender },
+            db_service,
+            health,
+            logs,
+            public_url,
+            access: config.access.map(Arc::new),
+        };
 
-        let listener = UnixListener::bind(socket_path)?;
-        let dispatch = DispatchChannels { eval_sender };
+        Ok(Self {
+            listener,
+            socket_path: config.socket_path,
+            context,
+        })
+    }
 
-        Ok(Self { listener, dispatch })
+    /// A handler for requests that reach the server through other means than the socket.
+    pub fn request_handler(&self) -> RequestHandler {
+        RequestHandler {
+            context: self.context.clone(),
+        }
     }
 
-    pub fn bind_addr(&self) -> SocketAddr {
-        // If the call fails either the system ran out of resources or libc is broken, for both of
-        // these cases a panic seems appropiate.
-        self.listener
-            .local_addr()
-            .expect("getsockname should always succeed on a properly initialized listener")
+    /// Where clients connect to. The listener itself was bound elsewhere, see [`bind_private`].
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
     }
 
     pub async fn run(self) {
@@ -45,14 +126,21 @@ impl UnixService {
     }
 
     async fn listen_for_client(self) {
+        let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
         loop {
             match self.listener.accept().await {
                 Ok((stream, _)) => {
-                    let new_dispatch = self.dispatch.clone();
-                    tokio::spawn(async {
-                        if let Err(err) = handle_client(stream, new_dispatch).await {
+                    let Ok(permit) = connections.clone().try_acquire_owned() else {
+                        warn!("Turning away socket client, {MAX_CONNECTIONS} are connected");
+                        tokio::spawn(turn_away(stream));
+                        continue;
+                    };
+                    let context = self.context.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_client(stream, context).await {
                             warn!("Failed to handle socket connection: {:?}", err);
                         }
+                        drop(permit);
                     });
                 }
                 Err(err) => {
@@ -63,8 +151,65 @@ impl UnixService {
     }
 }
 
+/// Serves socket requests made through the web API, one request at a time.
+#[derive(Clone)]
+pub struct RequestHandler {
+    context: RequestContext,
+}
+
+impl RequestHandler {
+    /// Handle a request in the background and return its response frames, the last of which
+    /// does not have `more` set.
+    ///
+    /// `client` names who made the request in error messages, requests needing more than
+    /// `granted` are rejected.
+    pub fn handle(
+        &self,
+        frame: RequestFrame,
+        client: String,
+        granted: Permission,
+    ) -> Receiver<ResponseFrame> {
+        let (frames, receiver) = mpsc::channel(FRAME_BUFFER);
+        let responder = Responder {
+            id: Some(frame.id),
+            frames,
+        };
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            debug!("Got request from {}: {:?}", client, &frame);
+            match access::missing(&frame.request, granted) {
+                Some(required) => {
+                    let reason = format!(
+                        "permission denied: {client} only has {granted} access, the request \
+                         needs {required} access"
+                    );
+                    warn!("Rejecting remote request: {}", reason);
+                    responder.finish(error_response(reason)).await;
+                }
+                None => handle_request(frame.request, &context, responder).await,
+            }
+        });
+
+        receiver
+    }
+}
+
+/// Tell a client that it can not be served right now, without reading its requests.
+async fn turn_away(mut stream: UnixStream) {
+    let frame = ResponseFrame {
+        id: None,
+        more: false,
+        response: error_response(format!(
+            "the server is busy with {MAX_CONNECTIONS} connections, try again later"
+        )),
+    };
+    let mut message = serde_json::to_vec(&frame).expect("Our types should always be serializable");
+    message.push(b'\n');
+    // Best effort, the client is not worth waiting for
+    let _ = tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(&message)).await;
+}
+
 /// Ensure parent directories
-/// Remove potential lingering socket file from previous runs
 fn prepare_path(socket_path: &Path) -> Result<()> {
     let parent = socket_path
         .parent()
@@ -75,74 +220,1171 @@ fn prepare_path(socket_path: &Path) -> Result<()> {
         let _ = std::fs::create_dir_all(parent);
     }
 
-    // Not deleting the previous socket file results in a:
-    // "Already in use" error
-    if socket_path.exists() {
-        debug!(
-            "Previous socket file {:?} found, attempting to remove",
-            socket_path
-        );
-        std::fs::remove_file(socket_path).context("failed to remove previous socket file")?;
+    Ok(())
+}
+
+/// Bind a listener that only becomes reachable at `socket_path` with its `mode` and `group`
+/// already applied.
+///
+/// The socket is bound in a directory only the server may enter and moved into place from there,
+/// which also replaces a socket file lingering from previous runs.
+fn bind_private(socket_path: &Path, mode: Option<u32>, group: Option<u32>) -> Result<UnixListener> {
+    let private_dir = socket_path.with_file_name(format!(".ekaci-socket-{}", std::process::id()));
+    // Left behind by an earlier server that happened to have the same pid
+    if private_dir.exists() {
+        std::fs::remove_dir_all(&private_dir)?;
     }
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .with_context(|| format!("failed to create {}", private_dir.display()))?;
 
-    Ok(())
+    let private_path = private_dir.join("socket");
+    let bind = || -> Result<UnixListener> {
+        let listener = UnixListener::bind(&private_path)?;
+        if let Some(group) = group {
+            std::os::unix::fs::chown(&private_path, None, Some(group))
+                .with_context(|| format!("failed to change socket group to {group}"))?;
+        }
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("failed to change socket mode to {mode:o}"))?;
+        }
+        std::fs::rename(&private_path, socket_path)
+            .context("failed to move the socket into place")?;
+        Ok(listener)
+    };
+    let listener = bind();
+    if let Err(e) = std::fs::remove_dir_all(&private_dir) {
+        warn!("Failed to remove {}: {:?}", private_dir.display(), e);
+    }
+
+    listener
 }
 
-async fn handle_client(mut stream: UnixStream, dispatch: DispatchChannels) -> Result<()> {
-    use shared::types as t;
-    info!("Got unix socket client: {:?}", stream);
+/// How responses are written, decided by the first message of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    /// One [`ResponseFrame`] per line.
+    Lines,
+    /// Clients speaking protocol 0 send a single bare request, and read a single bare response
+    /// until the connection is closed.
+    Legacy,
+}
+
+impl Framing {
+    fn detect(first_line: &str) -> Self {
+        // Bare requests are tagged by their type at the top level, frames never are
+        let bare = serde_json::from_str::<serde_json::Value>(first_line)
+            .is_ok_and(|message| message.get("type").is_some());
+        if bare {
+            Framing::Legacy
+        } else {
+            Framing::Lines
+        }
+    }
+}
+
+/// Serve all requests of a connection until the client stops sending.
+///
+/// Requests are handled concurrently, their response frames are written by a single task in the
+/// order they are produced. Once the client shut down its sending side, the responses still in
+/// flight are sent before the connection is closed. Once the client closed the connection
+/// entirely, requests in flight are abandoned.
+async fn handle_client(stream: UnixStream, context: RequestContext) -> Result<()> {
+    let peer = Peer::from(stream.peer_cred()?);
+    let granted = match &context.access {
+        Some(access) => access::permission(access, peer),
+        None => Some(access::default_permission(
+            peer,
+            rustix::process::getuid().as_raw(),
+        )),
+    };
+    info!(
+        "Got unix socket client: uid {} gid {} with {:?} access",
+        peer.uid, peer.gid, granted
+    );
+
+    let hangup = hangup(rustix::io::dup(&stream)?);
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (frames, frame_receiver) = mpsc::channel(FRAME_BUFFER);
+
+    let mut next_line = read_message(&mut reader, &frames).await;
+    let framing = match &next_line {
+        Ok(Some(line)) => Framing::detect(line),
+        _ => Framing::Lines,
+    };
+    if framing == Framing::Legacy {
+        debug!("Serving socket client speaking protocol 0");
+    }
+    let writer = tokio::spawn(write_frames(writer, frame_receiver, framing, hangup));
+
+    loop {
+        let line = match next_line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                warn!("Failed to read from socket client: {:#}", err);
+                let responder = Responder {
+                    id: None,
+                    frames: frames.clone(),
+                };
+                responder
+                    .finish(error_response(format!("failed to read request: {err:#}")))
+                    .await;
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            next_line = read_message(&mut reader, &frames).await;
+            continue;
+        }
+
+        // Malformed requests (including invalid store paths) are rejected here, before anything
+        // reaches the evaluator or the database.
+        let frame = match framing {
+            Framing::Lines => serde_json::from_str::<RequestFrame>(&line),
+            Framing::Legacy => serde_json::from_str::<ClientRequest>(&line)
+                .map(|request| RequestFrame { id: 0, request }),
+        };
+        match frame {
+            Ok(frame) => {
+                debug!("Got message from client: {:?}", &frame);
+                let responder = Responder {
+                    id: Some(frame.id),
+                    frames: frames.clone(),
+                };
+                if let Some(reason) = access::denial(&frame.request, peer, granted) {
+                    warn!("Rejecting socket request: {}", reason);
+                    responder.finish(error_response(reason)).await;
+                } else {
+                    let context = context.clone();
+                    tokio::spawn(async move {
+                        handle_request(frame.request, &context, responder).await;
+                    });
+                }
+            }
+            Err(err) => {
+                warn!("Rejecting malformed client request: {}", err);
+                // Still answer the right request, if at least its id can be made out
+                let id = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|frame| frame.get("id")?.as_u64());
+                let responder = Responder {
+                    id,
+                    frames: frames.clone(),
+                };
+                responder
+                    .finish(error_response(format!("malformed request: {err}")))
+                    .await;
+            }
+        }
+
+        if framing == Framing::Legacy {
+            break;
+        }
+        next_line = read_message(&mut reader, &frames).await;
+    }
 
-    let mut request_message: String = String::new();
-    stream.read_to_string(&mut request_message).await?;
-    let message: t::ClientRequest = serde_json::from_str(&request_message)?;
-    debug!("Got message from client: {:?}", &message);
+    // The writer finishes once every request handler dropped its responder
+    drop(frames);
+    writer.await?
+}
+
+/// Read the next message, a single line. `None` once the client stopped sending.
+///
+/// Fails if the message exceeds [`MAX_MESSAGE_SIZE`], or if the client sent nothing for
+/// [`IDLE_TIMEOUT`] while none of its requests are in flight.
+async fn read_message(
+    reader: &mut BufReader<OwnedReadHalf>,
+    frames: &Sender<ResponseFrame>,
+) -> Result<Option<String>> {
+    let mut message = Vec::new();
+    loop {
+        // One more byte than allowed, to tell a message of exactly the maximum size from a
+        // longer one
+        let limit = (MAX_MESSAGE_SIZE + 1 - message.len()) as u64;
+        // Partially read messages stay in the buffer when the read times out
+        let mut limited = (&mut *reader).take(limit);
+        let read = limited.read_until(b'\n', &mut message);
+        match tokio::time::timeout(IDLE_TIMEOUT, read).await {
+            Ok(read) => {
+                read?;
+                break;
+            }
+            // Every request in flight holds a sender, clients wait for their responses
+            Err(_) if frames.strong_count() > 1 => continue,
+            Err(_) => anyhow::bail!("no request within {} seconds", IDLE_TIMEOUT.as_secs()),
+        }
+    }
 
-    let response = handle_request(message, dispatch).await;
-    let response_message = serde_json::to_string(&response)?;
+    if message.len() > MAX_MESSAGE_SIZE {
+        anyhow::bail!("message exceeds {MAX_MESSAGE_SIZE} bytes");
+    }
+    if message.is_empty() {
+        return Ok(None);
+    }
+    let message = String::from_utf8(message).context("message is not valid UTF-8")?;
 
-    stream.write_all(response_message.as_bytes()).await?;
-    stream.flush().await?;
-    println!("Shutting down socket");
-    stream.shutdown().await?;
+    Ok(Some(message))
+}
+
+/// Resolves once the client closed the connection entirely, as opposed to only shutting down its
+/// sending side. `socket` is a duplicate of the connection's socket, so that waiting here does not
+/// interfere with the readiness the writer waits for.
+async fn hangup(socket: OwnedFd) {
+    let result = async {
+        let socket = AsyncFd::with_interest(socket, Interest::WRITABLE)?;
+        loop {
+            // A hung up socket reports itself as closed for writing
+            let mut guard = socket.writable().await?;
+            if guard.ready().is_write_closed() {
+                return anyhow::Ok(());
+            }
+            // Otherwise, wait for the next change of the socket
+            guard.clear_ready();
+        }
+    };
+    if let Err(e) = result.await {
+        warn!("Failed to watch socket client for hangups: {:?}", e);
+        std::future::pending().await
+    }
+}
+
+/// Write response frames until every responder is gone, or the client hung up.
+///
+/// A hangup drops `frames`, which abandons the requests in flight, see [`Responder::closed`].
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut frames: Receiver<ResponseFrame>,
+    framing: Framing,
+    hangup: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    let mut hangup = std::pin::pin!(hangup);
+    loop {
+        let frame = tokio::select! {
+            frame = frames.recv() => frame,
+            () = &mut hangup => {
+                debug!("Socket client hung up, abandoning its requests");
+                return Ok(());
+            }
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        let mut message = match framing {
+            Framing::Lines => serde_json::to_vec(&frame)?,
+            Framing::Legacy => serde_json::to_vec(&frame.response)?,
+        };
+        message.push(b'\n');
+        tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&message))
+            .await
+            .context("client stopped reading responses")?
+            .context("failed to send response frame")?;
+    }
+    writer.shutdown().await?;
 
     Ok(())
 }
 
-async fn handle_request(request: ClientRequest, dispatch: DispatchChannels) -> ClientResponse {
+/// Sends the response frames of a single request.
+struct Responder {
+    id: Option<u64>,
+    frames: Sender<ResponseFrame>,
+}
+
+impl Responder {
+    /// Send part of a streamed response, more frames have to follow.
+    ///
+    /// Fails if the connection is gone, in which case the request should be abandoned.
+    async fn send(&self, response: ClientResponse) -> Result<()> {
+        self.frames
+            .send(ResponseFrame {
+                id: self.id,
+                more: true,
+                response,
+            })
+            .await
+            .context("client connection is closed")
+    }
+
+    /// Send the last or only frame of the response.
+    async fn finish(self, response: ClientResponse) {
+        let frame = ResponseFrame {
+            id: self.id,
+            more: false,
+            response,
+        };
+        if self.frames.send(frame).await.is_err() {
+            debug!("Dropping response to request {:?}, client is gone", self.id);
+        }
+    }
+
+    /// Resolves once the connection is gone, either because the client hung up or because
+    /// sending to it failed.
+    async fn closed(&self) {
+        self.frames.closed().await
+    }
+}
+
+fn error_response(message: String) -> ClientResponse {
+    ClientResponse::Error(shared::types::ErrorResponse { message })
+}
+
+async fn handle_request(request: ClientRequest, context: &RequestContext, responder: Responder) {
     use shared::types as t;
     use shared::types::ClientRequest as req;
     use shared::types::ClientResponse as resp;
 
-    match request {
-        req::Info => resp::Info(t::InfoResponse {
-            status: t::ServerStatus::Active,
-            version: "0.1.0".to_string(),
-        }),
+    let RequestContext {
+        dispatch,
+        db_service,
+        health,
+        logs,
+        public_url,
+        access: _,
+    } = context;
+
+    let response = match request {
+        req::Hello(hello) => hello_response(hello),
+        req::Info => resp::Info(health.readiness().await),
+        req::Status => match status_response(context).await {
+            Ok(status) => resp::Status(status),
+            Err(e) => {
+                warn!("Failed to collect server status: {:?}", e);
+                error_response("failed to collect server status".to_owned())
+            }
+        },
         req::Job(job_info) => {
+            let evaluation = match db_service
+                .queue_evaluation(&job_info.file_path, job_info.pr)
+                .await
+            {
+                Ok(evaluation) => evaluation,
+                Err(e) => {
+                    warn!("Failed to record evaluation: {:?}", e);
+                    let message = "failed to queue the evaluation".to_owned();
+                    return responder.finish(error_response(message)).await;
+                }
+            };
             let job = crate::nix::EvalJob {
-                file_path: job_info.file_path,
+                evaluation,
+                file_path: job_info.file_path.clone(),
+                pr: job_info.pr,
             };
-            let task = EvalTask::Job(job);
-            dispatch
-                .eval_sender
-                .send(task)
-                .await
-                .expect("Eval service is unhealthy");
+            if let Err(message) = dispatch_eval(dispatch, health, EvalTask::Job(job)) {
+                // The evaluation will never run, do not leave it queued
+                if let Err(e) = db_service
+                    .finish_evaluation(evaluation, Err(&message))
+                    .await
+                {
+                    warn!(
+                        "Failed to record rejected evaluation {}: {:?}",
+                        evaluation, e
+                    );
+                }
+                return responder.finish(error_response(message)).await;
+            }
 
-            resp::Job(t::JobResponse { enqueued: true })
+            resp::Job(t::JobResponse {
+                evaluation,
+                file_path: job_info.file_path,
+                pr: job_info.pr,
+                url: format!("{public_url}/v1/evaluations/{evaluation}"),
+            })
         }
         req::Build(build_info) => {
+            // A derivation which is already known answers with its cached state right away
+            let latest = match db_service.latest_build_event(&build_info.drv_path).await {
+                Ok(latest) => latest,
+                Err(e) => {
+                    warn!("Failed to look up {}: {:?}", build_info.drv_path, e);
+                    let message = "failed to look up the derivation".to_owned();
+                    return responder.finish(error_response(message)).await;
+                }
+            };
+            let url = format!("{public_url}/v1/drvs/{}", build_info.drv_path);
+
             // TODO: we should not be doing this operation on the response thread
             // Instead, we should be sending a message for the evaluator service to traverse this
-            let task = EvalTask::TraverseDrv(build_info.drv_path);
-            dispatch
-                .eval_sender
-                .send(task)
+            let task = EvalTask::TraverseDrv(build_info.drv_path.clone());
+            if let Err(message) = dispatch_eval(dispatch, health, task) {
+                return responder.finish(error_response(message)).await;
+            }
+
+            resp::Build(t::BuildResponse {
+                drv_path: build_info.drv_path,
+                build_attempt: latest
+                    .as_ref()
+                    .map(|stored| stored.event.build.build_attempt.get()),
+                state: latest.map(|stored| stored.event.state),
+                url,
+            })
+        }
+        req::Restart(request) => admin_response(
+            db_service
+                .restart_build(&request.drv_path)
                 .await
-                .expect("Eval service is unhealthy");
+                .map(|e| vec![e]),
+        ),
+        req::Cancel(request) => admin_response(
+            db_service
+                .cancel_build(&request.drv_path)
+                .await
+                .map(|e| vec![e]),
+        ),
+        req::Invalidate(request) => admin_response(
+            db_service
+                .invalidate_build(&request.drv_path, &request.reason)
+                .await
+                .map(|e| vec![e]),
+        ),
+        req::RestartFailures(request) => {
+            admin_response(db_service.restart_failures(request.evaluation).await)
+        }
+        req::Log(request) => return stream_log(request, logs, responder).await,
+        req::Watch(request) => return watch::watch(request, context, responder).await,
+        req::WhyFailed(request) => match why_failed_response(request, context).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to look up root failures: {:?}", e);
+                error_response("failed to look up the failed dependencies".to_owned())
+            }
+        },
+    };
+
+    responder.finish(response).await;
+}
+
+/// Hand a task to the evaluator. A full queue is reported to the client rather than making it
+/// wait, as it would otherwise hold its connection for as long as the evaluator is backed up.
+fn dispatch_eval(
+    dispatch: &DispatchChannels,
+    health: &Health,
+    task: EvalTask,
+) -> Result<(), String> {
+    match dispatch.eval_sender.try_send(task) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            warn!("Evaluation queue is full, turning away a task");
+            health.eval_rejected();
+            Err("the evaluation queue is full, try again later".to_owned())
+        }
+        Err(TrySendError::Closed(_)) => {
+            Err("the evaluator is not running, please report this to the operators".to_owned())
+        }
+    }
+}
+
+async fn status_response(context: &RequestContext) -> Result<shared::types::StatusResponse> {
+    use shared::types as t;
+
+    let (states, running, evaluations, builders) = tokio::join!(
+        context.db_service.count_drvs_by_state(),
+        context.db_service.running_builds(),
+        context.db_service.recent_evaluations(RECENT_EVALUATIONS),
+        context.health.check_builders(),
+    );
+    let eval_sender = &context.dispatch.eval_sender;
+    let now = Utc::now();
+    let seconds = |delta: TimeDelta| delta.num_seconds().max(0) as u64;
+
+    Ok(t::StatusResponse {
+        states: states?
+            .into_iter()
+            .map(|(state, count)| t::StateCount {
+                state,
+                count: count as u64,
+            })
+            .collect(),
+        running: running?
+            .into_iter()
+            .map(|build| t::RunningBuild {
+                drv_path: build.derivation,
+                build_attempt: build.build_attempt,
+                system: build.system,
+                elapsed: seconds(now - build.since),
+            })
+            .collect(),
+        eval_queue: eval_sender.max_capacity() - eval_sender.capacity(),
+        evaluations: evaluations?
+            .into_iter()
+            .map(|evaluation| t::EvaluationSummary {
+                outcome: evaluation_outcome(&evaluation),
+                id: evaluation.id,
+                jobset: evaluation.jobset,
+                pr: evaluation.pr.map(|pr| pr as u64),
+                queued: evaluation.queued.timestamp(),
+                duration: evaluation
+                    .started
+                    .zip(evaluation.finished)
+                    .map(|(started, finished)| seconds(finished - started)),
+            })
+            .collect(),
+        builders,
+    })
+}
+
+fn evaluation_outcome(evaluation: &Evaluation) -> shared::types::EvaluationOutcome {
+    use shared::types::EvaluationOutcome;
+
+    match (evaluation.finished, &evaluation.error) {
+        (None, _) if evaluation.started.is_none() => EvaluationOutcome::Queued,
+        (None, _) => EvaluationOutcome::Running,
+        (Some(_), Some(error)) => EvaluationOutcome::Failed {
+            error: error.clone(),
+        },
+        (Some(_), None) => EvaluationOutcome::Succeeded {
+            drvs: evaluation.drvs.unwrap_or_default() as u64,
+        },
+    }
+}
+
+fn hello_response(hello: shared::types::HelloRequest) -> ClientResponse {
+    use shared::types::{self as t, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    let version = env!("CARGO_PKG_VERSION");
+    info!(
+        "Client is ekaci {} speaking protocol {}",
+        hello.version, hello.protocol
+    );
+
+    // Clients newer than the server decide themselves whether they can still speak our protocol
+    if hello.protocol < MIN_PROTOCOL_VERSION {
+        return error_response(format!(
+            "ekaci {} speaks protocol {}, but server {version} requires at least protocol \
+             {MIN_PROTOCOL_VERSION}, please upgrade ekaci",
+            hello.version, hello.protocol
+        ));
+    }
+
+    ClientResponse::Hello(t::HelloResponse {
+        protocol: PROTOCOL_VERSION,
+        version: version.to_owned(),
+        features: t::features::ALL.iter().map(|&f| f.to_owned()).collect(),
+    })
+}
 
-            // TODO: We should likely return a URL to build status
-            resp::Build(t::BuildResponse { enqueued: true })
+/// Explain why a derivation failed, by the dependencies that failed on their own and their logs.
+async fn why_failed_response(
+    request: shared::types::WhyFailedRequest,
+    context: &RequestContext,
+) -> Result<ClientResponse> {
+    use shared::types as t;
+
+    let drv = match (request.drv_path, request.attr) {
+        (Some(drv), None) => drv,
+        (None, Some(attr)) => match context.db_service.find_attr_drv(&attr).await? {
+            Some(drv) => drv,
+            None => {
+                return Ok(error_response(format!(
+                    "no derivation is exposed as {attr}"
+                )))
+            }
+        },
+        _ => {
+            let message = "explain either a derivation or an attribute".to_owned();
+            return Ok(error_response(message));
         }
+    };
+
+    let state = context
+        .db_service
+        .latest_build_event(&drv)
+        .await?
+        .map(|stored| stored.event.state);
+    let mut root_failures = Vec::new();
+    for root in context.db_service.root_failures(&drv).await? {
+        let failed = root.chain.last().expect("chains are never empty");
+        let mut log_tail = VecDeque::new();
+        if let Some(log) = context.logs.open(failed, None).await? {
+            // Only the requested tail is kept while the log is streamed
+            let mut lines = std::pin::pin!(log.lines());
+            while let Some(line) = lines.next().await {
+                log_tail.push_back(line?.to_string());
+                if log_tail.len() > request.lines {
+                    log_tail.pop_front();
+                }
+            }
+        }
+        root_failures.push(t::RootFailure {
+            chain: root.chain,
+            state: root.state,
+            log_tail: log_tail.into(),
+        });
+    }
+
+    Ok(ClientResponse::WhyFailed(t::WhyFailedResponse {
+        drv_path: drv,
+        state,
+        root_failures,
+    }))
+}
+
+/// Stream the log of a derivation in chunks of lines.
+///
+/// The log of a running build is replayed, afterwards new lines are streamed until the build
+/// finishes if the client asked to follow it. Otherwise the latest stored log is sent.
+async fn stream_log(request: shared::types::LogRequest, logs: &LogStore, responder: Responder) {
+    use shared::types::LogResponse;
+
+    let (replay, receiver) = match logs.follow(&request.drv_path, None) {
+        Some(follower) => (follower.replay, request.follow.then_some(follower.receiver)),
+        None => match logs.open(&request.drv_path, None).await {
+            Ok(Some(log)) => (log.lines().boxed(), None),
+            Ok(None) => {
+                let message = format!("no log exists for {}", request.drv_path);
+                return responder.finish(error_response(message)).await;
+            }
+            Err(e) => {
+                warn!("Failed to read log of {}: {:?}", request.drv_path, e);
+                let message = format!("failed to read log of {}", request.drv_path);
+                return responder.finish(error_response(message)).await;
+            }
+        },
+    };
+
+    let mut replay = replay.chunks(LOG_CHUNK_LINES);
+    while let Some(chunk) = replay.next().await {
+        let lines = match chunk
+            .into_iter()
+            .map(|line| line.map(|line| line.to_string()))
+            .collect()
+        {
+            Ok(lines) => lines,
+            Err(e) => {
+                warn!("Failed to read log of {}: {:?}", request.drv_path, e);
+                let message = format!("failed to read log of {}", request.drv_path);
+                return responder.finish(error_response(message)).await;
+            }
+        };
+        if responder
+            .send(ClientResponse::Log(LogResponse { lines, skipped: 0 }))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    if let Some(mut receiver) = receiver {
+        loop {
+            let first = tokio::select! {
+                first = receiver.recv() => first,
+                // Otherwise a quiet build would keep the request alive after the client left
+                _ = responder.closed() => return,
+            };
+            let mut chunk = LogResponse {
+                lines: Vec::new(),
+                skipped: 0,
+            };
+            match first {
+                Ok(line) => chunk.lines.push(line.to_string()),
+                Err(RecvError::Lagged(skipped)) => chunk.skipped = skipped,
+                Err(RecvError::Closed) => break,
+            }
+            // Send lines arriving in bursts together
+            while chunk.lines.len() < LOG_CHUNK_LINES {
+                match receiver.try_recv() {
+                    Ok(line) => chunk.lines.push(line.to_string()),
+                    Err(_) => break,
+                }
+            }
+            if responder.send(ClientResponse::Log(chunk)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // An empty chunk marks the end of the log
+    responder
+        .finish(ClientResponse::Log(LogResponse {
+            lines: Vec::new(),
+            skipped: 0,
+        }))
+        .await;
+}
+
+fn admin_response(result: Result<Vec<StoredBuildEvent>, AdminError>) -> ClientResponse {
+    use shared::types as t;
+
+    match result {
+        Ok(events) => ClientResponse::Admin(t::AdminResponse {
+            builds: events
+                .into_iter()
+                .map(|stored| t::BuildStateChange {
+                    drv_path: stored.event.build.derivation,
+                    build_attempt: stored.event.build.build_attempt.get(),
+                    state: stored.event.state,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            if matches!(e, AdminError::Event(_) | AdminError::Database(_)) {
+                warn!("Failed to handle admin request: {:?}", e);
+            }
+            ClientResponse::Error(t::ErrorResponse {
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::types::ServerStatus;
+    use sqlx::SqlitePool;
+
+    use crate::db::model::build::{dummy_drv_id, insert_raw_build_event};
+    use crate::nix::NixStore;
+
+    use super::*;
+
+    /// The evaluator is considered dead once the returned receiver is dropped.
+    pub(super) fn context(
+        pool: SqlitePool,
+        log_dir: &Path,
+    ) -> anyhow::Result<(RequestContext, Receiver<EvalTask>)> {
+        let db_service = DbService::from_pool(pool);
+        let (eval_sender, eval_receiver) = mpsc::channel(1);
+
+        let context = RequestContext {
+            dispatch: DispatchChannels {
+                eval_sender: eval_sender.clone(),
+            },
+            health: Health::new(db_service.clone(), eval_sender, None, Vec::new()),
+            db_service,
+            logs: LogStore::new(log_dir.to_owned(), NixStore::new(None, None)?),
+            public_url: "http://ci.example.org".to_owned(),
+            access: None,
+        };
+
+        Ok((context, eval_receiver))
+    }
+
+    #[tokio::test]
+    async fn bind_with_permissions() -> anyhow::Result<()> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ekaci.socket");
+        std::fs::write(&path, "left behind by an earlier run")?;
+        let gid = rustix::process::getgid().as_raw();
+
+        let _listener = bind_private(&path, Some(0o660), Some(gid))?;
+        let metadata = std::fs::metadata(&path)?;
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.mode() & 0o777, 0o660);
+        assert_eq!(metadata.gid(), gid);
+        // the private directory is cleaned up
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        UnixStream::connect(&path).await?;
+
+        Ok(())
+    }
+
+    /// Send `messages` on a new connection and return everything the server sent back.
+    async fn exchange(context: RequestContext, messages: &[&str]) -> anyhow::Result<String> {
+        let (mut client, server) = UnixStream::pair()?;
+        let server = tokio::spawn(handle_client(server, context));
+
+        client.write_all(messages.join("\n").as_bytes()).await?;
+        client.shutdown().await?;
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await?;
+        server.await??;
+
+        Ok(responses)
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn multiplexed_requests(pool: SqlitePool) -> anyhow::Result<()> {
+        let log_dir = tempfile::tempdir()?;
+        let requests = [
+            r#"{"id":1,"request":{"type":"Info"}}"#,
+            r#"{"id":2,"request":{"type":"Log","drv_path":"jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"}}"#,
+            r#"{"id":3,"request":{"type":"Build","drv_path":"not a derivation"}}"#,
+            "not even json",
+        ];
+        let (context, _eval_receiver) = context(pool, log_dir.path())?;
+        let responses = exchange(context, &requests).await?;
+
+        let mut frames = responses
+            .lines()
+            .map(serde_json::from_str::<ResponseFrame>)
+            .collect::<Result<Vec<_>, _>>()?;
+        frames.sort_by_key(|frame| frame.id);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|frame| !frame.more));
+
+        assert_eq!(frames[0].id, None);
+        assert!(matches!(frames[0].response, ClientResponse::Error(_)));
+        assert!(matches!(
+            &frames[1].response,
+            ClientResponse::Info(info) if info.status != ServerStatus::Dead
+        ));
+        assert!(matches!(
+            &frames[2].response,
+            ClientResponse::Error(e) if e.message.starts_with("no log exists")
+        ));
+        assert!(matches!(
+            &frames[3].response,
+            ClientResponse::Error(e) if e.message.starts_with("malformed request")
+        ));
+        assert_eq!(frames[3].id, Some(3));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn version_handshake(pool: SqlitePool) -> anyhow::Result<()> {
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool, log_dir.path())?;
+
+        // clients newer than the server are answered, so they can fall back
+        let responses = exchange(
+            context.clone(),
+            &[r#"{"id":1,"request":{"type":"Hello","protocol":99,"version":"9.0.0","features":[]}}"#],
+        )
+        .await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Hello(hello) if hello.protocol == shared::types::PROTOCOL_VERSION
+                && hello.features.iter().any(|f| f == shared::types::features::LOG)
+        ));
+
+        let responses = exchange(
+            context.clone(),
+            &[r#"{"id":1,"request":{"type":"Hello","protocol":0,"version":"0.0.1","features":[]}}"#],
+        )
+        .await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Error(e) if e.message.contains("please upgrade ekaci")
+        ));
+
+        // protocol 0 clients send a bare request without a trailing newline
+        let responses = exchange(context, &[r#"{"type":"Info"}"#]).await?;
+        let response: ClientResponse = serde_json::from_str(&responses)?;
+        assert!(matches!(response, ClientResponse::Info(_)));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn queued_identifiers(pool: SqlitePool) -> anyhow::Result<()> {
+        use shared::build::{DrvBuildResult, DrvBuildState};
+
+        let log_dir = tempfile::tempdir()?;
+        let (context, mut eval_receiver) = context(pool.clone(), log_dir.path())?;
+        let success = DrvBuildState::Completed(DrvBuildResult::Success);
+        insert_raw_build_event(&pool, &dummy_drv_id(), 1, success, None).await?;
+
+        let responses = exchange(
+            context.clone(),
+            &[r#"{"id":1,"request":{"type":"Job","file_path":"/ci/release.nix","pr":7}}"#],
+        )
+        .await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        let ClientResponse::Job(job) = frame.response else {
+            panic!("unexpected response {:?}", frame.response);
+        };
+        assert_eq!(job.pr, Some(7));
+        assert_eq!(
+            job.url,
+            format!("http://ci.example.org/v1/evaluations/{}", job.evaluation)
+        );
+        assert!(matches!(
+            eval_receiver.recv().await,
+            Some(EvalTask::Job(queued)) if queued.evaluation == job.evaluation
+        ));
+        let evaluation = context.db_service.evaluation(job.evaluation).await?;
+        assert!(evaluation.is_some_and(|e| e.started.is_none()));
+
+        // a drv that was built before answers with its result
+        let responses = exchange(
+            context,
+            &[r#"{"id":1,"request":{"type":"Build","drv_path":"jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"}}"#],
+        )
+        .await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Build(build) if build.build_attempt == Some(1)
+                && build.state == Some(success)
+                && build.url.ends_with("/v1/drvs/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn rejected_requests(pool: SqlitePool) -> anyhow::Result<()> {
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool, log_dir.path())?;
+        let job = r#"{"id":1,"request":{"type":"Job","file_path":"/ci/release.nix"}}"#;
+
+        // the queue holds a single task
+        exchange(context.clone(), &[job]).await?;
+        let responses = exchange(context.clone(), &[job]).await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Error(e) if e.message.contains("queue is full")
+        ));
+        let evaluations = context.db_service.recent_evaluations(1).await?;
+        assert!(evaluations[0].error.is_some());
+        assert_eq!(context.health.liveness().status, ServerStatus::Degraded);
+
+        let oversized = format!(
+            r#"{{"id":1,"request":{{"type":"Job","file_path":"{}"}}}}"#,
+            "a".repeat(MAX_MESSAGE_SIZE)
+        );
+        let responses = exchange(context, &[&oversized, job]).await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Error(e) if e.message.contains("exceeds")
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn peer_permissions(pool: SqlitePool) -> anyhow::Result<()> {
+        use crate::config::Peers;
+
+        let log_dir = tempfile::tempdir()?;
+        let (mut context, _eval_receiver) = context(pool, log_dir.path())?;
+        context.access = Some(Arc::new(SocketAccess {
+            read: Peers {
+                uids: vec![rustix::process::getuid().as_raw()],
+                gids: Vec::new(),
+            },
+            ..Default::default()
+        }));
+
+        let responses = exchange(
+            context.clone(),
+            &[
+                r#"{"id":1,"request":{"type":"Status"}}"#,
+                r#"{"id":2,"request":{"type":"Job","file_path":"/ci/release.nix"}}"#,
+            ],
+        )
+        .await?;
+        let mut frames = responses
+            .lines()
+            .map(serde_json::from_str::<ResponseFrame>)
+            .collect::<Result<Vec<_>, _>>()?;
+        frames.sort_by_key(|frame| frame.id);
+        assert!(matches!(frames[0].response, ClientResponse::Status(_)));
+        assert!(matches!(
+            &frames[1].response,
+            ClientResponse::Error(e) if e.message.contains("needs submit access")
+        ));
+
+        // unlisted peers may not even say hello
+        context.access = Some(Arc::new(SocketAccess::default()));
+        let responses = exchange(context, &[r#"{"id":1,"request":{"type":"Info"}}"#]).await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Error(e) if e.message.contains("not allowed to use this socket")
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn remote_requests(pool: SqlitePool) -> anyhow::Result<()> {
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool, log_dir.path())?;
+        let handler = RequestHandler { context };
+        let frame = |id, request: &str| -> anyhow::Result<RequestFrame> {
+            Ok(serde_json::from_str(&format!(
+                r#"{{"id":{id},"request":{request}}}"#
+            ))?)
+        };
+
+        let mut frames = handler.handle(
+            frame(1, r#"{"type":"Status"}"#)?,
+            "token laptop".to_owned(),
+            Permission::Read,
+        );
+        let response = frames.recv().await.expect("a response frame");
+        assert_eq!(response.id, Some(1));
+        assert!(!response.more);
+        assert!(matches!(response.response, ClientResponse::Status(_)));
+        assert!(frames.recv().await.is_none());
+
+        let mut frames = handler.handle(
+            frame(2, r#"{"type":"Job","file_path":"/ci/release.nix"}"#)?,
+            "token laptop".to_owned(),
+            Permission::Read,
+        );
+        let response = frames.recv().await.expect("a response frame");
+        assert!(matches!(
+            response.response,
+            ClientResponse::Error(e) if e.message
+                == "permission denied: token laptop only has read access, the request needs \
+                    submit access"
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn why_failed_log_tail(pool: SqlitePool) -> anyhow::Result<()> {
+        use shared::build::{DrvBuildResult, DrvBuildState};
+
+        use crate::db::model::build::DrvBuildId;
+
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool.clone(), log_dir.path())?;
+        let drv = dummy_drv_id();
+        let failed = DrvBuildState::Completed(DrvBuildResult::Failure);
+        insert_raw_build_event(&pool, &drv, 1, failed, None).await?;
+        let build = DrvBuildId {
+            derivation: drv.clone(),
+            build_attempt: std::num::NonZeroU32::MIN,
+        };
+        context
+            .logs
+            .capture(
+                &build,
+                &b"configuring\nbuilding\nerror: failed\n"[..],
+                tokio::io::empty(),
+            )
+            .await?;
+
+        let request =
+            format!(r#"{{"id":1,"request":{{"type":"WhyFailed","drv_path":"{drv}","lines":2}}}}"#);
+        let responses = exchange(context, &[&request]).await?;
+        let frame: ResponseFrame = serde_json::from_str(&responses)?;
+        let ClientResponse::WhyFailed(response) = frame.response else {
+            panic!("unexpected response {:?}", frame.response);
+        };
+        assert_eq!(response.root_failures.len(), 1);
+        assert_eq!(response.root_failures[0].chain, [drv]);
+        assert_eq!(
+            response.root_failures[0].log_tail,
+            ["building", "error: failed"]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn watch_drv_until_outcome(pool: SqlitePool) -> anyhow::Result<()> {
+        use shared::build::{DrvBuildResult, DrvBuildState};
+        use shared::types::{BuildOutcome, WatchEvent};
+
+        use crate::events::{BuildEvent, ServerEvent};
+
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool.clone(), log_dir.path())?;
+        let events = context.db_service.events().clone();
+        let drv = dummy_drv_id();
+        insert_raw_build_event(&pool, &drv, 1, DrvBuildState::Building, None).await?;
+
+        let (client, server) = UnixStream::pair()?;
+        let server = tokio::spawn(handle_client(server, context));
+        let (reader, mut writer) = client.into_split();
+        let mut frames = BufReader::new(reader).lines();
+        writer
+            .write_all(
+                br#"{"id":1,"request":{"type":"Watch","drv_path":"jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"}}
+"#,
+            )
+            .await?;
+        let mut next_frame = async || -> anyhow::Result<ResponseFrame> {
+            let line = frames.next_line().await?.expect("server sends more frames");
+            Ok(serde_json::from_str(&line)?)
+        };
+
+        // the current state is sent first
+        let frame = next_frame().await?;
+        assert!(frame.more);
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Watch(WatchEvent::Build(change)) if change.state == DrvBuildState::Building
+        ));
+
+        let failed = DrvBuildState::Completed(DrvBuildResult::Failure);
+        insert_raw_build_event(&pool, &drv, 1, failed, None).await?;
+        events.publish(ServerEvent::Build(BuildEvent {
+            id: 2,
+            drv: drv.clone(),
+            build_attempt: 1,
+            state: failed,
+            timestamp: 0,
+            jobsets: Default::default(),
+        }));
+
+        let frame = next_frame().await?;
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Watch(WatchEvent::Build(change)) if change.state == failed
+        ));
+        let frame = next_frame().await?;
+        assert!(!frame.more);
+        assert!(matches!(
+            frame.response,
+            ClientResponse::Watch(WatchEvent::Done(BuildOutcome::Failed))
+        ));
+
+        writer.shutdown().await?;
+        server.await??;
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn abandon_requests_on_hangup(pool: SqlitePool) -> anyhow::Result<()> {
+        use shared::build::DrvBuildState;
+
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = context(pool.clone(), log_dir.path())?;
+        let events = context.db_service.events().clone();
+        let drv = dummy_drv_id();
+        insert_raw_build_event(&pool, &drv, 1, DrvBuildState::Building, None).await?;
+
+        let (client, server) = UnixStream::pair()?;
+        let server = tokio::spawn(handle_client(server, context));
+        let (reader, mut writer) = client.into_split();
+        let mut frames = BufReader::new(reader).lines();
+        writer
+            .write_all(
+                br#"{"id":1,"request":{"type":"Watch","drv_path":"jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"}}
+"#,
+            )
+            .await?;
+        frames
+            .next_line()
+            .await?
+            .expect("the current state is sent");
+        assert_eq!(events.subscribers(), 1);
+
+        // Only shutting down the sending side keeps the watch going
+        writer.shutdown().await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!server.is_finished());
+        assert_eq!(events.subscribers(), 1);
+
+        drop(frames);
+        drop(writer);
+        tokio::time::timeout(Duration::from_secs(5), server).await???;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while events.subscribers() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        Ok(())
     }
 }
@@ -0,0 +1,185 @@
+//! Authorization of socket clients by their peer credentials.
+//!
+//! Only the uid and the primary gid of the connecting process are known to the kernel, membership
+//! in supplementary groups does not grant access.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use shared::types::ClientRequest;
+use tokio::net::unix::UCred;
+
+use crate::config::{Peers, SocketAccess};
+
+/// The credentials of a connected process.
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<UCred> for Peer {
+    fn from(cred: UCred) -> Self {
+        Self {
+            uid: cred.uid(),
+            gid: cred.gid(),
+        }
+    }
+}
+
+/// What a client may do. Each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Information about the server, builds and their logs
+    Read,
+    /// Queueing builds and evaluations
+    Submit,
+    /// Restarting, cancelling and invalidating builds
+    Admin,
+}
+
+impl Permission {
+    /// The permission needed to make `request`.
+    pub fn required(request: &ClientRequest) -> Self {
+        match request {
+            ClientRequest::Hello(_)
+            | ClientRequest::Info
+            | ClientRequest::Status
+            | ClientRequest::Log(_)
+            | ClientRequest::Watch(_)
+            | ClientRequest::WhyFailed(_) => Permission::Read,
+            ClientRequest::Build(_) | ClientRequest::Job(_) => Permission::Submit,
+            ClientRequest::Restart(_)
+            | ClientRequest::Cancel(_)
+            | ClientRequest::Invalidate(_)
+            | ClientRequest::RestartFailures(_) => Permission::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Read => "read",
+            Permission::Submit => "submit",
+            Permission::Admin => "admin",
+        })
+    }
+}
+
+/// The highest permission `access` grants to the peer, `None` if it may not use the socket at all.
+pub fn permission(access: &SocketAccess, peer: Peer) -> Option<Permission> {
+    let contains = |peers: &Peers| peers.uids.contains(&peer.uid) || peers.gids.contains(&peer.gid);
+
+    [
+        (Permission::Admin, &access.admin),
+        (Permission::Submit, &access.submit),
+        (Permission::Read, &access.read),
+    ]
+    .into_iter()
+    .find(|(_, peers)| contains(peers))
+    .map(|(permission, _)| permission)
+}
+
+/// The permission of the peer if no access rules are configured. Any process able to open the
+/// socket may read, only the user running the server may submit and administer builds.
+pub fn default_permission(peer: Peer, server_uid: u32) -> Permission {
+    if peer.uid == server_uid {
+        Permission::Admin
+    } else {
+        Permission::Read
+    }
+}
+
+/// The permission `request` needs, if `granted` does not include it.
+pub fn missing(request: &ClientRequest, granted: Permission) -> Option<Permission> {
+    let required = Permission::required(request);
+    (granted < required).then_some(required)
+}
+
+/// Why a request was rejected, `None` if `granted` allows it.
+pub fn denial(request: &ClientRequest, peer: Peer, granted: Option<Permission>) -> Option<String> {
+    match granted {
+        Some(granted) => missing(request, granted).map(|required| {
+            format!(
+                "permission denied: uid {} only has {granted} access to this socket, the request \
+                 needs {required} access",
+                peer.uid
+            )
+        }),
+        None => Some(format!(
+            "permission denied: uid {} (gid {}) is not allowed to use this socket",
+            peer.uid, peer.gid
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::types::LogRequest;
+
+    use super::*;
+
+    #[test]
+    fn permission_levels() -> anyhow::Result<()> {
+        let access = SocketAccess {
+            read: Peers {
+                uids: vec![1001],
+                gids: vec![100],
+            },
+            submit: Peers {
+                uids: vec![1002],
+                gids: Vec::new(),
+            },
+            admin: Peers {
+                uids: vec![1002],
+                gids: vec![10],
+            },
+        };
+        let peer = |uid, gid| Peer { uid, gid };
+
+        assert_eq!(
+            permission(&access, peer(1001, 1001)),
+            Some(Permission::Read)
+        );
+        assert_eq!(permission(&access, peer(1003, 100)), Some(Permission::Read));
+        // the highest matching level wins
+        assert_eq!(
+            permission(&access, peer(1002, 100)),
+            Some(Permission::Admin)
+        );
+        assert_eq!(permission(&access, peer(1001, 10)), Some(Permission::Admin));
+        assert_eq!(permission(&access, peer(1004, 1004)), None);
+
+        let log = ClientRequest::Log(LogRequest {
+            drv_path: "jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv".parse()?,
+            follow: false,
+        });
+        assert!(denial(&log, peer(1001, 1001), Some(Permission::Read)).is_none());
+        assert!(denial(&log, peer(1004, 1004), None).is_some());
+        assert!(denial(
+            &ClientRequest::Info,
+            peer(1001, 1001),
+            Some(Permission::Read)
+        )
+        .is_none());
+        let job = ClientRequest::Job(shared::types::JobRequest {
+            file_path: "/ci/release.nix".to_owned(),
+            pr: None,
+        });
+        assert!(denial(&job, peer(1001, 1001), Some(Permission::Read))
+            .is_some_and(|reason| reason.contains("needs submit access")));
+        assert!(denial(&job, peer(1002, 1002), Some(Permission::Submit)).is_none());
+
+        // without rules, only the server's own user may submit and administer builds
+        assert_eq!(default_permission(peer(1001, 100), 1001), Permission::Admin);
+        let granted = default_permission(peer(1002, 100), 1001);
+        let status = ClientRequest::Status;
+        assert!(denial(&status, peer(1002, 100), Some(granted)).is_none());
+        assert!(denial(&job, peer(1002, 100), Some(granted))
+            .is_some_and(|reason| reason.contains("needs submit access")));
+
+        Ok(())
+    }
+}
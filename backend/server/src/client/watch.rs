@@ -0,0 +1,391 @@
+//! Following a derivation or a job until its builds end, see [`WatchEvent`].
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use shared::build::DrvBuildState;
+use shared::store::DrvId;
+use shared::types::{
+    self as t, BuildOutcome, ClientResponse, EvaluationOutcome, WatchEvent, WatchRequest,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use super::{error_response, evaluation_outcome, RequestContext, Responder, LOG_CHUNK_LINES};
+use crate::db::jobset::BuildEventFilter;
+use crate::events::{EvalEventKind, ServerEvent};
+
+/// Builds are marked as building slightly before their log capture starts, so attaching to the
+/// log is retried this often.
+const LOG_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A derivation is traversed after `ekaci build` answered, so a watched derivation may not have
+/// any build events yet. If it still has none after this long, it is not going to be built.
+const SCHEDULE_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub(super) async fn watch(request: WatchRequest, context: &RequestContext, responder: Responder) {
+    // Subscribe before looking at the current state, so that no change is missed in between
+    let events = context.db_service.events().subscribe();
+
+    let result = match (request.drv_path, request.job) {
+        (Some(drv), None) => {
+            let log = request.log;
+            watch_drv(drv, log, SCHEDULE_TIMEOUT, events, context, &responder).await
+        }
+        (None, Some(job)) => {
+            let evaluation = request.evaluation;
+            watch_job(&job, request.pr, evaluation, events, context, &responder).await
+        }
+        _ => {
+            let message = "watch either a derivation or a job".to_owned();
+            return responder.finish(error_response(message)).await;
+        }
+    };
+
+    match result {
+        Ok(Some(outcome)) => {
+            responder
+                .finish(ClientResponse::Watch(WatchEvent::Done(outcome)))
+                .await
+        }
+        // The client is gone
+        Ok(None) => {}
+        Err(e) => {
+            warn!("Failed to watch builds: {:?}", e);
+            responder
+                .finish(error_response(format!("failed to watch builds: {e}")))
+                .await
+        }
+    }
+}
+
+async fn send(responder: &Responder, event: WatchEvent) -> Result<()> {
+    responder.send(ClientResponse::Watch(event)).await
+}
+
+async fn send_state(
+    responder: &Responder,
+    drv: &DrvId,
+    build_attempt: u32,
+    state: DrvBuildState,
+) -> Result<()> {
+    let change = t::BuildStateChange {
+        drv_path: drv.clone(),
+        build_attempt,
+        state,
+    };
+    send(responder, WatchEvent::Build(change)).await
+}
+
+/// Follow `drv` until its latest build attempt has an outcome. Returns `None` if the client left.
+///
+/// Fails if `drv` was not scheduled for building within `schedule_timeout`.
+async fn watch_drv(
+    drv: DrvId,
+    follow_log: bool,
+    schedule_timeout: Duration,
+    mut events: broadcast::Receiver<ServerEvent>,
+    context: &RequestContext,
+    responder: &Responder,
+) -> Result<Option<BuildOutcome>> {
+    let latest_state = || async {
+        let event = context.db_service.latest_build_event(&drv).await?;
+        anyhow::Ok(event.map(|stored| (stored.event.build.build_attempt.get(), stored.event.state)))
+    };
+
+    let mut current = latest_state().await?;
+    if let Some((attempt, state)) = current {
+        send_state(responder, &drv, attempt, state).await?;
+    }
+
+    let mut log = None;
+    let mut log_attempt = None;
+    let mut log_retry = tokio::time::interval(LOG_RETRY_INTERVAL);
+    let scheduled = tokio::time::sleep(schedule_timeout);
+    tokio::pin!(scheduled);
+    loop {
+        if let Some(outcome) = current.and_then(|(_, state)| BuildOutcome::of(state)) {
+            // Lines logged right before the build ended should not be lost
+            if let Some(mut receiver) = log.take() {
+                send_log_lines(responder, &mut receiver, None).await?;
+            }
+            return Ok(Some(outcome));
+        }
+
+        let wants_log = follow_log
+            && matches!(current, Some((attempt, DrvBuildState::Building)) if log_attempt != Some(attempt));
+
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(ServerEvent::Build(event)) if event.drv == drv => {
+                    send_state(responder, &drv, event.build_attempt, event.state).await?;
+                    current = Some((event.build_attempt, event.state));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    let latest = latest_state().await?;
+                    if latest != current {
+                        if let Some((attempt, state)) = latest {
+                            send_state(responder, &drv, attempt, state).await?;
+                        }
+                        current = latest;
+                    }
+                }
+                Err(RecvError::Closed) => anyhow::bail!("the server is shutting down"),
+            },
+            line = async { log.as_mut().expect("only polled while following").recv().await },
+                if log.is_some() =>
+            {
+                match line {
+                    Ok(line) => {
+                        let receiver = log.as_mut().expect("only polled while following");
+                        send_log_lines(responder, receiver, Some(line)).await?;
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        let chunk = t::LogResponse {
+                            lines: Vec::new(),
+                            skipped,
+                        };
+                        send(responder, WatchEvent::Log(chunk)).await?;
+                    }
+                    Err(RecvError::Closed) => log = None,
+                }
+            }
+            _ = log_retry.tick(), if wants_log => {
+                let attempt = current.and_then(|(attempt, _)| NonZeroU32::new(attempt));
+                if let Some(follower) = context.logs.follow(&drv, attempt) {
+                    let mut replay = follower.replay.chunks(LOG_CHUNK_LINES);
+                    while let Some(chunk) = replay.next().await {
+                        let chunk = t::LogResponse {
+                            lines: chunk
+                                .into_iter()
+                                .map(|line| line.map(|line| line.to_string()))
+                                .collect::<Result<_>>()?,
+                            skipped: 0,
+                        };
+                        send(responder, WatchEvent::Log(chunk)).await?;
+                    }
+                    log = Some(follower.receiver);
+                    log_attempt = current.map(|(attempt, _)| attempt);
+                }
+            }
+            _ = &mut scheduled, if current.is_none() => {
+                // An event may have been missed while lagging behind
+                current = latest_state().await?;
+                match current {
+                    Some((attempt, state)) => send_state(responder, &drv, attempt, state).await?,
+                    None if context.db_service.has_drv(&drv).await? => {
+                        anyhow::bail!("{drv} was never scheduled for building")
+                    }
+                    None => anyhow::bail!("{drv} is unknown to the server"),
+                }
+            }
+            _ = responder.closed() => return Ok(None),
+        }
+    }
+}
+
+/// Send `first` and whatever other lines are already waiting, as a single chunk.
+async fn send_log_lines(
+    responder: &Responder,
+    receiver: &mut broadcast::Receiver<Arc<str>>,
+    first: Option<Arc<str>>,
+) -> Result<()> {
+    let mut lines: Vec<String> = first.iter().map(|line| line.to_string()).collect();
+    while lines.len() < LOG_CHUNK_LINES {
+        match receiver.try_recv() {
+            Ok(line) => lines.push(line.to_string()),
+            Err(_) => break,
+        }
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    send(
+        responder,
+        WatchEvent::Log(t::LogResponse { lines, skipped: 0 }),
+    )
+    .await
+}
+
+/// Follow the evaluation of `jobset` and then the builds of the derivations it exposed, until
+/// all of them have an outcome. Returns `None` if the client left.
+///
+/// Without an `evaluation` id, the latest evaluation is followed if the jobset was evaluated
+/// before, and a new evaluation replaces it as soon as it starts.
+async fn watch_job(
+    jobset: &str,
+    pr: Option<u64>,
+    evaluation: Option<i64>,
+    mut events: broadcast::Receiver<ServerEvent>,
+    context: &RequestContext,
+    responder: &Responder,
+) -> Result<Option<BuildOutcome>> {
+    let filter = BuildEventFilter {
+        drv: None,
+        jobset: Some(jobset.to_owned()),
+        pr,
+    };
+
+    let current = match evaluation {
+        Some(id) => match context.db_service.evaluation(id).await? {
+            Some(current) if current.jobset == jobset && current.pr == pr.map(|pr| pr as i64) => {
+                Some(current)
+            }
+            _ => anyhow::bail!("evaluation {id} of {jobset} does not exist"),
+        },
+        None => context.db_service.latest_evaluation(jobset, pr).await?,
+    };
+
+    // The succeeded evaluation whose builds are followed
+    let mut evaluated = None;
+    if let Some(evaluation) = current {
+        let outcome = evaluation_outcome(&evaluation);
+        if matches!(outcome, EvaluationOutcome::Succeeded { .. }) {
+            evaluated = Some(evaluation.id);
+        }
+        let failed = matches!(outcome, EvaluationOutcome::Failed { .. });
+        send(responder, WatchEvent::Evaluation(outcome)).await?;
+        if failed {
+            return Ok(Some(BuildOutcome::Failed));
+        }
+    }
+
+    // Whether the builds may have ended since they were last looked at
+    let mut changed = true;
+    loop {
+        if let Some(id) = evaluated.filter(|_| changed) {
+            let states = context.db_service.evaluation_states(id).await?;
+            if let Some(outcome) = jobset_outcome(&states) {
+                return Ok(Some(outcome));
+            }
+        }
+        changed = false;
+
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(ServerEvent::Eval(eval))
+                    if eval.jobset == jobset
+                        && eval.pr == pr
+                        && evaluation.is_none_or(|id| id == eval.evaluation) =>
+                {
+                    let outcome = match eval.kind {
+                        EvalEventKind::Started => EvaluationOutcome::Running,
+                        EvalEventKind::Finished { drvs } => EvaluationOutcome::Succeeded {
+                            drvs: drvs as u64,
+                        },
+                        EvalEventKind::Failed { error } => EvaluationOutcome::Failed { error },
+                    };
+                    evaluated = matches!(outcome, EvaluationOutcome::Succeeded { .. })
+                        .then_some(eval.evaluation);
+                    changed = true;
+                    let failed = matches!(outcome, EvaluationOutcome::Failed { .. });
+                    send(responder, WatchEvent::Evaluation(outcome)).await?;
+                    if failed {
+                        return Ok(Some(BuildOutcome::Failed));
+                    }
+                }
+                Ok(ServerEvent::Build(event)) => {
+                    if filter.matches(&event) {
+                        send_state(responder, &event.drv, event.build_attempt, event.state)
+                            .await?;
+                        changed = true;
+                    }
+                }
+                Ok(ServerEvent::Eval(_)) => {}
+                // The outcome is checked against the database anyways
+                Err(RecvError::Lagged(_)) => changed = true,
+                Err(RecvError::Closed) => anyhow::bail!("the server is shutting down"),
+            },
+            _ = responder.closed() => return Ok(None),
+        }
+    }
+}
+
+/// The combined outcome of all builds, `None` while any of them is still in progress or was not
+/// scheduled yet. Failures take precedence over interruptions.
+fn jobset_outcome(states: &[Option<DrvBuildState>]) -> Option<BuildOutcome> {
+    let outcomes = states
+        .iter()
+        .map(|state| state.and_then(BuildOutcome::of))
+        .collect::<Option<Vec<_>>>()?;
+
+    [BuildOutcome::Failed, BuildOutcome::Interrupted]
+        .into_iter()
+        .find(|outcome| outcomes.contains(outcome))
+        .or(Some(BuildOutcome::Succeeded))
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::build::{DrvBuildInterruptionKind, DrvBuildResult};
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc;
+
+    use crate::db::model::build::dummy_drv_id;
+    use crate::db::model::drv::{insert_drv, Drv};
+
+    use super::*;
+
+    #[test]
+    fn combined_outcome() {
+        let success = Some(DrvBuildState::Completed(DrvBuildResult::Success));
+        let failure = Some(DrvBuildState::TransitiveFailure);
+        let interrupted = Some(DrvBuildState::Interrupted(
+            DrvBuildInterruptionKind::Timeout,
+        ));
+
+        assert_eq!(jobset_outcome(&[]), Some(BuildOutcome::Succeeded));
+        assert_eq!(jobset_outcome(&[success, None]), None);
+        assert_eq!(
+            jobset_outcome(&[success, Some(DrvBuildState::Building)]),
+            None
+        );
+        assert_eq!(
+            jobset_outcome(&[interrupted, failure, success]),
+            Some(BuildOutcome::Failed)
+        );
+        assert_eq!(
+            jobset_outcome(&[success, interrupted]),
+            Some(BuildOutcome::Interrupted)
+        );
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn unscheduled_drv(pool: SqlitePool) -> anyhow::Result<()> {
+        let log_dir = tempfile::tempdir()?;
+        let (context, _eval_receiver) = super::super::tests::context(pool.clone(), log_dir.path())?;
+        let (frames, _receiver) = mpsc::channel(1);
+        let responder = Responder {
+            id: Some(1),
+            frames,
+        };
+        let drv = dummy_drv_id();
+        let watch = async || {
+            let events = context.db_service.events().subscribe();
+            watch_drv(
+                drv.clone(),
+                false,
+                Duration::ZERO,
+                events,
+                &context,
+                &responder,
+            )
+            .await
+        };
+
+        assert!(watch()
+            .await
+            .is_err_and(|e| e.to_string().contains("is unknown to the server")));
+        insert_drv(&pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+        assert!(watch()
+            .await
+            .is_err_and(|e| e.to_string().contains("was never scheduled for building")));
+
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
+    fmt,
     net::{Ipv4Addr, SocketAddrV4},
     path::PathBuf,
 };
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
@@ -12,6 +13,9 @@ use figment::{
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
+use crate::client::Permission;
+use crate::nix::NixStore;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct ConfigCli {
@@ -23,6 +27,15 @@ struct ConfigCli {
     #[arg(short, long)]
     pub addr: Option<Ipv4Addr>,
 
+    /// Directory containing the compiled frontend, served at the root of the web service.
+    #[arg(long)]
+    pub bundle_path: Option<PathBuf>,
+
+    /// URL under which users reach the web service, used for links handed out to clients.
+    /// Defaults to the bound address.
+    #[arg(long)]
+    pub public_url: Option<String>,
+
     /// Socket for ekaci client. Defaults to $XDG_RUNTIME_DIR/ekaci.
     #[arg(short, long)]
     pub socket: Option<PathBuf>,
@@ -31,17 +44,47 @@ struct ConfigCli {
     #[arg(short, long)]
     pub db_path: Option<PathBuf>,
 
+    /// Directory for build logs. Defaults to $XDG_DATA_HOME/ekaci/logs
+    #[arg(long)]
+    pub log_dir: Option<PathBuf>,
+
+    /// Nix store URI used for evaluation, e.g. `local?root=/var/lib/ci-root`.
+    /// Defaults to the `store` setting of the local Nix configuration.
+    #[arg(long)]
+    pub store: Option<String>,
+
+    /// Nix store directory. Defaults to the store directory of the evaluating store.
+    #[arg(long)]
+    pub store_dir: Option<String>,
+
     /// Path for the configuration file. Can also be set using the $EKA_CI_CONFIG_FILE.
     /// If not provided a default path will be attempted, based on the XDG spec.
     #[arg(long)]
     pub config_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<ServerCommand>,
+}
+
+/// Maintenance tasks that run instead of the server.
+#[derive(Subcommand, Debug, Clone)]
+pub enum ServerCommand {
+    /// Check the database for inconsistencies and exit. Should only be run while no server is
+    /// using the database.
+    CheckDb {
+        /// Repair the inconsistencies found instead of only reporting them
+        #[arg(long)]
+        repair: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct ConfigFile {
     web: ConfigFileWeb,
     unix: ConfigFileUnix,
+    nix: ConfigFileNix,
     db_path: Option<PathBuf>,
+    log_dir: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -49,11 +92,28 @@ struct ConfigFileWeb {
     pub address: Option<Ipv4Addr>,
     pub port: Option<u16>,
     pub bundle_path: Option<PathBuf>,
+    pub public_url: Option<String>,
+    /// Only configurable through the file or environment, command lines are visible to every
+    /// user of the machine.
+    pub admin_token: Option<BearerToken>,
+    #[serde(default)]
+    pub client_tokens: Vec<ClientToken>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct ConfigFileUnix {
     pub socket_path: Option<PathBuf>,
+    /// Octal file mode of the socket, e.g. "0660"
+    pub mode: Option<String>,
+    /// Numeric id of the group owning the socket
+    pub group: Option<u32>,
+    pub access: Option<SocketAccess>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ConfigFileNix {
+    pub store: Option<String>,
+    pub store_dir: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -66,17 +126,91 @@ struct ConfigEnv {
 pub struct Config {
     pub web: ConfigWeb,
     pub unix: ConfigUnix,
+    pub store: NixStore,
     pub db_path: PathBuf,
+    pub log_dir: PathBuf,
+    pub command: Option<ServerCommand>,
 }
 
 #[derive(Debug)]
 pub struct ConfigWeb {
     pub address: SocketAddrV4,
+    pub bundle_path: Option<PathBuf>,
+    /// Base URL for links to the web service, without a trailing slash.
+    pub public_url: String,
+    /// Bearer token for the administrative API, which is disabled if unset.
+    pub admin_token: Option<BearerToken>,
+    /// Tokens of remote ekaci clients, which make socket requests through the web API.
+    pub client_tokens: Vec<ClientToken>,
+}
+
+/// Grants an ekaci client using the web API the same access a socket client could have.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientToken {
+    /// Who the token was handed out to, for logs and error messages
+    pub name: String,
+    pub token: BearerToken,
+    pub permission: Permission,
+}
+
+/// A secret that must not show up in logs.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BearerToken(String);
+
+impl BearerToken {
+    /// Compare in constant time, to not leak the token through response timings.
+    pub fn matches(&self, token: &str) -> bool {
+        self.0.len() == token.len()
+            && self
+                .0
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BearerToken(..)")
+    }
 }
 
 #[derive(Debug)]
 pub struct ConfigUnix {
     pub socket_path: PathBuf,
+    /// File mode of the socket, defaults to the umask of the server.
+    pub mode: Option<u32>,
+    /// Group owning the socket, defaults to the primary group of the server.
+    pub group: Option<u32>,
+    /// Without access rules, every process able to open the socket may read, only the user running
+    /// the server may also submit, restart, cancel and invalidate builds.
+    pub access: Option<SocketAccess>,
+}
+
+/// Which processes may use the socket and for what, by their peer credentials. Processes that
+/// are not listed are turned away, except for the user running the server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SocketAccess {
+    /// Information about the server, builds and their logs
+    #[serde(default)]
+    pub read: Peers,
+    /// Queueing builds and evaluations, in addition to reading
+    #[serde(default)]
+    pub submit: Peers,
+    /// Restarting, cancelling and invalidating builds, in addition to everything else
+    #[serde(default)]
+    pub admin: Peers,
+}
+
+/// Users and groups, by their numeric ids. Only the primary group of a process is checked.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Peers {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
 }
 
 impl Config {
@@ -98,25 +232,62 @@ impl Config {
             .extract::<ConfigFile>()
             .context("failed to parse config file")?;
 
+        let store_dir = args.store_dir.or(file.nix.store_dir);
+        let store = match args.store.or(file.nix.store) {
+            Some(uri) => NixStore::new(Some(uri), store_dir),
+            None => NixStore::detect(store_dir),
+        }
+        .context("failed to determine Nix store")?;
+
+        let address = SocketAddrV4::new(
+            args.addr
+                .or(file.web.address)
+                .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1)),
+            args.port.or(file.web.port).unwrap_or(3030),
+        );
+        let public_url = match args.public_url.or(file.web.public_url) {
+            Some(url) => url.trim_end_matches('/').to_owned(),
+            None => format!("http://{address}"),
+        };
+
         Ok(Config {
             web: ConfigWeb {
-                address: SocketAddrV4::new(
-                    args.addr
-                        .or(file.web.address)
-                        .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1)),
-                    args.port.or(file.web.port).unwrap_or(3030),
-                ),
+                address,
+                bundle_path: args.bundle_path.or(file.web.bundle_path),
+                public_url,
+                admin_token: file.web.admin_token,
+                client_tokens: file.web.client_tokens,
             },
             unix: ConfigUnix {
                 socket_path: match args.socket.or(file.unix.socket_path) {
                     Some(p) => p,
                     None => dirs.get_runtime_file("ekaci.socket")?,
                 },
+                mode: file
+                    .unix
+                    .mode
+                    .map(|mode| {
+                        u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                            .with_context(|| format!("invalid socket mode {mode:?}"))
+                    })
+                    .transpose()?,
+                group: file.unix.group,
+                access: file.unix.access.map(|mut access| {
+                    // The server must never lock out its own user
+                    access.admin.uids.push(rustix::process::getuid().as_raw());
+                    access
+                }),
             },
+            store,
             db_path: args
                 .db_path
                 .or(file.db_path)
                 .unwrap_or_else(|| dirs.get_data_file("sqlite.db")),
+            log_dir: args
+                .log_dir
+                .or(file.log_dir)
+                .unwrap_or_else(|| dirs.get_data_file("logs")),
+            command: args.command,
         })
     }
 }
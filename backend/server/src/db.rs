@@ -1,6 +1,13 @@
+pub mod admin;
+pub mod check;
+pub mod drvs;
+pub mod history;
 mod insert;
+pub mod jobset;
+mod lock;
 #[allow(dead_code, reason = "Only model definition for now, remove once used.")]
 pub mod model;
 mod service;
 
+pub use lock::DbLock;
 pub use service::DbService;
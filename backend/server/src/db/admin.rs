@@ -0,0 +1,421 @@
+//! Manual interventions in the build process, for when a builder misbehaves.
+//!
+//! All operations act on the latest build attempt of a derivation, the one with the highest
+//! `build_attempt` in [`DrvBuildMetadata`]. Restarting never modifies that attempt, its metadata
+//! is copied into a new attempt which starts out queued.
+//!
+//! [`DrvBuildMetadata`]: super::model::build::DrvBuildMetadata
+
+use std::num::NonZeroU32;
+
+use shared::build::{DrvBuildInterruptionKind, DrvBuildResult, DrvBuildState};
+use shared::store::DrvId;
+use sqlx::{SqliteConnection, SqlitePool};
+use thiserror::Error;
+
+use super::insert::{insert_drv_build_event, BuildEventError};
+use super::model::build::{DrvBuildEvent, DrvBuildId, StoredBuildEvent};
+
+/// Reasons for rejecting an administrative request.
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("{0} was never scheduled for building")]
+    NotScheduled(DrvId),
+    #[error("evaluation {0} does not exist")]
+    UnknownEvaluation(i64),
+    #[error("can not {action} {drv} while its latest build attempt is {state:?}")]
+    InvalidState {
+        drv: DrvId,
+        action: &'static str,
+        state: Option<DrvBuildState>,
+    },
+    #[error(transparent)]
+    Event(#[from] BuildEventError),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Start a new build attempt of a derivation whose latest attempt failed or was interrupted.
+pub async fn restart(pool: &SqlitePool, drv: &DrvId) -> Result<StoredBuildEvent, AdminError> {
+    let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+
+    let (_, state) = latest_attempt(&mut tx, drv).await?;
+    if !is_restartable(state) {
+        return Err(AdminError::InvalidState {
+            drv: drv.clone(),
+            action: "restart",
+            state,
+        });
+    }
+    let event = new_attempt(&mut tx, drv).await?;
+
+    tx.commit().await?;
+    Ok(event)
+}
+
+/// Interrupt the latest build attempt of a derivation, whether it is running or not.
+///
+/// Only the state is recorded, stopping a running build is up to the builder watching the
+/// build events.
+pub async fn cancel(pool: &SqlitePool, drv: &DrvId) -> Result<StoredBuildEvent, AdminError> {
+    let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+
+    let (build, state) = latest_attempt(&mut tx, drv).await?;
+    if !matches!(
+        state,
+        Some(
+            DrvBuildState::Queued
+                | DrvBuildState::Buildable
+                | DrvBuildState::Blocked
+                | DrvBuildState::Building
+        )
+    ) {
+        return Err(AdminError::InvalidState {
+            drv: drv.clone(),
+            action: "cancel",
+            state,
+        });
+    }
+    let event = insert_drv_build_event(
+        DrvBuildEvent::for_insert(
+            build,
+            DrvBuildState::Interrupted(DrvBuildInterruptionKind::Cancelled),
+        )
+        .0,
+        &mut tx,
+    )
+    .await?;
+
+    tx.commit().await?;
+    Ok(event)
+}
+
+/// Mark the result of the latest, completed build attempt as invalid and build the derivation
+/// again.
+pub async fn invalidate(
+    pool: &SqlitePool,
+    drv: &DrvId,
+    reason: &str,
+) -> Result<StoredBuildEvent, AdminError> {
+    let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+
+    let (build, state) = latest_attempt(&mut tx, drv).await?;
+    if !matches!(state, Some(DrvBuildState::Completed(_))) {
+        return Err(AdminError::InvalidState {
+            drv: drv.clone(),
+            action: "invalidate",
+            state,
+        });
+    }
+    sqlx::query(
+        r#"
+INSERT INTO DrvBuildInvalidation (derivation, build_attempt, reason)
+VALUES (?1, ?2, ?3)
+        "#,
+    )
+    .bind(&build.derivation)
+    .bind(build.build_attempt)
+    .bind(reason)
+    .execute(&mut *tx)
+    .await?;
+    let event = new_attempt(&mut tx, drv).await?;
+
+    tx.commit().await?;
+    Ok(event)
+}
+
+/// Restart every failed or interrupted build of an evaluation, including the dependencies of the
+/// derivations the evaluation exposed.
+pub async fn restart_failures(
+    pool: &SqlitePool,
+    evaluation: i64,
+) -> Result<Vec<StoredBuildEvent>, AdminError> {
+    let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM Evaluation WHERE id = ?1)")
+        .bind(evaluation)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !exists {
+        return Err(AdminError::UnknownEvaluation(evaluation));
+    }
+
+    let drvs: Vec<DrvId> = sqlx::query_scalar(
+        r#"
+WITH RECURSIVE Closure(derivation) AS (
+    SELECT derivation FROM JobsetDrv WHERE evaluation = ?1
+    UNION
+    SELECT r.reference FROM DrvRefs r JOIN Closure c ON r.referrer = c.derivation
+)
+SELECT derivation FROM Closure ORDER BY derivation
+        "#,
+    )
+    .bind(evaluation)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut events = Vec::new();
+    for drv in drvs {
+        let state = match latest_attempt(&mut tx, &drv).await {
+            Ok((_, state)) => state,
+            Err(AdminError::NotScheduled(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        if is_restartable(state) {
+            events.push(new_attempt(&mut tx, &drv).await?);
+        }
+    }
+
+    tx.commit().await?;
+    Ok(events)
+}
+
+fn is_restartable(state: Option<DrvBuildState>) -> bool {
+    matches!(
+        state,
+        Some(
+            DrvBuildState::Completed(DrvBuildResult::Failure)
+                | DrvBuildState::Interrupted(_)
+                | DrvBuildState::TransitiveFailure
+        )
+    )
+}
+
+/// The latest build attempt of `drv` and its current state.
+async fn latest_attempt(
+    conn: &mut SqliteConnection,
+    drv: &DrvId,
+) -> Result<(DrvBuildId, Option<DrvBuildState>), AdminError> {
+    let latest: Option<(NonZeroU32, Option<DrvBuildState>)> = sqlx::query_as(
+        r#"
+SELECT m.build_attempt, (
+    SELECT state FROM DrvBuildEvent e
+    WHERE e.derivation = m.derivation AND e.build_attempt = m.build_attempt
+    ORDER BY rowid DESC
+    LIMIT 1
+) FROM DrvBuildMetadata m
+WHERE m.derivation = ?1
+ORDER BY m.build_attempt DESC
+LIMIT 1
+        "#,
+    )
+    .bind(drv)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let (build_attempt, state) = latest.ok_or_else(|| AdminError::NotScheduled(drv.clone()))?;
+
+    Ok((
+        DrvBuildId {
+            derivation: drv.clone(),
+            build_attempt,
+        },
+        state,
+    ))
+}
+
+/// Queue a new build attempt, reusing the metadata of the latest attempt.
+async fn new_attempt(
+    conn: &mut SqliteConnection,
+    drv: &DrvId,
+) -> Result<StoredBuildEvent, AdminError> {
+    let build_attempt: NonZeroU32 = sqlx::query_scalar(
+        r#"
+INSERT INTO DrvBuildMetadata
+    (derivation, build_attempt, git_repo, git_commit, build_command)
+SELECT derivation, build_attempt + 1, git_repo, git_commit, build_command
+FROM DrvBuildMetadata
+WHERE derivation = ?1
+ORDER BY build_attempt DESC
+LIMIT 1
+RETURNING build_attempt
+        "#,
+    )
+    .bind(drv)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let build = DrvBuildId {
+        derivation: drv.clone(),
+        build_attempt,
+    };
+    let event = insert_drv_build_event(
+        DrvBuildEvent::for_insert(build, DrvBuildState::Queued).0,
+        conn,
+    )
+    .await?;
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::insert::new_drv_build_metadata;
+    use crate::db::jobset::{insert_jobset_drv, queue_evaluation};
+    use crate::db::model::build::{DrvBuildCommand, DrvBuildMetadata};
+    use crate::db::model::drv::{insert_drv, insert_drv_ref, Drv};
+    use crate::db::model::git::{GitCommit, GitRepo};
+
+    use super::*;
+
+    /// Schedule a build of `drv` and move it through `states`.
+    async fn build(pool: &SqlitePool, drv: &DrvId, states: &[DrvBuildState]) -> anyhow::Result<()> {
+        insert_drv(pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+        let metadata = new_drv_build_metadata(
+            DrvBuildMetadata::for_insert(
+                drv.clone(),
+                GitRepo(gix_url::parse(
+                    "https://github.com/ekala-project/eka-ci".into(),
+                )?),
+                GitCommit(gix_hash::ObjectId::from_hex(
+                    b"ad7fb3f7660de7435baf14af66edef106dcffff9",
+                )?),
+                DrvBuildCommand::dummy(),
+            ),
+            pool,
+        )
+        .await?;
+        for state in states {
+            let mut conn = pool.acquire().await?;
+            insert_drv_build_event(
+                DrvBuildEvent::for_insert(metadata.build.clone(), *state).0,
+                &mut conn,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn latest(
+        pool: &SqlitePool,
+        drv: &DrvId,
+    ) -> anyhow::Result<(u32, Option<DrvBuildState>)> {
+        let mut conn = pool.acquire().await?;
+        let (build, state) = latest_attempt(&mut conn, drv).await?;
+        Ok((build.build_attempt.get(), state))
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn restart_cancel_invalidate(pool: SqlitePool) -> anyhow::Result<()> {
+        use DrvBuildState::*;
+
+        let failed = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let succeeded = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.tar.gz.drv")?;
+        build(
+            &pool,
+            &failed,
+            &[
+                Queued,
+                Buildable,
+                Building,
+                Completed(DrvBuildResult::Failure),
+            ],
+        )
+        .await?;
+        build(
+            &pool,
+            &succeeded,
+            &[
+                Queued,
+                Buildable,
+                Building,
+                Completed(DrvBuildResult::Success),
+            ],
+        )
+        .await?;
+
+        assert!(matches!(
+            restart(&pool, &succeeded).await,
+            Err(AdminError::InvalidState { .. })
+        ));
+        let event = restart(&pool, &failed).await?;
+        assert_eq!(event.event.build.build_attempt.get(), 2);
+        assert_eq!(latest(&pool, &failed).await?, (2, Some(Queued)));
+
+        cancel(&pool, &failed).await?;
+        assert_eq!(
+            latest(&pool, &failed).await?,
+            (2, Some(Interrupted(DrvBuildInterruptionKind::Cancelled)))
+        );
+        assert!(matches!(
+            cancel(&pool, &failed).await,
+            Err(AdminError::InvalidState { .. })
+        ));
+
+        invalidate(&pool, &succeeded, "builder had a broken compiler").await?;
+        assert_eq!(latest(&pool, &succeeded).await?, (2, Some(Queued)));
+        let reason: String = sqlx::query_scalar(
+            "SELECT reason FROM DrvBuildInvalidation WHERE derivation = ?1 AND build_attempt = 1",
+        )
+        .bind(&succeeded)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(reason, "builder had a broken compiler");
+
+        let unknown = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        assert!(matches!(
+            restart(&pool, &unknown).await,
+            Err(AdminError::NotScheduled(_))
+        ));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn restart_jobset_failures(pool: SqlitePool) -> anyhow::Result<()> {
+        use DrvBuildState::*;
+
+        let hello = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let source = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.tar.gz.drv")?;
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        // the source failed to download, so hello could not be built
+        build(
+            &pool,
+            &source,
+            &[
+                Queued,
+                Buildable,
+                Building,
+                Completed(DrvBuildResult::Failure),
+            ],
+        )
+        .await?;
+        build(&pool, &hello, &[Queued, TransitiveFailure]).await?;
+        insert_drv_ref(&pool, &hello, &source).await?;
+        // failed as well, but is not part of the jobset
+        build(
+            &pool,
+            &llvm,
+            &[
+                Queued,
+                Buildable,
+                Building,
+                Completed(DrvBuildResult::Failure),
+            ],
+        )
+        .await?;
+        let mut evaluations = Vec::new();
+        for (pr, drv) in [(Some(1), &hello), (None, &llvm)] {
+            let evaluation = queue_evaluation(&pool, "ci.nix", pr).await?;
+            insert_jobset_drv(&pool, evaluation, drv).await?;
+            evaluations.push(evaluation);
+        }
+        // A later evaluation of the pull request no longer exposes hello
+        let rebased = queue_evaluation(&pool, "ci.nix", Some(1)).await?;
+        assert!(restart_failures(&pool, rebased).await?.is_empty());
+
+        assert!(matches!(
+            restart_failures(&pool, 42).await,
+            Err(AdminError::UnknownEvaluation(42))
+        ));
+        let events = restart_failures(&pool, evaluations[0]).await?;
+        let restarted: Vec<_> = events
+            .iter()
+            .map(|event| &event.event.build.derivation)
+            .collect();
+        assert_eq!(restarted, [&source, &hello]);
+        assert_eq!(latest(&pool, &llvm).await?.0, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,622 @@
+//! Consistency checks for the database.
+//!
+//! SQLite only enforces a small part of the invariants the server relies on. Bugs, manual edits
+//! and crashes can leave the database in a state the rest of the server does not expect, these
+//! checks find such drift and optionally repair it.
+//!
+//! The checks are meant to run while the server is stopped. In particular, every build that is
+//! still in [`DrvBuildState::Building`] is assumed to have lost its builder, and every queued or
+//! buildable build whose dependencies all finished is assumed to have lost its place in the
+//! scheduler.
+
+use std::fmt;
+use std::num::NonZeroU32;
+
+use shared::build::{DrvBuildInterruptionKind, DrvBuildResult};
+use shared::store::DrvId;
+use sqlx::SqlitePool;
+use tracing::info;
+
+use super::insert;
+use super::model::build::{DrvBuildEvent, DrvBuildId, DrvBuildState};
+
+/// An index the migrations are expected to have created.
+struct ExpectedIndex {
+    name: &'static str,
+    table: &'static str,
+    columns: &'static [&'static str],
+    unique: bool,
+}
+
+impl ExpectedIndex {
+    const fn new(
+        name: &'static str,
+        table: &'static str,
+        columns: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            table,
+            columns,
+            unique: false,
+        }
+    }
+
+    const fn unique(self) -> Self {
+        Self {
+            unique: true,
+            ..self
+        }
+    }
+}
+
+const EXPECTED_INDEXES: &[ExpectedIndex] = &[
+    ExpectedIndex::new(
+        "DrvBuildMetadataDerivation",
+        "DrvBuildMetadata",
+        &["derivation"],
+    ),
+    ExpectedIndex::new("DrvBuildEventDerivation", "DrvBuildEvent", &["derivation"]),
+    ExpectedIndex::new("DrvBuildEventState", "DrvBuildEvent", &["state"]),
+    ExpectedIndex::new("DrvReferrer", "DrvRefs", &["referrer"]),
+    ExpectedIndex::new("DrvReference", "DrvRefs", &["reference"]),
+    ExpectedIndex::new("DrvOutputPath", "DrvOutput", &["path"]),
+    ExpectedIndex::new("DrvAttrDerivation", "DrvAttr", &["derivation"]),
+    ExpectedIndex::new(
+        "JobsetDrvUnique",
+        "JobsetDrv",
+        &["evaluation", "derivation"],
+    )
+    .unique(),
+    ExpectedIndex::new("JobsetDrvJobset", "JobsetDrv", &["jobset", "pr"]),
+    ExpectedIndex::new("JobsetDrvDerivation", "JobsetDrv", &["derivation"]),
+    ExpectedIndex::new("EvaluationStarted", "Evaluation", &["started"]),
+    ExpectedIndex::new("EvaluationJobset", "Evaluation", &["jobset", "pr"]),
+];
+
+/// States a build attempt can never leave.
+const TERMINAL_STATES: [DrvBuildState; 3] = [
+    DrvBuildState::Completed(DrvBuildResult::Success),
+    DrvBuildState::Completed(DrvBuildResult::Failure),
+    DrvBuildState::TransitiveFailure,
+];
+
+/// A single violated invariant.
+#[derive(Clone, Debug)]
+pub enum Inconsistency {
+    /// A `DrvRefs` edge with at least one side missing from the `Drv` table.
+    DanglingDrvRef { referrer: DrvId, reference: DrvId },
+    /// Build events were recorded for a build attempt without `DrvBuildMetadata`.
+    EventWithoutMetadata(DrvBuildId),
+    /// A build event was recorded after the build attempt already reached a terminal state.
+    EventAfterTerminal { rowid: i64, build: DrvBuildId },
+    /// A build attempt is still marked as building, but no builder is working on it.
+    StuckBuild(DrvBuildId),
+    /// A queued or buildable build attempt, whose dependencies all finished, was never scheduled.
+    StalledBuild {
+        build: DrvBuildId,
+        state: DrvBuildState,
+        /// Whether one of the dependencies failed
+        dependency_failed: bool,
+    },
+    /// An index is missing or covers the wrong columns.
+    IndexDrift { name: &'static str },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DanglingDrvRef {
+                referrer,
+                reference,
+            } => write!(
+                f,
+                "DrvRefs edge {referrer} -> {reference} points at a missing Drv"
+            ),
+            Self::EventWithoutMetadata(build) => write!(
+                f,
+                "build events of {} attempt {} have no DrvBuildMetadata",
+                build.derivation, build.build_attempt
+            ),
+            Self::EventAfterTerminal { rowid, build } => write!(
+                f,
+                "build event {rowid} of {} attempt {} follows a terminal state",
+                build.derivation, build.build_attempt
+            ),
+            Self::StuckBuild(build) => write!(
+                f,
+                "{} attempt {} is building without a live builder",
+                build.derivation, build.build_attempt
+            ),
+            Self::StalledBuild { build, state, .. } => write!(
+                f,
+                "{} attempt {} is {state:?}, although all of its dependencies finished",
+                build.derivation, build.build_attempt
+            ),
+            Self::IndexDrift { name } => {
+                write!(f, "index {name} is missing or covers the wrong columns")
+            }
+        }
+    }
+}
+
+/// Run all checks and return every inconsistency found.
+pub async fn check(pool: &SqlitePool) -> anyhow::Result<Vec<Inconsistency>> {
+    let mut found = Vec::new();
+
+    let dangling: Vec<(DrvId, DrvId)> = sqlx::query_as(
+        r#"
+SELECT referrer, reference FROM DrvRefs
+WHERE referrer NOT IN (SELECT drv_path FROM Drv)
+    OR reference NOT IN (SELECT drv_path FROM Drv)
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    found.extend(
+        dangling
+            .into_iter()
+            .map(|(referrer, reference)| Inconsistency::DanglingDrvRef {
+                referrer,
+                reference,
+            }),
+    );
+
+    let without_metadata: Vec<DrvBuildId> = sqlx::query_as(
+        r#"
+SELECT DISTINCT e.derivation, e.build_attempt FROM DrvBuildEvent e
+LEFT JOIN DrvBuildMetadata m
+    ON m.derivation = e.derivation AND m.build_attempt = e.build_attempt
+WHERE m.derivation IS NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    found.extend(
+        without_metadata
+            .into_iter()
+            .map(Inconsistency::EventWithoutMetadata),
+    );
+
+    let after_terminal: Vec<(i64, DrvId, NonZeroU32)> = sqlx::query_as(
+        r#"
+SELECT e.rowid, e.derivation, e.build_attempt FROM DrvBuildEvent e
+WHERE e.rowid > (
+    SELECT MIN(t.rowid) FROM DrvBuildEvent t
+    WHERE t.derivation = e.derivation
+        AND t.build_attempt = e.build_attempt
+        AND t.state IN (?1, ?2, ?3)
+)
+        "#,
+    )
+    .bind(TERMINAL_STATES[0])
+    .bind(TERMINAL_STATES[1])
+    .bind(TERMINAL_STATES[2])
+    .fetch_all(pool)
+    .await?;
+    found.extend(
+        after_terminal
+            .into_iter()
+            .map(
+                |(rowid, derivation, build_attempt)| Inconsistency::EventAfterTerminal {
+                    rowid,
+                    build: DrvBuildId {
+                        derivation,
+                        build_attempt,
+                    },
+                },
+            ),
+    );
+
+    let stuck: Vec<DrvBuildId> = sqlx::query_as(
+        r#"
+SELECT e.derivation, e.build_attempt FROM DrvBuildEvent e
+WHERE e.state = ?1
+    AND e.rowid = (
+        SELECT MAX(l.rowid) FROM DrvBuildEvent l
+        WHERE l.derivation = e.derivation AND l.build_attempt = e.build_attempt
+    )
+    -- builds without metadata are reported as such
+    AND EXISTS (
+        SELECT 1 FROM DrvBuildMetadata m
+        WHERE m.derivation = e.derivation AND m.build_attempt = e.build_attempt
+    )
+    -- already reported as events after a terminal state
+    AND NOT EXISTS (
+        SELECT 1 FROM DrvBuildEvent t
+        WHERE t.derivation = e.derivation
+            AND t.build_attempt = e.build_attempt
+            AND t.state IN (?2, ?3, ?4)
+    )
+        "#,
+    )
+    .bind(DrvBuildState::Building)
+    .bind(TERMINAL_STATES[0])
+    .bind(TERMINAL_STATES[1])
+    .bind(TERMINAL_STATES[2])
+    .fetch_all(pool)
+    .await?;
+    found.extend(stuck.into_iter().map(Inconsistency::StuckBuild));
+
+    let stalled: Vec<(DrvId, NonZeroU32, DrvBuildState, bool)> = sqlx::query_as(
+        r#"
+WITH Latest AS (
+    SELECT e.derivation, e.build_attempt, e.state FROM DrvBuildEvent e
+    WHERE e.rowid = (
+        SELECT MAX(l.rowid) FROM DrvBuildEvent l
+        WHERE l.derivation = e.derivation AND l.build_attempt = e.build_attempt
+    )
+),
+-- a dependency is only as finished as its latest attempt
+LatestAttempt AS (
+    SELECT a.derivation, a.state FROM Latest a
+    WHERE a.build_attempt = (
+        SELECT MAX(b.build_attempt) FROM Latest b WHERE b.derivation = a.derivation
+    )
+)
+SELECT s.derivation, s.build_attempt, s.state, EXISTS (
+    SELECT 1 FROM DrvRefs r
+    JOIN LatestAttempt d ON d.derivation = r.reference
+    WHERE r.referrer = s.derivation AND d.state IN (?5, ?6)
+) FROM Latest s
+WHERE s.state IN (?1, ?2)
+    -- builds without metadata are reported as such
+    AND EXISTS (
+        SELECT 1 FROM DrvBuildMetadata m
+        WHERE m.derivation = s.derivation AND m.build_attempt = s.build_attempt
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM DrvRefs r
+        LEFT JOIN LatestAttempt d ON d.derivation = r.reference
+        WHERE r.referrer = s.derivation AND (d.state IS NULL OR d.state NOT IN (?4, ?5, ?6))
+    )
+    -- already reported as events after a terminal state
+    AND NOT EXISTS (
+        SELECT 1 FROM DrvBuildEvent t
+        WHERE t.derivation = s.derivation
+            AND t.build_attempt = s.build_attempt
+            AND t.state IN (?4, ?5, ?6)
+    )
+        "#,
+    )
+    .bind(DrvBuildState::Queued)
+    .bind(DrvBuildState::Buildable)
+    .bind(DrvBuildState::Building)
+    .bind(TERMINAL_STATES[0])
+    .bind(TERMINAL_STATES[1])
+    .bind(TERMINAL_STATES[2])
+    .fetch_all(pool)
+    .await?;
+    found.extend(stalled.into_iter().map(
+        |(derivation, build_attempt, state, dependency_failed)| Inconsistency::StalledBuild {
+            build: DrvBuildId {
+                derivation,
+                build_attempt,
+            },
+            state,
+            dependency_failed,
+        },
+    ));
+
+    for index in EXPECTED_INDEXES {
+        let actual: Option<(String, bool)> = sqlx::query_as(
+            r#"
+SELECT m.tbl_name, l."unique" FROM sqlite_master m
+JOIN pragma_index_list(m.tbl_name) l ON l.name = m.name
+WHERE m.type = 'index' AND m.name = ?1
+            "#,
+        )
+        .bind(index.name)
+        .fetch_optional(pool)
+        .await?;
+        let actual_columns: Vec<String> = sqlx::query_scalar(
+            r#"
+SELECT name FROM pragma_index_info(?1)
+ORDER BY seqno
+            "#,
+        )
+        .bind(index.name)
+        .fetch_all(pool)
+        .await?;
+
+        let matches = actual.is_some_and(|(table, unique)| {
+            table == index.table
+                && unique == index.unique
+                && actual_columns
+                    .iter()
+                    .map(String::as_str)
+                    .eq(index.columns.iter().copied())
+        });
+        if !matches {
+            found.push(Inconsistency::IndexDrift { name: index.name });
+        }
+    }
+
+    Ok(found)
+}
+
+/// Repair the given inconsistencies.
+///
+/// Repairs never invent data. Rows that violate an invariant are removed, builds without a live
+/// builder or scheduler are marked as interrupted and indexes are recreated.
+///
+/// The caller must hold the [`DbLock`](super::DbLock), as every running build is assumed to be
+/// orphaned.
+pub async fn repair(pool: &SqlitePool, inconsistencies: &[Inconsistency]) -> anyhow::Result<()> {
+    for inconsistency in inconsistencies {
+        info!("Repairing: {inconsistency}");
+
+        match inconsistency {
+            Inconsistency::DanglingDrvRef {
+                referrer,
+                reference,
+            } => {
+                sqlx::query("DELETE FROM DrvRefs WHERE referrer = ?1 AND reference = ?2")
+                    .bind(referrer)
+                    .bind(reference)
+                    .execute(pool)
+                    .await?;
+            }
+            Inconsistency::EventWithoutMetadata(build) => {
+                sqlx::query(
+                    "DELETE FROM DrvBuildEvent WHERE derivation = ?1 AND build_attempt = ?2",
+                )
+                .bind(&build.derivation)
+                .bind(build.build_attempt)
+                .execute(pool)
+                .await?;
+            }
+            Inconsistency::EventAfterTerminal { rowid, .. } => {
+                sqlx::query("DELETE FROM DrvBuildEvent WHERE rowid = ?1")
+                    .bind(rowid)
+                    .execute(pool)
+                    .await?;
+            }
+            Inconsistency::StuckBuild(build) => {
+                // This is exactly the situation `SchedulerDeath` describes
+                let event = DrvBuildEvent::for_insert(
+                    build.clone(),
+                    DrvBuildState::Interrupted(DrvBuildInterruptionKind::SchedulerDeath),
+                );
+                insert::new_drv_build_event(event, pool).await?;
+            }
+            Inconsistency::StalledBuild {
+                build,
+                state,
+                dependency_failed,
+            } => {
+                // Nothing would pick these builds up again, cancelled ones can at least be
+                // restarted. Queued builds behind a failure fail like the scheduler would have.
+                let state = match (state, dependency_failed) {
+                    (DrvBuildState::Queued, true) => DrvBuildState::TransitiveFailure,
+                    _ => DrvBuildState::Interrupted(DrvBuildInterruptionKind::Cancelled),
+                };
+                insert::new_drv_build_event(DrvBuildEvent::for_insert(build.clone(), state), pool)
+                    .await?;
+            }
+            Inconsistency::IndexDrift { name } => {
+                let index = EXPECTED_INDEXES
+                    .iter()
+                    .find(|index| index.name == *name)
+                    .expect("only expected indexes are reported");
+                let columns = index.columns.join(", ");
+                // Other connections must not see the index missing in between
+                let mut tx = pool.begin().await?;
+                // Names come from the constant above, so formatting them into SQL is safe
+                sqlx::query(&format!("DROP INDEX IF EXISTS {name}"))
+                    .execute(&mut *tx)
+                    .await?;
+                if index.unique {
+                    // Duplicates that slipped in without the index would fail to create it
+                    sqlx::query(&format!(
+                        "DELETE FROM {table} WHERE rowid NOT IN \
+                         (SELECT MIN(rowid) FROM {table} GROUP BY {columns})",
+                        table = index.table,
+                    ))
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                sqlx::query(&format!(
+                    "CREATE {}INDEX {name} ON {} ({columns})",
+                    if index.unique { "UNIQUE " } else { "" },
+                    index.table,
+                ))
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::model::build::{
+        dummy_drv_id, insert_raw_build_event, DrvBuildCommand, DrvBuildMetadata,
+    };
+    use crate::db::model::drv::{insert_drv, insert_drv_ref, Drv};
+    use crate::db::model::git::{GitCommit, GitRepo};
+
+    use super::*;
+
+    async fn insert_metadata(pool: &SqlitePool, drv: &DrvId) -> anyhow::Result<DrvBuildMetadata> {
+        insert::new_drv_build_metadata(
+            DrvBuildMetadata::for_insert(
+                drv.clone(),
+                GitRepo(gix_url::parse(
+                    "https://github.com/ekala-project/eka-ci".into(),
+                )?),
+                GitCommit(gix_hash::ObjectId::from_hex(
+                    b"ad7fb3f7660de7435baf14af66edef106dcffff9",
+                )?),
+                DrvBuildCommand::dummy(),
+            ),
+            pool,
+        )
+        .await
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn consistent_database(pool: SqlitePool) -> anyhow::Result<()> {
+        let metadata = insert_metadata(&pool, &dummy_drv_id()).await?;
+        for state in [
+            DrvBuildState::Queued,
+            DrvBuildState::Buildable,
+            DrvBuildState::Building,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+        ] {
+            insert::new_drv_build_event(
+                DrvBuildEvent::for_insert(metadata.build.clone(), state),
+                &pool,
+            )
+            .await?;
+        }
+
+        let found = check(&pool).await?;
+        assert!(found.is_empty(), "unexpected inconsistencies: {found:?}");
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn find_and_repair(pool: SqlitePool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO DrvRefs (referrer, reference) VALUES (?1, ?2)")
+            .bind(dummy_drv_id())
+            .bind(DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-source.drv")?)
+            .execute(&mut *conn)
+            .await?;
+        // same name and columns, but no longer unique
+        sqlx::raw_sql(
+            r#"
+DROP INDEX JobsetDrvUnique;
+CREATE INDEX JobsetDrvUnique ON JobsetDrv (evaluation, derivation);
+            "#,
+        )
+        .execute(&mut *conn)
+        .await?;
+        drop(conn);
+
+        // the first attempt is stuck building, the second one has no metadata and an event after
+        // completion
+        let stuck = insert_metadata(&pool, &dummy_drv_id()).await?.build;
+        insert_raw_build_event(
+            &pool,
+            &stuck.derivation,
+            stuck.build_attempt.get(),
+            DrvBuildState::Building,
+            None,
+        )
+        .await?;
+        for state in [
+            DrvBuildState::Completed(DrvBuildResult::Failure),
+            DrvBuildState::Building,
+        ] {
+            insert_raw_build_event(&pool, &dummy_drv_id(), 2, state, None).await?;
+        }
+
+        sqlx::query("DROP INDEX DrvReference")
+            .execute(&pool)
+            .await?;
+
+        let found = check(&pool).await?;
+        let count = |pred: fn(&Inconsistency) -> bool| found.iter().filter(|i| pred(i)).count();
+        assert_eq!(
+            count(|i| matches!(i, Inconsistency::DanglingDrvRef { .. })),
+            1
+        );
+        assert_eq!(
+            count(|i| matches!(i, Inconsistency::EventWithoutMetadata(_))),
+            1
+        );
+        assert_eq!(
+            count(|i| matches!(i, Inconsistency::EventAfterTerminal { .. })),
+            1
+        );
+        assert_eq!(count(|i| matches!(i, Inconsistency::StuckBuild(_))), 1);
+        assert_eq!(count(|i| matches!(i, Inconsistency::IndexDrift { .. })), 2);
+
+        repair(&pool, &found).await?;
+
+        let found = check(&pool).await?;
+        assert!(found.is_empty(), "unexpected inconsistencies: {found:?}");
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn stalled_builds(pool: SqlitePool) -> anyhow::Result<()> {
+        let hello = dummy_drv_id();
+        let source = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-source.drv")?;
+        let zlib = DrvId::new("1hmqa6nl0lbn2nd7m7hw84hj4x3bfhwk-zlib-1.3.1.drv")?;
+        let curl = DrvId::new("2rxgcyvwskqv4dwvvbx0kbn1hmlyrwfp-curl-8.12.1.drv")?;
+        for drv in [&hello, &source, &zlib, &curl] {
+            insert_drv(&pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+        }
+        insert_drv_ref(&pool, &hello, &source).await?;
+        insert_drv_ref(&pool, &curl, &zlib).await?;
+
+        let insert_states = |drv: DrvId, states: Vec<DrvBuildState>| {
+            let pool = pool.clone();
+            async move {
+                let build = insert_metadata(&pool, &drv).await?.build;
+                for state in states {
+                    insert::new_drv_build_event(
+                        DrvBuildEvent::for_insert(build.clone(), state),
+                        &pool,
+                    )
+                    .await?;
+                }
+                anyhow::Ok(build)
+            }
+        };
+        insert_states(
+            source,
+            vec![
+                DrvBuildState::Queued,
+                DrvBuildState::Buildable,
+                DrvBuildState::Building,
+                DrvBuildState::Completed(DrvBuildResult::Failure),
+            ],
+        )
+        .await?;
+        // its dependency failed, but it was never failed transitively
+        let hello = insert_states(hello, vec![DrvBuildState::Queued]).await?;
+        // ready to build, but never handed to a builder
+        let zlib =
+            insert_states(zlib, vec![DrvBuildState::Queued, DrvBuildState::Buildable]).await?;
+        // still waiting for zlib
+        insert_states(curl, vec![DrvBuildState::Queued]).await?;
+
+        let found = check(&pool).await?;
+        let mut stalled = found
+            .iter()
+            .filter_map(|i| match i {
+                Inconsistency::StalledBuild {
+                    build,
+                    dependency_failed,
+                    ..
+                } => Some((build.derivation.clone(), *dependency_failed)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        stalled.sort();
+        assert_eq!(found.len(), 2, "unexpected inconsistencies: {found:?}");
+        assert_eq!(
+            stalled,
+            [(zlib.derivation, false), (hello.derivation, true)]
+        );
+
+        repair(&pool, &found).await?;
+
+        let found = check(&pool).await?;
+        assert!(found.is_empty(), "unexpected inconsistencies: {found:?}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+//! Read-only views of derivations and their build state.
+//!
+//! The current state of a derivation is the state of its newest [`DrvBuildEvent`], regardless of
+//! the build attempt it belongs to. Derivations that were never scheduled have no state.
+//!
+//! [`DrvBuildEvent`]: super::model::build::DrvBuildEvent
+
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use shared::build::DrvBuildResult;
+use shared::store::DrvId;
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool};
+
+use super::model::build::{DrvBuildMetadata, DrvBuildState, StoredBuildEvent};
+use super::model::drv::Drv;
+
+/// Everything known about a single derivation.
+#[derive(Clone, Debug)]
+pub struct DrvDetails {
+    pub drv: DrvSummary,
+
+    /// All build attempts, oldest first.
+    pub attempts: Vec<DrvBuildAttempt>,
+
+    /// Derivations this derivation directly depends on.
+    pub dependencies: Vec<DrvSummary>,
+
+    /// Derivations directly depending on this derivation.
+    pub referrers: Vec<DrvSummary>,
+}
+
+/// A derivation together with its current build state.
+#[derive(Clone, Debug, FromRow)]
+pub struct DrvSummary {
+    #[sqlx(flatten)]
+    pub drv: Drv,
+
+    pub state: Option<DrvBuildState>,
+
+    /// When the current state was reached.
+    pub updated: Option<DateTime<Utc>>,
+}
+
+/// A single build attempt with the state it is currently in.
+#[derive(Clone, Debug)]
+pub struct DrvBuildAttempt {
+    pub metadata: DrvBuildMetadata,
+
+    /// `None` if no event was recorded for this attempt yet.
+    pub state: Option<DrvBuildState>,
+
+    /// Why a maintainer marked the result of this attempt as invalid.
+    pub invalidated: Option<String>,
+}
+
+/// A build attempt that is currently building.
+#[derive(Clone, Debug, FromRow)]
+pub struct RunningBuild {
+    pub derivation: DrvId,
+    pub build_attempt: u32,
+    /// `None` if the derivation was never evaluated by Eka CI.
+    pub system: Option<String>,
+    /// When the build started.
+    pub since: DateTime<Utc>,
+}
+
+/// A derivation whose own build failed or was interrupted, see [`root_failures`].
+#[derive(Clone, Debug)]
+pub struct RootFailure {
+    /// Derivations from the inspected one down to the failed one, both included.
+    pub chain: Vec<DrvId>,
+    pub state: DrvBuildState,
+}
+
+/// Restricts which derivations [`list_drvs`] returns. Unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct DrvFilter {
+    /// Only derivations currently in one of these states. An empty list disables the filter.
+    pub states: Vec<DrvBuildState>,
+    pub system: Option<String>,
+
+    /// Substring of the derivation name, the hash part of the path is not searched.
+    pub name: Option<String>,
+
+    /// Only derivations whose state changed in this time range (end exclusive).
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// One page of derivations, see [`list_drvs`].
+#[derive(Clone, Debug)]
+pub struct DrvPage {
+    pub drvs: Vec<DrvSummary>,
+
+    /// Pass this as `after` to fetch the next page. `None` on the last page.
+    pub next: Option<DrvId>,
+}
+
+const SUMMARY_SELECT: &str = r#"
+SELECT d.drv_path, d.system, e.state, e.timestamp AS updated FROM Drv d
+LEFT JOIN DrvBuildEvent e ON e.rowid = (
+    SELECT MAX(rowid) FROM DrvBuildEvent WHERE derivation = d.drv_path
+)
+"#;
+
+/// Look up a single derivation. Returns `None` if the derivation is unknown.
+pub async fn drv_details(pool: &SqlitePool, drv: &DrvId) -> anyhow::Result<Option<DrvDetails>> {
+    let summary: Option<DrvSummary> =
+        sqlx::query_as(&format!("{SUMMARY_SELECT} WHERE d.drv_path = ?1"))
+            .bind(drv)
+            .fetch_optional(pool)
+            .await?;
+    let Some(summary) = summary else {
+        return Ok(None);
+    };
+
+    let metadata: Vec<DrvBuildMetadata> = sqlx::query_as(
+        r#"
+SELECT derivation, build_attempt, git_repo, git_commit, build_command FROM DrvBuildMetadata
+WHERE derivation = ?1
+ORDER BY build_attempt
+        "#,
+    )
+    .bind(drv)
+    .fetch_all(pool)
+    .await?;
+    let states: HashMap<u32, DrvBuildState> = sqlx::query_as(
+        r#"
+SELECT e.build_attempt, e.state FROM DrvBuildEvent e
+WHERE e.derivation = ?1
+    AND e.rowid = (
+        SELECT MAX(l.rowid) FROM DrvBuildEvent l
+        WHERE l.derivation = e.derivation AND l.build_attempt = e.build_attempt
+    )
+        "#,
+    )
+    .bind(drv)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    let mut invalidations: HashMap<u32, String> = sqlx::query_as(
+        r#"
+SELECT build_attempt, reason FROM DrvBuildInvalidation
+WHERE derivation = ?1
+        "#,
+    )
+    .bind(drv)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+    let attempts = metadata
+        .into_iter()
+        .map(|metadata| {
+            let attempt = metadata.build.build_attempt.get();
+            DrvBuildAttempt {
+                state: states.get(&attempt).copied(),
+                invalidated: invalidations.remove(&attempt),
+                metadata,
+            }
+        })
+        .collect();
+
+    let dependencies = sqlx::query_as(&format!(
+        "{SUMMARY_SELECT} JOIN DrvRefs r ON r.reference = d.drv_path \
+         WHERE r.referrer = ?1 ORDER BY d.drv_path"
+    ))
+    .bind(drv)
+    .fetch_all(pool)
+    .await?;
+    let referrers = sqlx::query_as(&format!(
+        "{SUMMARY_SELECT} JOIN DrvRefs r ON r.referrer = d.drv_path \
+         WHERE r.reference = ?1 ORDER BY d.drv_path"
+    ))
+    .bind(drv)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(DrvDetails {
+        drv: summary,
+        attempts,
+        dependencies,
+        referrers,
+    }))
+}
+
+/// List derivations matching `filter`, ordered by their path.
+///
+/// Pagination uses the last derivation of the previous page as cursor (`after`), so pages stay
+/// consistent while new derivations are inserted.
+pub async fn list_drvs(
+    pool: &SqlitePool,
+    filter: &DrvFilter,
+    after: Option<&DrvId>,
+    limit: u32,
+) -> anyhow::Result<DrvPage> {
+    let mut query = QueryBuilder::<Sqlite>::new(SUMMARY_SELECT);
+    query.push(" WHERE 1");
+
+    if !filter.states.is_empty() {
+        query.push(" AND e.state IN (");
+        let mut states = query.separated(", ");
+        for state in &filter.states {
+            states.push_bind(*state);
+        }
+        query.push(")");
+    }
+    if let Some(system) = &filter.system {
+        query.push(" AND d.system = ").push_bind(system.clone());
+    }
+    if let Some(name) = &filter.name {
+        // Skip the hash part and the dash and drop the .drv suffix, SQLite strings are 1-indexed
+        query
+            .push(" AND instr(substr(d.drv_path, 34, length(d.drv_path) - 37), ")
+            .push_bind(name.clone())
+            .push(") > 0");
+    }
+    if let Some(since) = filter.since {
+        query
+            .push(" AND e.timestamp >= ")
+            .push_bind(since.timestamp());
+    }
+    if let Some(until) = filter.until {
+        query
+            .push(" AND e.timestamp < ")
+            .push_bind(until.timestamp());
+    }
+    if let Some(after) = after {
+        query.push(" AND d.drv_path > ").push_bind(after.clone());
+    }
+
+    // Fetch one more row than requested to know whether there is another page
+    query
+        .push(" ORDER BY d.drv_path LIMIT ")
+        .push_bind(i64::from(limit) + 1);
+
+    let mut drvs: Vec<DrvSummary> = query.build_query_as().fetch_all(pool).await?;
+    let next = if drvs.len() > limit as usize {
+        drvs.truncate(limit as usize);
+        drvs.last().map(|summary| summary.drv.drv_path.clone())
+    } else {
+        None
+    };
+
+    Ok(DrvPage { drvs, next })
+}
+
+/// Count derivations by their current state. Derivations that were never scheduled are not
+/// included.
+pub async fn count_by_state(pool: &SqlitePool) -> anyhow::Result<Vec<(DrvBuildState, i64)>> {
+    let counts = sqlx::query_as(
+        r#"
+SELECT state, COUNT(*) FROM DrvBuildEvent
+WHERE rowid IN (SELECT MAX(rowid) FROM DrvBuildEvent GROUP BY derivation)
+GROUP BY state
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(counts)
+}
+
+/// The newest build event of `drv`, which determines its current state.
+pub async fn latest_build_event(
+    pool: &SqlitePool,
+    drv: &DrvId,
+) -> anyhow::Result<Option<StoredBuildEvent>> {
+    let event = sqlx::query_as(
+        r#"
+SELECT rowid, derivation, build_attempt, state, timestamp FROM DrvBuildEvent
+WHERE derivation = ?1
+ORDER BY rowid DESC LIMIT 1
+        "#,
+    )
+    .bind(drv)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(event)
+}
+
+/// Find the dependencies whose builds caused `drv` to fail or to be blocked.
+///
+/// Starting at `drv`, dependencies in [`DrvBuildState::TransitiveFailure`] or
+/// [`DrvBuildState::Blocked`] are followed until a derivation which failed or was interrupted on
+/// its own is reached. Each of these is returned once, with the shortest chain leading to it. If
+/// `drv` itself failed, it is the only root failure.
+pub async fn root_failures(pool: &SqlitePool, drv: &DrvId) -> anyhow::Result<Vec<RootFailure>> {
+    let Some(state) = latest_build_event(pool, drv).await?.map(|e| e.event.state) else {
+        return Ok(Vec::new());
+    };
+    if is_root_failure(state) {
+        return Ok(vec![RootFailure {
+            chain: vec![drv.clone()],
+            state,
+        }]);
+    }
+
+    // Breadth first, so the first path reaching a derivation is the shortest one
+    let mut parents: HashMap<DrvId, Option<DrvId>> = HashMap::from([(drv.clone(), None)]);
+    let mut queue = VecDeque::from([drv.clone()]);
+    let mut roots = Vec::new();
+    while let Some(current) = queue.pop_front() {
+        let dependencies: Vec<DrvSummary> = sqlx::query_as(&format!(
+            "{SUMMARY_SELECT} JOIN DrvRefs r ON r.reference = d.drv_path \
+             WHERE r.referrer = ?1 ORDER BY d.drv_path"
+        ))
+        .bind(&current)
+        .fetch_all(pool)
+        .await?;
+
+        for dependency in dependencies {
+            let Some(state) = dependency.state else {
+                continue;
+            };
+            let path = dependency.drv.drv_path;
+            if parents.contains_key(&path) {
+                continue;
+            }
+            parents.insert(path.clone(), Some(current.clone()));
+
+            if is_root_failure(state) {
+                let mut chain = vec![path];
+                while let Some(Some(parent)) = chain.last().and_then(|last| parents.get(last)) {
+                    chain.push(parent.clone());
+                }
+                chain.reverse();
+                roots.push(RootFailure { chain, state });
+            } else if matches!(
+                state,
+                DrvBuildState::TransitiveFailure | DrvBuildState::Blocked
+            ) {
+                queue.push_back(path);
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+fn is_root_failure(state: DrvBuildState) -> bool {
+    matches!(
+        state,
+        DrvBuildState::Completed(DrvBuildResult::Failure) | DrvBuildState::Interrupted(_)
+    )
+}
+
+/// All derivations that are building right now, longest running first.
+pub async fn running_builds(pool: &SqlitePool) -> anyhow::Result<Vec<RunningBuild>> {
+    let builds = sqlx::query_as(
+        r#"
+SELECT e.derivation, e.build_attempt, d.system, e.timestamp AS since FROM DrvBuildEvent e
+LEFT JOIN Drv d ON d.drv_path = e.derivation
+WHERE e.rowid IN (SELECT MAX(rowid) FROM DrvBuildEvent GROUP BY derivation) AND e.state = ?1
+ORDER BY e.timestamp
+        "#,
+    )
+    .bind(DrvBuildState::Building)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(builds)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::build::DrvBuildResult;
+
+    use crate::db::insert::new_drv_build_metadata;
+    use crate::db::model::build::{insert_raw_build_event, DrvBuildCommand};
+    use crate::db::model::drv::{insert_drv, insert_drv_ref};
+    use crate::db::model::git::{GitCommit, GitRepo};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn details_and_listing(pool: SqlitePool) -> anyhow::Result<()> {
+        let hello = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let source = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.tar.gz.drv")?;
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        for drv in [&hello, &source] {
+            insert_drv(&pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+        }
+        insert_drv(&pool, &Drv::new(llvm.clone(), "aarch64-linux".to_owned())).await?;
+        insert_drv_ref(&pool, &hello, &source).await?;
+
+        insert_raw_build_event(&pool, &source, 1, DrvBuildState::Building, Some(100)).await?;
+        insert_raw_build_event(
+            &pool,
+            &source,
+            1,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(200),
+        )
+        .await?;
+        insert_raw_build_event(&pool, &hello, 1, DrvBuildState::Queued, Some(300)).await?;
+        for _ in 0..2 {
+            new_drv_build_metadata(
+                DrvBuildMetadata::for_insert(
+                    hello.clone(),
+                    GitRepo(gix_url::parse(
+                        "https://github.com/ekala-project/eka-ci".into(),
+                    )?),
+                    GitCommit(gix_hash::ObjectId::from_hex(
+                        b"ad7fb3f7660de7435baf14af66edef106dcffff9",
+                    )?),
+                    DrvBuildCommand::dummy(),
+                ),
+                &pool,
+            )
+            .await?;
+        }
+
+        let details = drv_details(&pool, &hello).await?.expect("drv should exist");
+        assert_eq!(details.drv.state, Some(DrvBuildState::Queued));
+        let states = details
+            .attempts
+            .iter()
+            .map(|attempt| (attempt.metadata.build.build_attempt.get(), attempt.state))
+            .collect::<Vec<_>>();
+        assert_eq!(states, [(1, Some(DrvBuildState::Queued)), (2, None)]);
+        assert_eq!(details.dependencies.len(), 1);
+        assert_eq!(details.dependencies[0].drv.drv_path, source);
+        assert_eq!(
+            details.dependencies[0].state,
+            Some(DrvBuildState::Completed(DrvBuildResult::Success))
+        );
+        assert!(details.referrers.is_empty());
+        assert!(drv_details(
+            &pool,
+            &DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-source.drv")?
+        )
+        .await?
+        .is_none());
+
+        // the name filter matches both hello derivations, but not the hash of llvm
+        let filter = DrvFilter {
+            name: Some("hello".to_owned()),
+            ..Default::default()
+        };
+        let first = list_drvs(&pool, &filter, None, 1).await?;
+        assert_eq!(first.drvs.len(), 1);
+        assert_eq!(first.drvs[0].drv.drv_path, source);
+        let second = list_drvs(&pool, &filter, first.next.as_ref(), 1).await?;
+        assert_eq!(second.drvs[0].drv.drv_path, hello);
+        assert!(second.next.is_none());
+
+        // the .drv suffix is not part of the name
+        let filter = DrvFilter {
+            name: Some("drv".to_owned()),
+            ..Default::default()
+        };
+        assert!(list_drvs(&pool, &filter, None, 10).await?.drvs.is_empty());
+
+        let filter = DrvFilter {
+            states: vec![DrvBuildState::Completed(DrvBuildResult::Success)],
+            since: DateTime::from_timestamp(150, 0),
+            ..Default::default()
+        };
+        let page = list_drvs(&pool, &filter, None, 10).await?;
+        assert_eq!(page.drvs.len(), 1);
+        assert_eq!(page.drvs[0].drv.drv_path, source);
+
+        let filter = DrvFilter {
+            system: Some("aarch64-linux".to_owned()),
+            ..Default::default()
+        };
+        let page = list_drvs(&pool, &filter, None, 10).await?;
+        assert_eq!(page.drvs.len(), 1);
+        assert_eq!(page.drvs[0].drv.drv_path, llvm);
+        assert_eq!(page.drvs[0].state, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn running(pool: SqlitePool) -> anyhow::Result<()> {
+        let hello = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        insert_drv(&pool, &Drv::new(hello.clone(), "x86_64-linux".to_owned())).await?;
+        insert_drv(&pool, &Drv::new(llvm.clone(), "aarch64-linux".to_owned())).await?;
+
+        insert_raw_build_event(&pool, &hello, 1, DrvBuildState::Building, Some(100)).await?;
+        insert_raw_build_event(&pool, &llvm, 1, DrvBuildState::Building, Some(200)).await?;
+        insert_raw_build_event(
+            &pool,
+            &hello,
+            1,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(300),
+        )
+        .await?;
+
+        let latest = latest_build_event(&pool, &hello)
+            .await?
+            .expect("hello has events");
+        assert_eq!(
+            latest.event.state,
+            DrvBuildState::Completed(DrvBuildResult::Success)
+        );
+
+        let running = running_builds(&pool).await?;
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].derivation, llvm);
+        assert_eq!(running[0].system.as_deref(), Some("aarch64-linux"));
+        assert_eq!(running[0].since.timestamp(), 200);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn failure_chains(pool: SqlitePool) -> anyhow::Result<()> {
+        use shared::build::DrvBuildInterruptionKind;
+
+        let drv = |name: &str| DrvId::new(format!("jd83l3jn2mkn530lgcg0y523jq5qji85-{name}.drv"));
+        let (app, lib, tool, zlib, fetch, ok) = (
+            drv("app")?,
+            drv("lib")?,
+            drv("tool")?,
+            drv("zlib")?,
+            drv("fetch")?,
+            drv("ok")?,
+        );
+        for drv in [&app, &lib, &tool, &zlib, &fetch, &ok] {
+            insert_drv(&pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+        }
+        for (referrer, reference) in [
+            (&app, &lib),
+            (&app, &tool),
+            (&app, &ok),
+            (&lib, &zlib),
+            (&lib, &tool),
+            (&tool, &fetch),
+        ] {
+            insert_drv_ref(&pool, referrer, reference).await?;
+        }
+
+        let interrupted = DrvBuildState::Interrupted(DrvBuildInterruptionKind::Timeout);
+        let failed = DrvBuildState::Completed(DrvBuildResult::Failure);
+        insert_raw_build_event(&pool, &app, 1, DrvBuildState::TransitiveFailure, Some(100)).await?;
+        insert_raw_build_event(&pool, &lib, 1, DrvBuildState::TransitiveFailure, Some(100)).await?;
+        insert_raw_build_event(&pool, &tool, 1, DrvBuildState::Blocked, Some(100)).await?;
+        insert_raw_build_event(&pool, &zlib, 1, failed, Some(100)).await?;
+        insert_raw_build_event(&pool, &fetch, 1, interrupted, Some(100)).await?;
+        insert_raw_build_event(
+            &pool,
+            &ok,
+            1,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(100),
+        )
+        .await?;
+
+        // tool is reachable through lib as well, but the direct edge is shorter
+        let roots = root_failures(&pool, &app).await?;
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].chain, [app.clone(), lib, zlib.clone()]);
+        assert_eq!(roots[0].state, failed);
+        assert_eq!(roots[1].chain, [app, tool, fetch]);
+        assert_eq!(roots[1].state, interrupted);
+
+        let roots = root_failures(&pool, &zlib).await?;
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].chain, [zlib]);
+        assert!(root_failures(&pool, &ok).await?.is_empty());
+
+        Ok(())
+    }
+}
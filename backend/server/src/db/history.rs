@@ -0,0 +1,358 @@
+//! Queries about how derivation builds progressed over time.
+//!
+//! All durations are derived from consecutive [`DrvBuildEvent`]s of the same build attempt,
+//! ordered by their ROWID. Because event timestamps only have second accuracy, so do the
+//! durations.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use shared::build::DrvBuildResult;
+use shared::store::DrvId;
+use sqlx::SqlitePool;
+
+use super::model::build::{DrvBuildEvent, DrvBuildId, DrvBuildState};
+
+/// Only build attempts that completed within this window are part of the statistics.
+const STATS_WINDOW: TimeDelta = TimeDelta::days(30);
+
+/// Statistics are based on at most this many of the most recent build attempts per name.
+const STATS_SAMPLES: u32 = 100;
+
+/// The full event history of a single build attempt.
+#[derive(Clone, Debug)]
+pub struct DrvBuildAttemptTimeline {
+    pub build_attempt: NonZeroU32,
+
+    /// All events of this attempt, in the order they happened.
+    pub events: Vec<DrvBuildEvent>,
+
+    pub durations: DrvBuildDurations,
+}
+
+/// Time a build attempt spent in each of the non-terminal states.
+///
+/// If the attempt is still in one of these states, the time up until now is included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrvBuildDurations {
+    pub queued: TimeDelta,
+    pub buildable: TimeDelta,
+    pub building: TimeDelta,
+}
+
+impl DrvBuildDurations {
+    /// Compute the durations from the ordered events of a single build attempt.
+    fn from_events(events: &[DrvBuildEvent], now: DateTime<Utc>) -> Self {
+        let mut durations = Self::default();
+
+        for (idx, event) in events.iter().enumerate() {
+            let end = events.get(idx + 1).map_or(now, |next| next.timestamp);
+            let Some(slot) = durations.slot(event.state) else {
+                continue;
+            };
+            *slot += end - event.timestamp;
+        }
+
+        durations
+    }
+
+    fn slot(&mut self, state: DrvBuildState) -> Option<&mut TimeDelta> {
+        match state {
+            DrvBuildState::Queued => Some(&mut self.queued),
+            DrvBuildState::Buildable => Some(&mut self.buildable),
+            DrvBuildState::Building => Some(&mut self.building),
+            _ => None,
+        }
+    }
+}
+
+/// Build time statistics for all derivations sharing the same name (e.g. `hello-2.12.1`).
+#[derive(Clone, Debug)]
+pub struct DrvBuildTimeStats {
+    pub name: String,
+
+    /// Number of completed build attempts the statistics are based on.
+    pub builds: usize,
+
+    pub build_p50: TimeDelta,
+    pub build_p95: TimeDelta,
+
+    /// Time spent in [`DrvBuildState::Queued`] and [`DrvBuildState::Buildable`]. A high value
+    /// compared to other derivations hints at a stall in scheduling.
+    pub wait_p50: TimeDelta,
+    pub wait_p95: TimeDelta,
+}
+
+/// Return the timeline of every build attempt of `drv`, oldest attempt first.
+///
+/// Returns `None` if the derivation is unknown.
+pub async fn build_timeline(
+    pool: &SqlitePool,
+    drv: &DrvId,
+) -> anyhow::Result<Option<Vec<DrvBuildAttemptTimeline>>> {
+    let events: Vec<DrvBuildEvent> = sqlx::query_as(
+        r#"
+SELECT derivation, build_attempt, state, timestamp FROM DrvBuildEvent
+WHERE derivation = ?1
+ORDER BY build_attempt, rowid
+        "#,
+    )
+    .bind(drv)
+    .fetch_all(pool)
+    .await?;
+    if events.is_empty() {
+        let known: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM Drv WHERE drv_path = ?1)")
+                .bind(drv)
+                .fetch_one(pool)
+                .await?;
+        if !known {
+            return Ok(None);
+        }
+    }
+
+    let now = Utc::now();
+    let timelines = events
+        .chunk_by(|a, b| a.build.build_attempt == b.build.build_attempt)
+        .map(|events| DrvBuildAttemptTimeline {
+            build_attempt: events[0].build.build_attempt,
+            events: events.to_vec(),
+            durations: DrvBuildDurations::from_events(events, now),
+        })
+        .collect();
+
+    Ok(Some(timelines))
+}
+
+/// Compute build time statistics per derivation name, slowest builds first.
+///
+/// Only build attempts that completed (successfully or not) are taken into account, attempts
+/// that are still running or were interrupted would skew the numbers. Of those, only the
+/// [`STATS_SAMPLES`] most recent attempts per name that completed within [`STATS_WINDOW`] are
+/// considered, the durations of each attempt are summed up by the database.
+pub async fn build_time_stats(pool: &SqlitePool) -> anyhow::Result<Vec<DrvBuildTimeStats>> {
+    // Names start after the hash and its dash, and end before the `.drv` suffix
+    let attempts: Vec<(String, i64, i64)> = sqlx::query_as(
+        r#"
+WITH Completed AS (
+    SELECT derivation, build_attempt, rowid AS finished FROM DrvBuildEvent
+    WHERE state IN (?1, ?2) AND timestamp >= ?3
+),
+Spans AS (
+    SELECT
+        derivation,
+        build_attempt,
+        finished,
+        state,
+        LEAD(timestamp) OVER (
+            PARTITION BY derivation, build_attempt ORDER BY DrvBuildEvent.rowid
+        ) - timestamp AS duration
+    FROM DrvBuildEvent JOIN Completed USING (derivation, build_attempt)
+),
+Attempts AS (
+    SELECT
+        substr(derivation, 34, length(derivation) - 37) AS name,
+        MAX(finished) AS finished,
+        SUM(iif(state = ?4, ifnull(duration, 0), 0)) AS building,
+        SUM(iif(state IN (?5, ?6), ifnull(duration, 0), 0)) AS waiting
+    FROM Spans
+    GROUP BY derivation, build_attempt
+),
+Ranked AS (
+    SELECT
+        name,
+        building,
+        waiting,
+        ROW_NUMBER() OVER (PARTITION BY name ORDER BY finished DESC) AS recency
+    FROM Attempts
+)
+SELECT name, building, waiting FROM Ranked
+WHERE recency <= ?7
+        "#,
+    )
+    .bind(DrvBuildState::Completed(DrvBuildResult::Success))
+    .bind(DrvBuildState::Completed(DrvBuildResult::Failure))
+    .bind((Utc::now() - STATS_WINDOW).timestamp())
+    .bind(DrvBuildState::Building)
+    .bind(DrvBuildState::Queued)
+    .bind(DrvBuildState::Buildable)
+    .bind(STATS_SAMPLES)
+    .fetch_all(pool)
+    .await?;
+
+    let mut samples: HashMap<String, (Vec<TimeDelta>, Vec<TimeDelta>)> = HashMap::new();
+    for (name, building, waiting) in attempts {
+        let (build, wait) = samples.entry(name).or_default();
+        build.push(TimeDelta::seconds(building));
+        wait.push(TimeDelta::seconds(waiting));
+    }
+
+    let mut stats: Vec<DrvBuildTimeStats> = samples
+        .into_iter()
+        .map(|(name, (mut build, mut wait))| {
+            build.sort();
+            wait.sort();
+            DrvBuildTimeStats {
+                name,
+                builds: build.len(),
+                build_p50: percentile(&build, 50),
+                build_p95: percentile(&build, 95),
+                wait_p50: percentile(&wait, 50),
+                wait_p95: percentile(&wait, 95),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.build_p95.cmp(&a.build_p95).then(a.name.cmp(&b.name)));
+
+    Ok(stats)
+}
+
+/// Time a build attempt spent building, together with the system of the derivation.
+///
+/// Returns `None` if the derivation is unknown or the attempt never started building.
+pub async fn build_time(
+    pool: &SqlitePool,
+    build: &DrvBuildId,
+) -> anyhow::Result<Option<(String, TimeDelta)>> {
+    let system: Option<String> = sqlx::query_scalar("SELECT system FROM Drv WHERE drv_path = ?1")
+        .bind(&build.derivation)
+        .fetch_optional(pool)
+        .await?;
+    let Some(system) = system else {
+        return Ok(None);
+    };
+
+    let events: Vec<DrvBuildEvent> = sqlx::query_as(
+        r#"
+SELECT derivation, build_attempt, state, timestamp FROM DrvBuildEvent
+WHERE derivation = ?1 AND build_attempt = ?2
+ORDER BY rowid
+        "#,
+    )
+    .bind(&build.derivation)
+    .bind(build.build_attempt)
+    .fetch_all(pool)
+    .await?;
+    if !events
+        .iter()
+        .any(|event| event.state == DrvBuildState::Building)
+    {
+        return Ok(None);
+    }
+
+    let durations = DrvBuildDurations::from_events(&events, Utc::now());
+    Ok(Some((system, durations.building)))
+}
+
+/// Nearest-rank percentile of already sorted, non-empty samples.
+fn percentile(sorted: &[TimeDelta], percent: usize) -> TimeDelta {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::build::{DrvBuildInterruptionKind, DrvBuildResult};
+
+    use crate::db::model::build::{dummy_drv_id, insert_raw_build_event};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn timeline_durations(pool: SqlitePool) -> anyhow::Result<()> {
+        let drv = dummy_drv_id();
+        // first attempt is interrupted, second one succeeds
+        insert_raw_build_event(&pool, &drv, 1, DrvBuildState::Queued, Some(1000)).await?;
+        insert_raw_build_event(&pool, &drv, 1, DrvBuildState::Buildable, Some(1010)).await?;
+        insert_raw_build_event(&pool, &drv, 1, DrvBuildState::Building, Some(1015)).await?;
+        insert_raw_build_event(
+            &pool,
+            &drv,
+            1,
+            DrvBuildState::Interrupted(DrvBuildInterruptionKind::OutOfMemory),
+            Some(1100),
+        )
+        .await?;
+        insert_raw_build_event(&pool, &drv, 2, DrvBuildState::Buildable, Some(2000)).await?;
+        insert_raw_build_event(&pool, &drv, 2, DrvBuildState::Building, Some(2000)).await?;
+        insert_raw_build_event(
+            &pool,
+            &drv,
+            2,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(2060),
+        )
+        .await?;
+
+        let timeline = build_timeline(&pool, &drv)
+            .await?
+            .expect("a drv with events is known");
+        let unknown = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.drv")?;
+        assert!(build_timeline(&pool, &unknown).await?.is_none());
+
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(timeline[0].build_attempt.get(), 1);
+        assert_eq!(timeline[0].events.len(), 4);
+        assert_eq!(
+            timeline[0].durations,
+            DrvBuildDurations {
+                queued: TimeDelta::seconds(10),
+                buildable: TimeDelta::seconds(5),
+                building: TimeDelta::seconds(85),
+            }
+        );
+        assert_eq!(timeline[1].durations.queued, TimeDelta::zero());
+        assert_eq!(timeline[1].durations.buildable, TimeDelta::zero());
+        assert_eq!(timeline[1].durations.building, TimeDelta::seconds(60));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn stats_per_name(pool: SqlitePool) -> anyhow::Result<()> {
+        // the same package name built from two different derivations
+        let first = dummy_drv_id();
+        let second = DrvId::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.drv")?;
+        let running = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+
+        let now = Utc::now().timestamp();
+        for (drv, build_time) in [(&first, 30), (&second, 90)] {
+            insert_raw_build_event(&pool, drv, 1, DrvBuildState::Buildable, Some(now - 100))
+                .await?;
+            insert_raw_build_event(&pool, drv, 1, DrvBuildState::Building, Some(now - 95)).await?;
+            insert_raw_build_event(
+                &pool,
+                drv,
+                1,
+                DrvBuildState::Completed(DrvBuildResult::Failure),
+                Some(now - 95 + build_time),
+            )
+            .await?;
+        }
+        insert_raw_build_event(&pool, &running, 1, DrvBuildState::Building, Some(now)).await?;
+        // attempts that completed long ago are not part of the statistics either
+        let old = now - STATS_WINDOW.num_seconds() - 1000;
+        insert_raw_build_event(&pool, &first, 2, DrvBuildState::Building, Some(old)).await?;
+        insert_raw_build_event(
+            &pool,
+            &first,
+            2,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(old + 500),
+        )
+        .await?;
+
+        let stats = build_time_stats(&pool).await?;
+
+        // running builds are not part of the statistics
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].name, "hello-2.12.1");
+        assert_eq!(stats[0].builds, 2);
+        assert_eq!(stats[0].build_p50, TimeDelta::seconds(30));
+        assert_eq!(stats[0].build_p95, TimeDelta::seconds(90));
+        assert_eq!(stats[0].wait_p50, TimeDelta::seconds(5));
+
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
-use sqlx::SqlitePool;
+use shared::build::DrvBuildState;
+use sqlx::{SqliteConnection, SqlitePool};
+use thiserror::Error;
 
 use super::model::{
-    build::{DrvBuildEvent, DrvBuildMetadata},
+    build::{DrvBuildEvent, DrvBuildId, DrvBuildMetadata, StoredBuildEvent},
     ForInsert,
 };
 
@@ -36,23 +38,94 @@ RETURNING derivation, build_attempt, git_repo, git_commit, build_command
     Ok(metadata)
 }
 
+/// Reasons for rejecting a new build event.
+#[derive(Error, Debug)]
+pub enum BuildEventError {
+    #[error("build attempt {} of {} has no metadata", .0.build_attempt, .0.derivation)]
+    UnknownBuild(DrvBuildId),
+    #[error(
+        "illegal state transition from {from:?} to {to:?} for build attempt {} of {}",
+        .build.build_attempt, .build.derivation
+    )]
+    IllegalTransition {
+        build: DrvBuildId,
+        from: Option<DrvBuildState>,
+        to: DrvBuildState,
+    },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Record a new state of a build attempt.
+///
+/// The event is only inserted if the build attempt exists and the transition from its current
+/// state is allowed by [`DrvBuildState::is_valid_transition`].
 pub async fn new_drv_build_event(
     event: ForInsert<DrvBuildEvent>,
     pool: &SqlitePool,
-) -> anyhow::Result<DrvBuildEvent> {
-    let event = event.0;
+) -> Result<StoredBuildEvent, BuildEventError> {
+    // Take the write lock right away, so that no other event for the same build attempt can be
+    // inserted between validating and inserting this one.
+    let mut tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+    let event = insert_drv_build_event(event.0, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(event)
+}
+
+/// [`new_drv_build_event`] on a connection, which must hold the write lock of the database.
+pub(super) async fn insert_drv_build_event(
+    event: DrvBuildEvent,
+    conn: &mut SqliteConnection,
+) -> Result<StoredBuildEvent, BuildEventError> {
+    let has_metadata: bool = sqlx::query_scalar(
+        r#"
+SELECT EXISTS (
+    SELECT 1 FROM DrvBuildMetadata
+    WHERE derivation = ?1 AND build_attempt = ?2
+)
+        "#,
+    )
+    .bind(&event.build.derivation)
+    .bind(event.build.build_attempt)
+    .fetch_one(&mut *conn)
+    .await?;
+    if !has_metadata {
+        return Err(BuildEventError::UnknownBuild(event.build));
+    }
+
+    let current: Option<DrvBuildState> = sqlx::query_scalar(
+        r#"
+SELECT state FROM DrvBuildEvent
+WHERE derivation = ?1 AND build_attempt = ?2
+ORDER BY rowid DESC
+LIMIT 1
+        "#,
+    )
+    .bind(&event.build.derivation)
+    .bind(event.build.build_attempt)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if !DrvBuildState::is_valid_transition(current, event.state) {
+        return Err(BuildEventError::IllegalTransition {
+            build: event.build,
+            from: current,
+            to: event.state,
+        });
+    }
+
     let event = sqlx::query_as(
         r#"
 INSERT INTO DrvBuildEvent
     (derivation, build_attempt, state)
 VALUES (?1, ?2, ?3)
-RETURNING derivation, build_attempt, state, timestamp
+RETURNING rowid, derivation, build_attempt, state, timestamp
         "#,
     )
     .bind(&event.build.derivation)
     .bind(event.build.build_attempt)
-    .bind(&event.state)
-    .fetch_one(pool)
+    .bind(event.state)
+    .fetch_one(&mut *conn)
     .await?;
 
     Ok(event)
@@ -62,8 +135,10 @@ RETURNING derivation, build_attempt, state, timestamp
 mod tests {
     use std::num::NonZeroU32;
 
+    use shared::build::{DrvBuildResult, DrvBuildState};
+
     use crate::db::model::{
-        build::{DrvBuildCommand, DrvBuildId, DrvBuildResult, DrvBuildState, DrvId},
+        build::{dummy_drv_id, DrvBuildCommand, DrvBuildId},
         git::{GitCommit, GitRepo},
     };
 
@@ -72,7 +147,7 @@ mod tests {
     #[sqlx::test(migrations = "./sql/migrations")]
     async fn insert_metadata_new_drv(pool: SqlitePool) -> anyhow::Result<()> {
         let metadata = DrvBuildMetadata::for_insert(
-            DrvId::dummy(),
+            dummy_drv_id(),
             GitRepo(gix_url::parse(
                 "https://github.com/ekala-project/eka-ci".into(),
             )?),
@@ -104,13 +179,13 @@ INSERT INTO DrvBuildMetadata (derivation, build_attempt, git_repo, git_commit, b
 VALUES (?, 1, 'https://github.com/ekala-project/corepkgs', '0000000000000000000000000000000000000000', ?)
             "#,
         )
-        .bind(DrvId::dummy())
+        .bind(dummy_drv_id())
         .bind(DrvBuildCommand::dummy())
         .execute(&pool)
         .await?;
 
         let metadata = DrvBuildMetadata::for_insert(
-            DrvId::dummy(),
+            dummy_drv_id(),
             GitRepo(gix_url::parse(
                 "https://github.com/ekala-project/corepkgs".into(),
             )?),
@@ -136,17 +211,25 @@ VALUES (?, 1, 'https://github.com/ekala-project/corepkgs', '00000000000000000000
         Ok(())
     }
 
+    fn dummy_metadata() -> anyhow::Result<ForInsert<DrvBuildMetadata>> {
+        Ok(DrvBuildMetadata::for_insert(
+            dummy_drv_id(),
+            GitRepo(gix_url::parse(
+                "https://github.com/ekala-project/eka-ci".into(),
+            )?),
+            GitCommit(gix_hash::ObjectId::from_hex(
+                b"ad7fb3f7660de7435baf14af66edef106dcffff9",
+            )?),
+            DrvBuildCommand::dummy(),
+        ))
+    }
+
     #[sqlx::test(migrations = "./sql/migrations")]
     async fn insert_event(pool: SqlitePool) -> anyhow::Result<()> {
-        let event = DrvBuildEvent::for_insert(
-            DrvBuildId {
-                derivation: DrvId::dummy(),
-                build_attempt: NonZeroU32::new(1).unwrap(),
-            },
-            DrvBuildState::Completed(DrvBuildResult::Success),
-        );
+        let metadata = new_drv_build_metadata(dummy_metadata()?, &pool).await?;
+        let event = DrvBuildEvent::for_insert(metadata.build, DrvBuildState::Queued);
 
-        let inserted = new_drv_build_event(event.clone(), &pool).await?;
+        let inserted = new_drv_build_event(event.clone(), &pool).await?.event;
 
         // some sanity checks that the correct event record is returned
         assert_eq!(&inserted.build.derivation, &event.0.build.derivation);
@@ -159,4 +242,50 @@ VALUES (?, 1, 'https://github.com/ekala-project/corepkgs', '00000000000000000000
 
         Ok(())
     }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn insert_event_unknown_build(pool: SqlitePool) -> anyhow::Result<()> {
+        let event = DrvBuildEvent::for_insert(
+            DrvBuildId {
+                derivation: dummy_drv_id(),
+                build_attempt: NonZeroU32::new(1).unwrap(),
+            },
+            DrvBuildState::Queued,
+        );
+
+        let result = new_drv_build_event(event, &pool).await;
+
+        assert!(matches!(result, Err(BuildEventError::UnknownBuild(_))));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn insert_event_illegal_transition(pool: SqlitePool) -> anyhow::Result<()> {
+        let metadata = new_drv_build_metadata(dummy_metadata()?, &pool).await?;
+        for state in [
+            DrvBuildState::Queued,
+            DrvBuildState::Buildable,
+            DrvBuildState::Building,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+        ] {
+            let event = DrvBuildEvent::for_insert(metadata.build.clone(), state);
+            new_drv_build_event(event, &pool).await?;
+        }
+
+        // a completed build can not be restarted
+        let event = DrvBuildEvent::for_insert(metadata.build.clone(), DrvBuildState::Building);
+        let result = new_drv_build_event(event, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(BuildEventError::IllegalTransition {
+                from: Some(DrvBuildState::Completed(DrvBuildResult::Success)),
+                to: DrvBuildState::Building,
+                ..
+            })
+        ));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,358 @@
+//! Evaluations of jobsets, associations between jobsets and derivations, and queries about the
+//! build events relevant to them.
+
+use chrono::{DateTime, Utc};
+use shared::build::DrvBuildState;
+use shared::store::DrvId;
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool};
+
+use super::model::build::StoredBuildEvent;
+use crate::events::BuildEvent;
+
+/// Restricts which build events are returned. Unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct BuildEventFilter {
+    pub drv: Option<DrvId>,
+    /// Only derivations exposed by an evaluation of this jobset.
+    pub jobset: Option<String>,
+    /// Only derivations exposed by an evaluation for this pull request.
+    pub pr: Option<u64>,
+}
+
+impl BuildEventFilter {
+    /// Whether a published event matches the filter, like [`build_events_after`] would have
+    /// decided.
+    pub fn matches(&self, event: &BuildEvent) -> bool {
+        self.drv.as_ref().is_none_or(|drv| *drv == event.drv)
+            && (self.jobset.is_none() && self.pr.is_none()
+                || event.jobsets.iter().any(|(jobset, pr)| {
+                    self.jobset
+                        .as_ref()
+                        .is_none_or(|expected| expected == jobset)
+                        && self.pr.is_none_or(|expected| *pr == Some(expected))
+                }))
+    }
+
+    /// Append the filter as conditions on the `derivation` column to `query`.
+    fn push_conditions(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        if let Some(drv) = &self.drv {
+            query.push(" AND derivation = ").push_bind(drv.clone());
+        }
+        if self.jobset.is_some() || self.pr.is_some() {
+            query.push(" AND derivation IN (SELECT derivation FROM JobsetDrv WHERE 1");
+            if let Some(jobset) = &self.jobset {
+                query.push(" AND jobset = ").push_bind(jobset.clone());
+            }
+            if let Some(pr) = self.pr {
+                query.push(" AND pr = ").push_bind(pr as i64);
+            }
+            query.push(")");
+        }
+    }
+}
+
+/// Remember that `evaluation` exposed `drv`.
+pub async fn insert_jobset_drv(
+    pool: &SqlitePool,
+    evaluation: i64,
+    drv: &DrvId,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+INSERT OR IGNORE INTO JobsetDrv
+    (jobset, pr, evaluation, derivation)
+SELECT jobset, pr, id, ?2 FROM Evaluation WHERE id = ?1
+    "#,
+    )
+    .bind(evaluation)
+    .bind(drv)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// An evaluation of a jobset, from the moment it was queued.
+#[derive(Clone, Debug, FromRow)]
+pub struct Evaluation {
+    pub id: i64,
+    pub jobset: String,
+    pub pr: Option<i64>,
+    pub queued: DateTime<Utc>,
+    /// `None` while the evaluation waits for the evaluator.
+    pub started: Option<DateTime<Utc>>,
+    /// `None` until the evaluation finished.
+    pub finished: Option<DateTime<Utc>>,
+    /// Number of derivations found, if the evaluation succeeded.
+    pub drvs: Option<i64>,
+    /// Why the evaluation failed, if it did.
+    pub error: Option<String>,
+}
+
+const EVALUATION_SELECT: &str = r#"
+SELECT id, jobset, pr, queued, started, finished, drvs, error FROM Evaluation
+"#;
+
+/// Record that an evaluation of `jobset` was queued, returning its id.
+pub async fn queue_evaluation(
+    pool: &SqlitePool,
+    jobset: &str,
+    pr: Option<u64>,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query_scalar("INSERT INTO Evaluation (jobset, pr) VALUES (?1, ?2) RETURNING id")
+        .bind(jobset)
+        .bind(pr.map(|pr| pr as i64))
+        .fetch_one(pool)
+        .await?;
+
+    Ok(id)
+}
+
+/// Record that the evaluator started working on an evaluation.
+pub async fn start_evaluation(pool: &SqlitePool, id: i64) -> anyhow::Result<()> {
+    sqlx::query("UPDATE Evaluation SET started = unixepoch() WHERE id = ?1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Record the outcome of an evaluation: the number of derivations found, or why it failed.
+pub async fn finish_evaluation(
+    pool: &SqlitePool,
+    id: i64,
+    result: Result<usize, &str>,
+) -> anyhow::Result<()> {
+    let (drvs, error) = match result {
+        Ok(drvs) => (Some(drvs as i64), None),
+        Err(error) => (None, Some(error)),
+    };
+    sqlx::query(
+        r#"
+UPDATE Evaluation SET finished = unixepoch(), drvs = ?2, error = ?3
+WHERE id = ?1
+    "#,
+    )
+    .bind(id)
+    .bind(drvs)
+    .bind(error)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Mark evaluations that never finished as failed. Neither the evaluation queue nor running
+/// evaluations survive a restart, so this is called before the server starts accepting jobs.
+pub async fn abandon_evaluations(pool: &SqlitePool) -> anyhow::Result<u64> {
+    let result = sqlx::query(
+        r#"
+UPDATE Evaluation
+SET finished = unixepoch(), error = 'the server stopped before the evaluation finished'
+WHERE finished IS NULL
+    "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Look up a single evaluation.
+pub async fn evaluation(pool: &SqlitePool, id: i64) -> anyhow::Result<Option<Evaluation>> {
+    let evaluation = sqlx::query_as(&format!("{EVALUATION_SELECT} WHERE id = ?1"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(evaluation)
+}
+
+/// The `limit` most recently queued evaluations, newest first.
+pub async fn recent_evaluations(pool: &SqlitePool, limit: u32) -> anyhow::Result<Vec<Evaluation>> {
+    let evaluations = sqlx::query_as(&format!("{EVALUATION_SELECT} ORDER BY id DESC LIMIT ?1"))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(evaluations)
+}
+
+/// The most recently queued evaluation of `jobset` for `pr`.
+pub async fn latest_evaluation(
+    pool: &SqlitePool,
+    jobset: &str,
+    pr: Option<u64>,
+) -> anyhow::Result<Option<Evaluation>> {
+    let evaluation = sqlx::query_as(&format!(
+        "{EVALUATION_SELECT} WHERE jobset = ?1 AND pr IS ?2 ORDER BY id DESC LIMIT 1"
+    ))
+    .bind(jobset)
+    .bind(pr.map(|pr| pr as i64))
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(evaluation)
+}
+
+/// Current states of the derivations `evaluation` exposed, `None` for derivations that were never
+/// scheduled.
+pub async fn evaluation_states(
+    pool: &SqlitePool,
+    evaluation: i64,
+) -> anyhow::Result<Vec<Option<DrvBuildState>>> {
+    let states = sqlx::query_scalar(
+        r#"
+SELECT e.state FROM JobsetDrv j
+LEFT JOIN DrvBuildEvent e ON e.rowid = (
+    SELECT MAX(rowid) FROM DrvBuildEvent WHERE derivation = j.derivation
+)
+WHERE j.evaluation = ?1
+    "#,
+    )
+    .bind(evaluation)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(states)
+}
+
+/// Build events matching `filter` with a ROWID greater than `after`, oldest first.
+pub async fn build_events_after(
+    pool: &SqlitePool,
+    filter: &BuildEventFilter,
+    after: i64,
+    limit: u32,
+) -> anyhow::Result<Vec<StoredBuildEvent>> {
+    let mut query = QueryBuilder::new(
+        "SELECT rowid, derivation, build_attempt, state, timestamp FROM DrvBuildEvent WHERE rowid > ",
+    );
+    query.push_bind(after);
+    filter.push_conditions(&mut query);
+    query.push(" ORDER BY rowid LIMIT ").push_bind(limit);
+
+    Ok(query.build_query_as().fetch_all(pool).await?)
+}
+
+/// The jobsets and pull requests evaluations exposed `drv` for.
+pub async fn drv_jobsets(
+    pool: &SqlitePool,
+    drv: &DrvId,
+) -> anyhow::Result<Vec<(String, Option<u64>)>> {
+    let jobsets: Vec<(String, Option<i64>)> =
+        sqlx::query_as("SELECT DISTINCT jobset, pr FROM JobsetDrv WHERE derivation = ?1")
+            .bind(drv)
+            .fetch_all(pool)
+            .await?;
+
+    Ok(jobsets
+        .into_iter()
+        .map(|(jobset, pr)| (jobset, pr.map(|pr| pr as u64)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::model::build::insert_raw_build_event;
+    use crate::db::model::drv::{insert_drv, Drv};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn filter_by_jobset(pool: SqlitePool) -> anyhow::Result<()> {
+        let hello = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        for drv in [&hello, &llvm] {
+            insert_drv(&pool, &Drv::new(drv.clone(), "x86_64-linux".to_owned())).await?;
+            insert_raw_build_event(&pool, drv, 1, DrvBuildState::Queued, None).await?;
+        }
+        let release = queue_evaluation(&pool, "release.nix", None).await?;
+        let pr_42 = queue_evaluation(&pool, "release.nix", Some(42)).await?;
+        insert_jobset_drv(&pool, release, &hello).await?;
+        insert_jobset_drv(&pool, release, &hello).await?;
+        insert_jobset_drv(&pool, pr_42, &llvm).await?;
+
+        let all = build_events_after(&pool, &BuildEventFilter::default(), 0, 10).await?;
+        assert_eq!(all.len(), 2);
+        let resumed =
+            build_events_after(&pool, &BuildEventFilter::default(), all[0].rowid, 10).await?;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].event.build.derivation, llvm);
+
+        let pr = BuildEventFilter {
+            jobset: Some("release.nix".to_owned()),
+            pr: Some(42),
+            ..Default::default()
+        };
+        let events = build_events_after(&pool, &pr, 0, 10).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.build.derivation, llvm);
+        // published events are matched the same way
+        let published = async |drv: &DrvId| {
+            let mut event =
+                BuildEvent::from(build_events_after(&pool, &pr, 0, 10).await?.remove(0));
+            event.drv = drv.clone();
+            event.jobsets = drv_jobsets(&pool, drv).await?.into();
+            anyhow::Ok(event)
+        };
+        assert!(pr.matches(&published(&llvm).await?));
+        assert!(!pr.matches(&published(&hello).await?));
+
+        assert_eq!(
+            evaluation_states(&pool, pr_42).await?,
+            [Some(DrvBuildState::Queued)]
+        );
+        // a later evaluation of the pull request only sees what it exposed itself
+        let rebased = queue_evaluation(&pool, "release.nix", Some(42)).await?;
+        assert!(evaluation_states(&pool, rebased).await?.is_empty());
+        insert_jobset_drv(&pool, rebased, &hello).await?;
+        insert_jobset_drv(&pool, rebased, &llvm).await?;
+        assert_eq!(evaluation_states(&pool, rebased).await?.len(), 2);
+        assert_eq!(evaluation_states(&pool, pr_42).await?.len(), 1);
+
+        let unique: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM JobsetDrv")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(unique, 4);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn evaluation_outcomes(pool: SqlitePool) -> anyhow::Result<()> {
+        let succeeded = queue_evaluation(&pool, "release.nix", None).await?;
+        start_evaluation(&pool, succeeded).await?;
+        finish_evaluation(&pool, succeeded, Ok(3)).await?;
+        let failed = queue_evaluation(&pool, "release.nix", Some(42)).await?;
+        start_evaluation(&pool, failed).await?;
+        finish_evaluation(&pool, failed, Err("syntax error")).await?;
+        let running = queue_evaluation(&pool, "release.nix", None).await?;
+        start_evaluation(&pool, running).await?;
+        let queued = queue_evaluation(&pool, "release.nix", None).await?;
+
+        let evaluations = recent_evaluations(&pool, 10).await?;
+        assert_eq!(
+            evaluations.iter().map(|e| e.id).collect::<Vec<_>>(),
+            [queued, running, failed, succeeded]
+        );
+        assert_eq!(evaluations[3].drvs, Some(3));
+        assert_eq!(evaluations[2].pr, Some(42));
+        assert_eq!(evaluations[2].error.as_deref(), Some("syntax error"));
+        assert!(evaluations[1].started.is_some());
+        assert!(evaluations[1].finished.is_none());
+        assert!(evaluations[0].started.is_none());
+
+        let latest = latest_evaluation(&pool, "release.nix", Some(42)).await?;
+        assert_eq!(latest.map(|e| e.id), Some(failed));
+
+        assert_eq!(abandon_evaluations(&pool).await?, 2);
+        let running = evaluation(&pool, running)
+            .await?
+            .expect("evaluation exists");
+        assert!(running.finished.is_some());
+        assert!(running.error.is_some());
+
+        Ok(())
+    }
+}
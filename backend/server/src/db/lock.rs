@@ -0,0 +1,64 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use rustix::fs::{flock, FlockOperation};
+use rustix::io::Errno;
+
+/// Exclusive advisory lock on a database, held by the server for as long as it runs.
+///
+/// Anything that assumes there is no live builder, like repairing the database, must hold this
+/// lock as well. The lock lives on a file next to the database and is released when dropped.
+#[derive(Debug)]
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Take the lock for the database at `location`, failing if another process holds it.
+    pub fn acquire(location: &Path) -> anyhow::Result<Self> {
+        let path = lock_path(location);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open database lock {}", path.display()))?;
+
+        match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(Errno::WOULDBLOCK) => anyhow::bail!(
+                "database {} is in use by another process (is a server running?)",
+                location.display()
+            ),
+            Err(e) => {
+                Err(e).with_context(|| format!("failed to lock database lock {}", path.display()))
+            }
+        }
+    }
+}
+
+fn lock_path(location: &Path) -> PathBuf {
+    let mut path = location.as_os_str().to_owned();
+    path.push(".lock");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = dir.path().join("sqlite.db");
+
+        let lock = DbLock::acquire(&db)?;
+        assert!(DbLock::acquire(&db).is_err());
+
+        drop(lock);
+        DbLock::acquire(&db)?;
+
+        Ok(())
+    }
+}
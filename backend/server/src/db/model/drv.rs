@@ -1,50 +1,66 @@
+use shared::store::{DrvId, StorePath};
 use sqlx::{FromRow, Pool, Sqlite};
 use std::collections::HashMap;
 use std::fmt;
 use tracing::debug;
 
+use crate::nix::NixStore;
+
 #[derive(Clone, FromRow)]
 pub struct Drv {
     /// Derivation store path
-    pub drv_path: String,
+    pub drv_path: DrvId,
 
     /// to reattempt the build (depending on the interruption kind).
     pub system: String,
 }
 
 impl Drv {
-    pub fn new(drv_path: String, system: String) -> Self {
-        Drv {
-            drv_path: strip_store_prefix(drv_path),
-            system,
-        }
+    pub fn new(drv_path: DrvId, system: String) -> Self {
+        Drv { drv_path, system }
     }
 
-    pub fn full_drv_path(&self) -> String {
-        format!("/nix/store/{}", &self.drv_path)
+    /// The derivation path inside of the store which evaluated the derivation.
+    pub fn full_drv_path(&self, store: &NixStore) -> String {
+        store.drv_path(&self.drv_path)
     }
 }
 
+/// A realized output of a derivation.
+#[derive(Clone, Debug, FromRow)]
+pub struct DrvOutput {
+    /// Derivation producing the output
+    pub derivation: DrvId,
+
+    /// Output name, e.g. "out" or "dev"
+    pub name: String,
+
+    /// Output store path
+    pub path: StorePath,
+}
+
+/// The answer to "where does this store path come from?"
+#[derive(Clone, Debug)]
+pub struct DrvOutputOrigin {
+    pub output: DrvOutput,
+
+    /// All attribute paths an evaluation exposed the derivation under. Empty if the derivation
+    /// was only discovered as a dependency of another derivation.
+    pub attrs: Vec<String>,
+}
+
 impl fmt::Debug for Drv {
     // Allow for debug output to resemble expected output
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "{{ drv_path:{}, system:{} }}",
-            self.full_drv_path(),
-            &self.system
+            &self.drv_path, &self.system
         )
     }
 }
 
-pub fn strip_store_prefix(drv_path: String) -> String {
-    drv_path
-        .strip_prefix("/nix/store/")
-        .unwrap_or(&drv_path)
-        .to_string()
-}
-
-pub async fn has_drv(pool: &Pool<Sqlite>, drv_path: &str) -> anyhow::Result<bool> {
+pub async fn has_drv(pool: &Pool<Sqlite>, drv_path: &DrvId) -> anyhow::Result<bool> {
     let result = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM Drv WHERE drv_path = $1)")
         .bind(drv_path)
         .fetch_one(pool)
@@ -57,27 +73,21 @@ pub async fn has_drv(pool: &Pool<Sqlite>, drv_path: &str) -> anyhow::Result<bool
 /// references may or may not already exist
 pub async fn insert_drv_graph(
     pool: &Pool<Sqlite>,
-    drv_graph: HashMap<String, Vec<String>>,
+    drv_graph: HashMap<DrvId, Vec<DrvId>>,
 ) -> anyhow::Result<()> {
-    let mut reference_map: HashMap<String, Vec<String>> = HashMap::new();
     // We must first traverse the keys, add them all, then we can create
     // the reference relationships
-    for (drv_path, references) in &drv_graph {
+    for drv_path in drv_graph.keys() {
         debug!("Inserting {:?} into Drv", &drv_path);
         // TODO: have system be captured before this function
-        let drv = Drv::new(drv_path.to_string(), "x86_64-linux".to_string());
+        let drv = Drv::new(drv_path.clone(), "x86_64-linux".to_string());
         insert_drv(pool, &drv).await?;
-        reference_map.insert(drv.drv_path, references.clone());
     }
 
-    for (drv_path, references) in reference_map {
+    for (drv_path, references) in &drv_graph {
         for reference in references {
-            let fixed_reference = strip_store_prefix(reference);
-            debug!(
-                "Inserting {:?},{:?} into DrvRef",
-                &drv_path, &fixed_reference
-            );
-            insert_drv_ref(pool, &drv_path, &fixed_reference).await?;
+            debug!("Inserting {:?},{:?} into DrvRef", drv_path, reference);
+            insert_drv_ref(pool, drv_path, reference).await?;
         }
     }
 
@@ -89,8 +99,8 @@ pub async fn insert_drv_graph(
 /// by their drv_path since that was not yet known
 pub async fn insert_drv_ref(
     pool: &Pool<Sqlite>,
-    drv_referrer_path: &str,
-    drv_reference_path: &str,
+    drv_referrer_path: &DrvId,
+    drv_reference_path: &DrvId,
 ) -> anyhow::Result<()> {
     debug!(
         "Inserting DrvRef ({:?}, {:?})",
@@ -127,3 +137,148 @@ VALUES (?1, ?2)
 
     Ok(())
 }
+
+pub async fn insert_drv_outputs(
+    pool: &Pool<Sqlite>,
+    drv_path: &DrvId,
+    outputs: &HashMap<String, StorePath>,
+) -> anyhow::Result<()> {
+    for (name, path) in outputs {
+        debug!(
+            "Inserting DrvOutput ({:?}, {:?}, {:?})",
+            drv_path, name, path
+        );
+
+        sqlx::query(
+            r#"
+INSERT INTO DrvOutput
+    (derivation, name, path)
+VALUES (?1, ?2, ?3)
+    "#,
+        )
+        .bind(drv_path)
+        .bind(name)
+        .bind(path)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn insert_drv_attr(
+    pool: &Pool<Sqlite>,
+    drv_path: &DrvId,
+    attr: &str,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+INSERT INTO DrvAttr
+    (derivation, attr)
+VALUES (?1, ?2)
+    "#,
+    )
+    .bind(drv_path)
+    .bind(attr)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Find the derivation exposed as `attr`. Evaluations of different revisions expose different
+/// derivations under the same attribute, the one whose build state changed last wins.
+pub async fn find_attr_drv(pool: &Pool<Sqlite>, attr: &str) -> anyhow::Result<Option<DrvId>> {
+    let drv = sqlx::query_scalar(
+        r#"
+SELECT a.derivation FROM DrvAttr a
+LEFT JOIN DrvBuildEvent e ON e.derivation = a.derivation
+WHERE a.attr = ?1
+GROUP BY a.derivation
+ORDER BY MAX(e.rowid) DESC
+LIMIT 1
+    "#,
+    )
+    .bind(attr)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(drv)
+}
+
+/// Find the derivation (and the attributes exposing it) which produced `output_path`.
+pub async fn find_output_origin(
+    pool: &Pool<Sqlite>,
+    output_path: &StorePath,
+) -> anyhow::Result<Option<DrvOutputOrigin>> {
+    let output: Option<DrvOutput> = sqlx::query_as(
+        r#"
+SELECT derivation, name, path FROM DrvOutput
+WHERE path = ?1
+    "#,
+    )
+    .bind(output_path)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(output) = output else {
+        return Ok(None);
+    };
+
+    let attrs = sqlx::query_scalar(
+        r#"
+SELECT attr FROM DrvAttr
+WHERE derivation = ?1
+ORDER BY attr
+    "#,
+    )
+    .bind(&output.derivation)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(DrvOutputOrigin { output, attrs }))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn lookup_output_origin(pool: SqlitePool) -> anyhow::Result<()> {
+        let drv = Drv::new(
+            "/nix/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv".parse()?,
+            "x86_64-linux".to_owned(),
+        );
+        insert_drv(&pool, &drv).await?;
+        insert_drv_outputs(
+            &pool,
+            &drv.drv_path,
+            &HashMap::from([(
+                "out".to_owned(),
+                "/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1".parse()?,
+            )]),
+        )
+        .await?;
+        insert_drv_attr(&pool, &drv.drv_path, "hello").await?;
+        insert_drv_attr(&pool, &drv.drv_path, "gnuHello").await?;
+
+        let origin = find_output_origin(
+            &pool,
+            &StorePath::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1")?,
+        )
+        .await?
+        .expect("output should be known");
+
+        assert_eq!(origin.output.derivation, drv.drv_path);
+        assert_eq!(origin.output.name, "out");
+        assert_eq!(origin.attrs, vec!["gnuHello", "hello"]);
+
+        let unknown =
+            find_output_origin(&pool, &"0aykaqxhbby7mx7lgb217m9b3gkl52fn-source".parse()?).await?;
+        assert!(unknown.is_none());
+
+        Ok(())
+    }
+}
@@ -1,18 +1,33 @@
 use std::collections::HashMap;
 use std::path::Path;
 
+use chrono::TimeDelta;
+use shared::store::{DrvId, StorePath};
 use sqlx::migrate;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
+use crate::events::{BuildEvent, EventBus, ServerEvent};
+
+use super::admin::{self, AdminError};
+use super::check::{self, Inconsistency};
+use super::drvs::{self, DrvDetails, DrvFilter, DrvPage, RootFailure, RunningBuild};
+use super::history::{self, DrvBuildAttemptTimeline, DrvBuildTimeStats};
 use super::insert;
-use super::model::{build::DrvBuildMetadata, drv, ForInsert};
+use super::jobset::{self, BuildEventFilter, Evaluation};
+use super::model::{
+    build::{DrvBuildId, DrvBuildMetadata, DrvBuildState, StoredBuildEvent},
+    drv, ForInsert,
+};
 
 #[derive(Clone)]
 pub struct DbService {
     // Instead of exposing this, we should probably have a function
     // where people can get a cloned instance
     pub pool: SqlitePool,
+
+    /// Every inserted build event is published here.
+    events: EventBus,
 }
 
 impl DbService {
@@ -39,7 +54,19 @@ impl DbService {
         info!("Running database migrations");
         migrate!("sql/migrations").run(&pool).await?;
 
-        Ok(DbService { pool })
+        Ok(DbService {
+            pool,
+            events: EventBus::new(),
+        })
+    }
+
+    /// Wrap the already migrated pool of a `sqlx::test`.
+    #[cfg(test)]
+    pub fn from_pool(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            events: EventBus::new(),
+        }
     }
 
     #[allow(dead_code)]
@@ -50,14 +77,201 @@ impl DbService {
         insert::new_drv_build_metadata(metadata, &self.pool).await
     }
 
-    pub async fn has_drv(&self, drv_path: &str) -> anyhow::Result<bool> {
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub async fn restart_build(&self, drv: &DrvId) -> Result<StoredBuildEvent, AdminError> {
+        let event = admin::restart(&self.pool, drv).await?;
+        self.publish_build_events([&event]).await;
+        Ok(event)
+    }
+
+    pub async fn cancel_build(&self, drv: &DrvId) -> Result<StoredBuildEvent, AdminError> {
+        let event = admin::cancel(&self.pool, drv).await?;
+        self.publish_build_events([&event]).await;
+        Ok(event)
+    }
+
+    pub async fn invalidate_build(
+        &self,
+        drv: &DrvId,
+        reason: &str,
+    ) -> Result<StoredBuildEvent, AdminError> {
+        let event = admin::invalidate(&self.pool, drv, reason).await?;
+        self.publish_build_events([&event]).await;
+        Ok(event)
+    }
+
+    pub async fn restart_failures(
+        &self,
+        evaluation: i64,
+    ) -> Result<Vec<StoredBuildEvent>, AdminError> {
+        let events = admin::restart_failures(&self.pool, evaluation).await?;
+        self.publish_build_events(&events).await;
+        Ok(events)
+    }
+
+    async fn publish_build_events<'a>(
+        &self,
+        events: impl IntoIterator<Item = &'a StoredBuildEvent>,
+    ) {
+        for event in events {
+            let mut event = BuildEvent::from(event.clone());
+            match jobset::drv_jobsets(&self.pool, &event.drv).await {
+                Ok(jobsets) => event.jobsets = jobsets.into(),
+                // Only subscribers filtering by jobset miss the event
+                Err(e) => warn!("Failed to look up the jobsets of {}: {:?}", event.drv, e),
+            }
+            self.events.publish(ServerEvent::Build(event));
+        }
+    }
+
+    pub async fn has_drv(&self, drv_path: &DrvId) -> anyhow::Result<bool> {
         drv::has_drv(&self.pool, drv_path).await
     }
 
     pub async fn insert_drv_graph(
         &self,
-        drv_graph: HashMap<String, Vec<String>>,
+        drv_graph: HashMap<DrvId, Vec<DrvId>>,
     ) -> anyhow::Result<()> {
         drv::insert_drv_graph(&self.pool, drv_graph).await
     }
+
+    pub async fn insert_drv_outputs(
+        &self,
+        drv_path: &DrvId,
+        outputs: &HashMap<String, StorePath>,
+    ) -> anyhow::Result<()> {
+        drv::insert_drv_outputs(&self.pool, drv_path, outputs).await
+    }
+
+    pub async fn insert_drv_attr(&self, drv_path: &DrvId, attr: &str) -> anyhow::Result<()> {
+        drv::insert_drv_attr(&self.pool, drv_path, attr).await
+    }
+
+    pub async fn find_output_origin(
+        &self,
+        output_path: &StorePath,
+    ) -> anyhow::Result<Option<drv::DrvOutputOrigin>> {
+        drv::find_output_origin(&self.pool, output_path).await
+    }
+
+    pub async fn find_attr_drv(&self, attr: &str) -> anyhow::Result<Option<DrvId>> {
+        drv::find_attr_drv(&self.pool, attr).await
+    }
+
+    pub async fn build_timeline(
+        &self,
+        drv: &DrvId,
+    ) -> anyhow::Result<Option<Vec<DrvBuildAttemptTimeline>>> {
+        history::build_timeline(&self.pool, drv).await
+    }
+
+    pub async fn build_time(
+        &self,
+        build: &DrvBuildId,
+    ) -> anyhow::Result<Option<(String, TimeDelta)>> {
+        history::build_time(&self.pool, build).await
+    }
+
+    pub async fn build_time_stats(&self) -> anyhow::Result<Vec<DrvBuildTimeStats>> {
+        history::build_time_stats(&self.pool).await
+    }
+
+    pub async fn check_consistency(&self) -> anyhow::Result<Vec<Inconsistency>> {
+        check::check(&self.pool).await
+    }
+
+    pub async fn repair(&self, inconsistencies: &[Inconsistency]) -> anyhow::Result<()> {
+        check::repair(&self.pool, inconsistencies).await
+    }
+
+    pub async fn drv_details(&self, drv: &DrvId) -> anyhow::Result<Option<DrvDetails>> {
+        drvs::drv_details(&self.pool, drv).await
+    }
+
+    pub async fn count_drvs_by_state(&self) -> anyhow::Result<Vec<(DrvBuildState, i64)>> {
+        drvs::count_by_state(&self.pool).await
+    }
+
+    pub async fn latest_build_event(
+        &self,
+        drv: &DrvId,
+    ) -> anyhow::Result<Option<StoredBuildEvent>> {
+        drvs::latest_build_event(&self.pool, drv).await
+    }
+
+    pub async fn root_failures(&self, drv: &DrvId) -> anyhow::Result<Vec<RootFailure>> {
+        drvs::root_failures(&self.pool, drv).await
+    }
+
+    pub async fn running_builds(&self) -> anyhow::Result<Vec<RunningBuild>> {
+        drvs::running_builds(&self.pool).await
+    }
+
+    pub async fn list_drvs(
+        &self,
+        filter: &DrvFilter,
+        after: Option<&DrvId>,
+        limit: u32,
+    ) -> anyhow::Result<DrvPage> {
+        drvs::list_drvs(&self.pool, filter, after, limit).await
+    }
+
+    pub async fn insert_jobset_drv(&self, evaluation: i64, drv: &DrvId) -> anyhow::Result<()> {
+        jobset::insert_jobset_drv(&self.pool, evaluation, drv).await
+    }
+
+    pub async fn queue_evaluation(&self, jobset: &str, pr: Option<u64>) -> anyhow::Result<i64> {
+        jobset::queue_evaluation(&self.pool, jobset, pr).await
+    }
+
+    pub async fn start_evaluation(&self, id: i64) -> anyhow::Result<()> {
+        jobset::start_evaluation(&self.pool, id).await
+    }
+
+    pub async fn evaluation(&self, id: i64) -> anyhow::Result<Option<Evaluation>> {
+        jobset::evaluation(&self.pool, id).await
+    }
+
+    pub async fn finish_evaluation(
+        &self,
+        id: i64,
+        result: Result<usize, &str>,
+    ) -> anyhow::Result<()> {
+        jobset::finish_evaluation(&self.pool, id, result).await
+    }
+
+    pub async fn abandon_evaluations(&self) -> anyhow::Result<u64> {
+        jobset::abandon_evaluations(&self.pool).await
+    }
+
+    pub async fn recent_evaluations(&self, limit: u32) -> anyhow::Result<Vec<Evaluation>> {
+        jobset::recent_evaluations(&self.pool, limit).await
+    }
+
+    pub async fn latest_evaluation(
+        &self,
+        jobset: &str,
+        pr: Option<u64>,
+    ) -> anyhow::Result<Option<Evaluation>> {
+        jobset::latest_evaluation(&self.pool, jobset, pr).await
+    }
+
+    pub async fn evaluation_states(
+        &self,
+        evaluation: i64,
+    ) -> anyhow::Result<Vec<Option<DrvBuildState>>> {
+        jobset::evaluation_states(&self.pool, evaluation).await
+    }
+
+    pub async fn build_events_after(
+        &self,
+        filter: &BuildEventFilter,
+        after: i64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<StoredBuildEvent>> {
+        jobset::build_events_after(&self.pool, filter, after, limit).await
+    }
 }
@@ -0,0 +1,113 @@
+//! Live feed of everything happening on the server.
+//!
+//! Services publish [`ServerEvent`]s on the [`EventBus`], which fans them out to all subscribers
+//! (e.g. the `/v1/events` endpoint). Subscribers which are too slow miss events, the bus never
+//! blocks a publisher.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use shared::build::DrvBuildState;
+use shared::store::DrvId;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::db::model::build::StoredBuildEvent;
+
+/// Number of events a subscriber may fall behind before it starts missing events.
+const BUS_CAPACITY: usize = 4096;
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Build(BuildEvent),
+    Eval(EvalEvent),
+}
+
+/// Jobset and pull request pairs of a [`BuildEvent`].
+pub type EventJobsets = Arc<[(String, Option<u64>)]>;
+
+/// A new [`DrvBuildEvent`](crate::db::model::build::DrvBuildEvent) was recorded.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct BuildEvent {
+    /// ROWID of the event in the database, increases with every event.
+    pub id: i64,
+    pub drv: DrvId,
+    pub build_attempt: u32,
+    pub state: DrvBuildState,
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    /// Jobsets and pull requests evaluations exposed the derivation for, so that subscribers can
+    /// filter events without asking the database. Only set on published events.
+    #[serde(skip)]
+    pub jobsets: EventJobsets,
+}
+
+impl From<StoredBuildEvent> for BuildEvent {
+    fn from(value: StoredBuildEvent) -> Self {
+        Self {
+            id: value.rowid,
+            drv: value.event.build.derivation,
+            build_attempt: value.event.build.build_attempt.get(),
+            state: value.event.state,
+            timestamp: value.event.timestamp.timestamp(),
+            jobsets: Arc::default(),
+        }
+    }
+}
+
+/// Progress of a jobset evaluation.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct EvalEvent {
+    /// Id of the evaluation, stable across its events
+    pub evaluation: i64,
+    pub jobset: String,
+    pub pr: Option<u64>,
+    #[serde(flatten)]
+    pub kind: EvalEventKind,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EvalEventKind {
+    Started,
+    /// The evaluation finished, `drvs` derivations were found.
+    Finished {
+        drvs: usize,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        // Having no subscribers is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    #[cfg(test)]
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
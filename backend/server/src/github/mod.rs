@@ -4,6 +4,8 @@ use octocrab::{Octocrab, Page};
 use thiserror::Error;
 use tracing::info;
 
+use crate::metrics::Metrics;
+
 #[derive(Error, Debug)]
 pub enum AppRegistrationError {
     #[error(transparent)]
@@ -16,7 +18,26 @@ pub enum AppRegistrationError {
     Octocrab(#[from] octocrab::Error),
 }
 
-pub async fn register_app() -> Result<Page<Installation>, AppRegistrationError> {
+/// A registered GitHub app.
+#[derive(Clone)]
+pub struct GitHubApp {
+    octocrab: Octocrab,
+    metrics: Metrics,
+}
+
+impl GitHubApp {
+    /// Check that GitHub still accepts the app credentials, returns the name of the app.
+    pub async fn verify(&self) -> Result<String, octocrab::Error> {
+        let app = self.octocrab.current().app().await;
+        self.metrics.github_request(app.is_ok());
+
+        Ok(app?.name)
+    }
+}
+
+pub async fn register_app(
+    metrics: &Metrics,
+) -> Result<(GitHubApp, Page<Installation>), AppRegistrationError> {
     let app_id = std::env::var("GITHUB_APP_ID")
         .context("failed to locate $GITHUB_APP_ID")?
         .parse::<u64>()?
@@ -27,9 +48,15 @@ pub async fn register_app() -> Result<Page<Installation>, AppRegistrationError>
     let key = jsonwebtoken::EncodingKey::from_rsa_pem(app_private_key.as_bytes())?;
 
     let octocrab = Octocrab::builder().app(app_id, key).build()?;
-    let installations = octocrab.apps().installations().send().await?;
+    let installations = octocrab.apps().installations().send().await;
+    metrics.github_request(installations.is_ok());
+    let installations = installations?;
 
     info!("Successfully registered as github app");
 
-    Ok(installations)
+    let app = GitHubApp {
+        octocrab,
+        metrics: metrics.clone(),
+    };
+    Ok((app, installations))
 }
@@ -0,0 +1,319 @@
+//! Health of the server, computed from checks of the subsystems it depends on.
+//!
+//! The overall status is the worst status of all components. Only failures the server can not
+//! work around (no database, no evaluator, a full disk) are reported as
+//! [`ServerStatus::Dead`], everything else degrades the server at most.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use shared::build::DrvBuildState;
+use shared::types::{ComponentStatus, InfoResponse, ServerStatus};
+use tokio::sync::mpsc::Sender;
+
+use crate::db::DbService;
+use crate::github::GitHubApp;
+use crate::nix::EvalTask;
+
+/// Checks taking longer than this count as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// GitHub rate limits requests, so its answer is reused for this long.
+const GITHUB_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The evaluator is degraded for this long after it had to turn away a task.
+const EVAL_REJECTION_MEMORY: Duration = Duration::from_secs(300);
+
+/// Free space below which the server is degraded.
+const DISK_LOW: u64 = 1024 * 1024 * 1024;
+/// Free space below which writes are about to fail.
+const DISK_CRITICAL: u64 = 100 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct Health {
+    db_service: DbService,
+    eval_sender: Sender<EvalTask>,
+    /// When a task was last turned away because the evaluation queue was full.
+    eval_rejected: Arc<Mutex<Option<Instant>>>,
+    github: Option<GitHubApp>,
+    github_status: Arc<Mutex<Option<(Instant, ComponentStatus)>>>,
+    /// Directories the server writes to, their file systems must not run full.
+    data_dirs: Vec<PathBuf>,
+}
+
+impl Health {
+    pub fn new(
+        db_service: DbService,
+        eval_sender: Sender<EvalTask>,
+        github: Option<GitHubApp>,
+        data_dirs: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            db_service,
+            eval_sender,
+            eval_rejected: Arc::new(Mutex::new(None)),
+            github,
+            github_status: Arc::new(Mutex::new(None)),
+            data_dirs,
+        }
+    }
+
+    /// Record that a task was turned away because the evaluation queue was full.
+    pub fn eval_rejected(&self) {
+        *self
+            .eval_rejected
+            .lock()
+            .expect("eval rejection lock is never poisoned") = Some(Instant::now());
+    }
+
+    /// Only checks the server process itself, without touching any external resources.
+    pub fn liveness(&self) -> InfoResponse {
+        report(vec![self.check_evaluator()])
+    }
+
+    /// Run all checks.
+    pub async fn readiness(&self) -> InfoResponse {
+        let (database, builders, github) = tokio::join!(
+            self.check_database(),
+            self.check_builders(),
+            self.check_github()
+        );
+
+        report(vec![
+            database,
+            self.check_evaluator(),
+            builders,
+            github,
+            self.check_disk(),
+        ])
+    }
+
+    async fn check_database(&self) -> ComponentStatus {
+        let query = sqlx::query("SELECT 1").execute(&self.db_service.pool);
+        match tokio::time::timeout(CHECK_TIMEOUT, query).await {
+            Ok(Ok(_)) => component("database", ServerStatus::Active, "reachable"),
+            Ok(Err(e)) => component("database", ServerStatus::Dead, format!("query failed: {e}")),
+            Err(_) => component("database", ServerStatus::Dead, "query timed out"),
+        }
+    }
+
+    fn check_evaluator(&self) -> ComponentStatus {
+        // The receiver is only dropped when the evaluation service stopped, e.g. by panicking
+        if self.eval_sender.is_closed() {
+            return component(
+                "evaluator",
+                ServerStatus::Dead,
+                "evaluation service stopped",
+            );
+        }
+
+        let queued = self.eval_sender.max_capacity() - self.eval_sender.capacity();
+        let rejected = self
+            .eval_rejected
+            .lock()
+            .expect("eval rejection lock is never poisoned")
+            .map(|rejected| rejected.elapsed())
+            .filter(|&elapsed| elapsed < EVAL_REJECTION_MEMORY);
+        if self.eval_sender.capacity() == 0 {
+            component(
+                "evaluator",
+                ServerStatus::Degraded,
+                format!("queue is full with {queued} tasks"),
+            )
+        } else if let Some(elapsed) = rejected {
+            component(
+                "evaluator",
+                ServerStatus::Degraded,
+                format!(
+                    "{queued} tasks queued, tasks were turned away {}s ago",
+                    elapsed.as_secs()
+                ),
+            )
+        } else {
+            component(
+                "evaluator",
+                ServerStatus::Active,
+                format!("{queued} tasks queued"),
+            )
+        }
+    }
+
+    /// Builders are not registered with the server, so their capacity is inferred from the
+    /// derivations waiting for them: if derivations are buildable but none is being built, no
+    /// builder is picking up work.
+    pub async fn check_builders(&self) -> ComponentStatus {
+        let counts = match self.db_service.count_drvs_by_state().await {
+            Ok(counts) => counts,
+            Err(e) => {
+                return component(
+                    "builders",
+                    ServerStatus::Degraded,
+                    format!("failed to count builds: {e}"),
+                )
+            }
+        };
+        let count = |wanted| {
+            counts
+                .iter()
+                .find(|(state, _)| *state == wanted)
+                .map_or(0, |(_, count)| *count)
+        };
+        let buildable = count(DrvBuildState::Buildable);
+        let building = count(DrvBuildState::Building);
+
+        let status = if buildable > 0 && building == 0 {
+            ServerStatus::Degraded
+        } else {
+            ServerStatus::Active
+        };
+        component(
+            "builders",
+            status,
+            format!("{building} building, {buildable} waiting for a builder"),
+        )
+    }
+
+    async fn check_github(&self) -> ComponentStatus {
+        let Some(github) = &self.github else {
+            return component(
+                "github",
+                ServerStatus::Active,
+                "GitHub app is not configured",
+            );
+        };
+
+        if let Some((checked, status)) = &*self.github_status.lock().expect("lock is not poisoned")
+        {
+            if checked.elapsed() < GITHUB_CHECK_INTERVAL {
+                return status.clone();
+            }
+        }
+
+        let status = match tokio::time::timeout(CHECK_TIMEOUT, github.verify()).await {
+            Ok(Ok(name)) => component(
+                "github",
+                ServerStatus::Active,
+                format!("authenticated as {name}"),
+            ),
+            Ok(Err(e)) => component(
+                "github",
+                ServerStatus::Degraded,
+                format!("app credentials were rejected: {e}"),
+            ),
+            Err(_) => component("github", ServerStatus::Degraded, "GitHub did not respond"),
+        };
+        *self.github_status.lock().expect("lock is not poisoned") =
+            Some((Instant::now(), status.clone()));
+
+        status
+    }
+
+    fn check_disk(&self) -> ComponentStatus {
+        let mut status = ServerStatus::Active;
+        let mut details = Vec::new();
+
+        for dir in &self.data_dirs {
+            // Directories may only be created on the first write, check the file system they
+            // will end up on instead
+            let existing = dir
+                .ancestors()
+                .find(|ancestor| ancestor.exists())
+                .unwrap_or(dir);
+            match rustix::fs::statvfs(existing) {
+                Ok(stat) => {
+                    let free = stat.f_bavail * stat.f_frsize;
+                    status = status.max(if free < DISK_CRITICAL {
+                        ServerStatus::Dead
+                    } else if free < DISK_LOW {
+                        ServerStatus::Degraded
+                    } else {
+                        ServerStatus::Active
+                    });
+                    details.push(format!(
+                        "{} MiB free on {}",
+                        free / (1024 * 1024),
+                        dir.display()
+                    ));
+                }
+                Err(e) => {
+                    status = status.max(ServerStatus::Degraded);
+                    details.push(format!("failed to check {}: {e}", dir.display()));
+                }
+            }
+        }
+
+        component("disk", status, details.join(", "))
+    }
+}
+
+fn component(name: &str, status: ServerStatus, detail: impl Into<String>) -> ComponentStatus {
+    ComponentStatus {
+        name: name.to_owned(),
+        status,
+        detail: detail.into(),
+    }
+}
+
+fn report(components: Vec<ComponentStatus>) -> InfoResponse {
+    InfoResponse {
+        status: components
+            .iter()
+            .map(|component| component.status)
+            .max()
+            .unwrap_or(ServerStatus::Active),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        components,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc::channel;
+
+    use crate::db::model::build::{dummy_drv_id, insert_raw_build_event};
+
+    use super::*;
+
+    fn status(info: &InfoResponse, name: &str) -> ServerStatus {
+        info.components
+            .iter()
+            .find(|component| component.name == name)
+            .expect("component should be reported")
+            .status
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn component_status(pool: SqlitePool) -> anyhow::Result<()> {
+        let (sender, receiver) = channel(1);
+        let health = Health::new(DbService::from_pool(pool.clone()), sender, None, Vec::new());
+
+        let info = health.readiness().await;
+        assert_eq!(info.status, ServerStatus::Active);
+        assert_eq!(status(&info, "database"), ServerStatus::Active);
+        assert_eq!(status(&info, "github"), ServerStatus::Active);
+
+        // a derivation waits for a builder, but nothing is building
+        insert_raw_build_event(&pool, &dummy_drv_id(), 1, DrvBuildState::Buildable, Some(0))
+            .await?;
+        let info = health.readiness().await;
+        assert_eq!(info.status, ServerStatus::Degraded);
+        assert_eq!(status(&info, "builders"), ServerStatus::Degraded);
+
+        health.eval_rejected();
+        let info = health.liveness();
+        assert_eq!(status(&info, "evaluator"), ServerStatus::Degraded);
+
+        drop(receiver);
+        let info = health.liveness();
+        assert_eq!(info.status, ServerStatus::Dead);
+        assert_eq!(status(&info, "evaluator"), ServerStatus::Dead);
+
+        pool.close().await;
+        let info = health.readiness().await;
+        assert_eq!(status(&info, "database"), ServerStatus::Dead);
+
+        Ok(())
+    }
+}
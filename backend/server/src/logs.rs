@@ -0,0 +1,744 @@
+//! Storage of derivation build logs.
+//!
+//! Every build attempt gets its own gzip compressed log file, containing the interleaved stdout
+//! and stderr lines of the build. The files are located at
+//! `<dir>/<first two hash characters>/<drv>/<attempt>.log.gz`.
+//!
+//! While a build is running, its most recent lines are additionally kept in memory and broadcast
+//! to everyone following the log, see [`LogStore::follow`]. Older lines are read back from the
+//! file.
+//!
+//! Derivations that were built outside of Eka CI (e.g. by someone running `nix-build` against the
+//! same store) have no log of our own. For those, the log Nix stored itself is used instead.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+
+use anyhow::Context;
+use bzip2::read::MultiBzDecoder;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{Stream, StreamExt};
+use shared::store::DrvId;
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+use tokio::sync::broadcast;
+
+use crate::db::model::build::DrvBuildId;
+use crate::nix::NixStore;
+
+const LOG_EXTENSION: &str = "log.gz";
+
+/// Number of lines a follower may fall behind before it starts missing lines.
+const FOLLOW_CAPACITY: usize = 1024;
+
+/// Number of recent lines of a running build kept in memory for new followers.
+const LIVE_LINES: usize = 1024;
+
+/// Size of the chunks a stored log is streamed in.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks decompressed ahead of a slow consumer.
+const STREAM_CHUNKS: usize = 4;
+
+/// Number of lines decompressed ahead of a slow consumer.
+const STREAM_LINES: usize = 1024;
+
+/// Where a log was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogSource {
+    /// Captured by Eka CI during the given build attempt.
+    Attempt(NonZeroU32),
+    /// Stored by Nix in the log directory of the store.
+    Nix,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogStore {
+    dir: PathBuf,
+    store: NixStore,
+    /// Logs of the builds that are currently being captured.
+    live: Arc<Mutex<HashMap<DrvBuildId, LiveLog>>>,
+}
+
+/// The log of a running build.
+#[derive(Debug)]
+struct LiveLog {
+    /// The most recent lines, to replay them to new followers.
+    recent: VecDeque<Arc<str>>,
+    /// Number of lines that dropped out of `recent`, they are all in the log file.
+    evicted: usize,
+    sender: broadcast::Sender<Arc<str>>,
+}
+
+/// A subscription to the log of a running build, see [`LogStore::follow`].
+pub struct LogFollower {
+    /// The lines logged before following started.
+    pub replay: BoxStream<'static, anyhow::Result<Arc<str>>>,
+    pub receiver: broadcast::Receiver<Arc<str>>,
+}
+
+/// Removes a live log once its capture ends, no matter how.
+struct LiveLogGuard<'a> {
+    live: &'a Mutex<HashMap<DrvBuildId, LiveLog>>,
+    build: &'a DrvBuildId,
+}
+
+impl Drop for LiveLogGuard<'_> {
+    fn drop(&mut self) {
+        // Dropping the sender closes the streams of all followers
+        self.live
+            .lock()
+            .expect("live log lock is never poisoned")
+            .remove(self.build);
+    }
+}
+
+impl LogStore {
+    pub fn new(dir: PathBuf, store: NixStore) -> Self {
+        Self {
+            dir,
+            store,
+            live: Default::default(),
+        }
+    }
+
+    fn drv_dir(&self, drv: &DrvId) -> PathBuf {
+        let (shard, _) = drv.as_str().split_at(2);
+        self.dir.join(shard).join(drv.as_str())
+    }
+
+    fn attempt_path(&self, build: &DrvBuildId) -> PathBuf {
+        self.drv_dir(&build.derivation)
+            .join(format!("{}.{LOG_EXTENSION}", build.build_attempt))
+    }
+
+    /// Write the output of a build attempt to its log file.
+    ///
+    /// Lines of both streams are interleaved in the order they arrive. Returns once both streams
+    /// are closed, which usually means the build process exited.
+    #[allow(dead_code, reason = "Builders will capture their logs with this.")]
+    pub async fn capture(
+        &self,
+        build: &DrvBuildId,
+        stdout: impl AsyncRead + Unpin,
+        stderr: impl AsyncRead + Unpin,
+    ) -> anyhow::Result<()> {
+        let (sender, _) = broadcast::channel(FOLLOW_CAPACITY);
+        self.live
+            .lock()
+            .expect("live log lock is never poisoned")
+            .insert(
+                build.clone(),
+                LiveLog {
+                    recent: VecDeque::new(),
+                    evicted: 0,
+                    sender,
+                },
+            );
+        let _guard = LiveLogGuard {
+            live: &self.live,
+            build,
+        };
+
+        let path = self.attempt_path(build);
+        let (lines, written) = mpsc::channel::<Vec<u8>>();
+        let (this, published) = (self.clone(), build.clone());
+        // Compressing and writing blocks, keep it away from the async workers
+        let writer = tokio::task::spawn_blocking(move || {
+            write_log(&path, written, |lines| this.publish(&published, lines))
+        });
+
+        let mut stdout = tokio::io::BufReader::new(stdout).split(b'\n');
+        let mut stderr = tokio::io::BufReader::new(stderr).split(b'\n');
+        let (mut stdout_open, mut stderr_open) = (true, true);
+        while stdout_open || stderr_open {
+            let line = tokio::select! {
+                line = stdout.next_segment(), if stdout_open => {
+                    let line = line?;
+                    stdout_open = line.is_some();
+                    line
+                }
+                line = stderr.next_segment(), if stderr_open => {
+                    let line = line?;
+                    stderr_open = line.is_some();
+                    line
+                }
+            };
+            if let Some(line) = line {
+                if lines.send(line).is_err() {
+                    // The writer failed, its error is returned below
+                    break;
+                }
+            }
+        }
+
+        drop(lines);
+        writer.await?
+    }
+
+    /// Hand lines that were written to the log file to the followers.
+    fn publish(&self, build: &DrvBuildId, lines: Vec<Vec<u8>>) {
+        let mut live = self.live.lock().expect("live log lock is never poisoned");
+        let Some(log) = live.get_mut(build) else {
+            return;
+        };
+
+        for line in lines {
+            let line: Arc<str> = String::from_utf8_lossy(&line).into();
+            if log.recent.len() == LIVE_LINES {
+                log.recent.pop_front();
+                log.evicted += 1;
+            }
+            log.recent.push_back(line.clone());
+            // Having no followers is not an error
+            let _ = log.sender.send(line);
+        }
+    }
+
+    /// Follow the log of a running build of `drv`, of the given attempt or the latest one.
+    ///
+    /// Returns the lines logged so far and a receiver for the lines that follow, without gaps or
+    /// duplicates in between. The receiver is closed once the build finishes. Returns `None` if
+    /// no such build is running right now.
+    pub fn follow(&self, drv: &DrvId, attempt: Option<NonZeroU32>) -> Option<LogFollower> {
+        let live = self.live.lock().expect("live log lock is never poisoned");
+        let (build, log) = live
+            .iter()
+            .filter(|(build, _)| {
+                build.derivation == *drv && attempt.is_none_or(|a| a == build.build_attempt)
+            })
+            .max_by_key(|(build, _)| build.build_attempt)?;
+
+        let recent = stream::iter(log.recent.iter().cloned().map(Ok).collect::<Vec<_>>());
+        let replay = if log.evicted > 0 {
+            let file = StoredLog {
+                source: LogSource::Attempt(build.build_attempt),
+                path: self.attempt_path(build),
+            };
+            file.lines().take(log.evicted).chain(recent).boxed()
+        } else {
+            recent.boxed()
+        };
+
+        Some(LogFollower {
+            replay,
+            receiver: log.sender.subscribe(),
+        })
+    }
+
+    /// All build attempts of `drv` that have a log, in ascending order.
+    pub fn attempts(&self, drv: &DrvId) -> io::Result<Vec<NonZeroU32>> {
+        let entries = match fs::read_dir(self.drv_dir(drv)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut attempts = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let attempt = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(LOG_EXTENSION)?.strip_suffix('.'))
+                .and_then(|attempt| attempt.parse::<NonZeroU32>().ok());
+            attempts.extend(attempt);
+        }
+        attempts.sort();
+
+        Ok(attempts)
+    }
+
+    /// Find the log of `drv`.
+    ///
+    /// Without an explicit `attempt`, the log of the latest attempt is returned, falling back to
+    /// the log Nix stored if Eka CI never built the derivation. Returns `None` if there is no log.
+    pub async fn open(
+        &self,
+        drv: &DrvId,
+        attempt: Option<NonZeroU32>,
+    ) -> anyhow::Result<Option<StoredLog>> {
+        let this = self.clone();
+        let drv = drv.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let attempt = match attempt {
+                Some(attempt) => Some(attempt),
+                None => this.attempts(&drv)?.pop(),
+            };
+
+            let log = match attempt {
+                Some(attempt) => {
+                    let build = DrvBuildId {
+                        derivation: drv,
+                        build_attempt: attempt,
+                    };
+                    StoredLog {
+                        source: LogSource::Attempt(attempt),
+                        path: this.attempt_path(&build),
+                    }
+                }
+                None => StoredLog {
+                    source: LogSource::Nix,
+                    path: this.store.nix_log_path(&drv),
+                },
+            };
+
+            match fs::metadata(&log.path) {
+                Ok(_) => Ok(Some(log)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e)
+                    .with_context(|| format!("failed to read log file {}", log.path.display())),
+            }
+        })
+        .await?
+    }
+}
+
+/// Write the lines of a build to a new log file, until the sender is dropped.
+///
+/// Lines are passed on to `written` once they can be read back from the file.
+fn write_log(
+    path: &Path,
+    lines: mpsc::Receiver<Vec<u8>>,
+    mut written: impl FnMut(Vec<Vec<u8>>),
+) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = File::create(path)
+        .with_context(|| format!("failed to create log file {}", path.display()))?;
+    let mut log = GzEncoder::new(file, Compression::default());
+
+    // Wait for a line, then take whatever else arrived in the meantime
+    while let Ok(line) = lines.recv() {
+        let mut batch = vec![line];
+        batch.extend(lines.try_iter());
+        for line in &batch {
+            log.write_all(line)?;
+            log.write_all(b"\n")?;
+        }
+        log.flush()?;
+        written(batch);
+    }
+    log.finish()?;
+
+    Ok(())
+}
+
+/// Bytes of a log to read with [`StoredLog::read_range`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogRange {
+    /// From an offset up to an inclusive end, or the end of the log.
+    From(u64, Option<u64>),
+    /// The given number of bytes at the end of the log, or the whole log if it is shorter.
+    Last(u64),
+}
+
+/// Part of a decompressed log, see [`StoredLog::read_range`].
+#[derive(Debug)]
+pub struct LogSlice {
+    /// Offset of the content in the log.
+    pub start: u64,
+    pub content: Vec<u8>,
+    /// Length of the whole log.
+    pub len: u64,
+}
+
+/// A log file that exists on disk, see [`LogStore::open`].
+#[derive(Clone, Debug)]
+pub struct StoredLog {
+    pub source: LogSource,
+    path: PathBuf,
+}
+
+impl StoredLog {
+    fn decoder(&self) -> anyhow::Result<Unterminated<Box<dyn Read + Send>>> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("failed to read log file {}", self.path.display()))?;
+        let file = BufReader::new(file);
+
+        Ok(Unterminated(match self.source {
+            LogSource::Attempt(_) => Box::new(MultiGzDecoder::new(file)),
+            LogSource::Nix => Box::new(MultiBzDecoder::new(file)),
+        }))
+    }
+
+    /// Read `range` of the decompressed log along with the length of the whole log, decompressing
+    /// it only once.
+    ///
+    /// The range is kept in memory, so `None` is returned for ranges longer than `max` bytes. A
+    /// range starting past the end of the log reads no content.
+    pub async fn read_range(
+        &self,
+        range: LogRange,
+        max: usize,
+    ) -> anyhow::Result<Option<LogSlice>> {
+        let log = self.clone();
+
+        // Decompression is CPU bound, keep it away from the async workers
+        tokio::task::spawn_blocking(move || {
+            let mut decoder = log.decoder()?;
+            // One byte more than allowed tells ranges that are too long apart
+            let max = max as u64 + 1;
+            match range {
+                LogRange::From(start, end) => {
+                    let skipped = io::copy(&mut (&mut decoder).take(start), &mut io::sink())?;
+                    let limit =
+                        end.map_or(u64::MAX, |end| end.saturating_add(1).saturating_sub(start));
+                    let mut content = Vec::new();
+                    (&mut decoder)
+                        .take(limit.min(max))
+                        .read_to_end(&mut content)?;
+                    if content.len() as u64 == max {
+                        return Ok(None);
+                    }
+                    let rest = io::copy(&mut decoder, &mut io::sink())?;
+
+                    Ok(Some(LogSlice {
+                        start,
+                        len: skipped + content.len() as u64 + rest,
+                        content,
+                    }))
+                }
+                LogRange::Last(last) => {
+                    let keep = last.min(max) as usize;
+                    let mut tail = VecDeque::with_capacity(keep);
+                    let mut len = 0;
+                    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+                    loop {
+                        let read = match decoder.read(&mut chunk) {
+                            Ok(0) => break,
+                            Ok(read) => read,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(e) => return Err(e.into()),
+                        };
+                        len += read as u64;
+                        tail.extend(&chunk[..read]);
+                        if tail.len() > keep {
+                            tail.drain(..tail.len() - keep);
+                        }
+                    }
+                    if tail.len() as u64 == max {
+                        return Ok(None);
+                    }
+
+                    Ok(Some(LogSlice {
+                        start: len - tail.len() as u64,
+                        len,
+                        content: tail.into(),
+                    }))
+                }
+            }
+        })
+        .await?
+    }
+
+    /// Stream at most `limit` bytes of the decompressed log, starting at `offset`.
+    pub fn stream(
+        &self,
+        offset: u64,
+        limit: Option<u64>,
+    ) -> impl Stream<Item = anyhow::Result<Vec<u8>>> + Send + 'static {
+        self.spawn_reader(STREAM_CHUNKS, move |mut decoder, sender| {
+            io::copy(&mut (&mut decoder).take(offset), &mut io::sink())?;
+            let mut decoder = decoder.take(limit.unwrap_or(u64::MAX));
+            loop {
+                let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+                let read = match decoder.read(&mut chunk) {
+                    Ok(0) => return Ok(()),
+                    Ok(read) => read,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                chunk.truncate(read);
+                if sender.blocking_send(Ok(chunk)).is_err() {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    /// Stream the lines of the decompressed log, without their line breaks.
+    pub fn lines(&self) -> impl Stream<Item = anyhow::Result<Arc<str>>> + Send + 'static {
+        self.spawn_reader(STREAM_LINES, |decoder, sender| {
+            let mut decoder = BufReader::new(decoder);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if decoder.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(());
+                }
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                if sender
+                    .blocking_send(Ok(String::from_utf8_lossy(&line).into()))
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    /// Decompress the log on a blocking task, which passes what it read on through `sender`.
+    ///
+    /// Only `buffer` items are read ahead of the consumer, and reading stops once the stream is
+    /// dropped, which makes sending fail.
+    fn spawn_reader<T: Send + 'static>(
+        &self,
+        buffer: usize,
+        read: impl FnOnce(
+                Unterminated<Box<dyn Read + Send>>,
+                &tokio::sync::mpsc::Sender<anyhow::Result<T>>,
+            ) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> impl Stream<Item = anyhow::Result<T>> + Send + 'static {
+        let log = self.clone();
+        let (sender, receiver) = tokio::sync::mpsc::channel(buffer);
+
+        // Decompression is CPU bound, keep it away from the async workers
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = log.decoder().and_then(|decoder| read(decoder, &sender)) {
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+
+        stream::unfold(receiver, |mut receiver| async move {
+            let item = receiver.recv().await?;
+            Some((item, receiver))
+        })
+    }
+}
+
+/// Ends a log that is still being written at its last complete block, instead of failing.
+struct Unterminated<R>(R);
+
+impl<R: Read> Read for Unterminated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::db::model::build::dummy_drv_id;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn capture_and_read() -> anyhow::Result<()> {
+        use futures_util::TryStreamExt;
+
+        let dir = tempfile::tempdir()?;
+        let logs = LogStore::new(dir.path().to_owned(), NixStore::new(None, None)?);
+        let build = DrvBuildId {
+            derivation: dummy_drv_id(),
+            build_attempt: NonZeroU32::MIN,
+        };
+
+        logs.capture(&build, &b"building\ndone\n"[..], &b"warning\n"[..])
+            .await?;
+
+        assert_eq!(logs.attempts(&build.derivation)?, vec![NonZeroU32::MIN]);
+        let log = logs.open(&build.derivation, None).await?.unwrap();
+        assert_eq!(log.source, LogSource::Attempt(NonZeroU32::MIN));
+        let mut lines = log.lines().try_collect::<Vec<_>>().await?;
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![Arc::from("building"), "done".into(), "warning".into()]
+        );
+
+        assert!(logs
+            .open(&build.derivation, NonZeroU32::new(2))
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_range() -> anyhow::Result<()> {
+        use futures_util::TryStreamExt;
+
+        let dir = tempfile::tempdir()?;
+        let logs = LogStore::new(dir.path().to_owned(), NixStore::new(None, None)?);
+        let build = DrvBuildId {
+            derivation: dummy_drv_id(),
+            build_attempt: NonZeroU32::MIN,
+        };
+        logs.capture(&build, &b"building\ndone\n"[..], tokio::io::empty())
+            .await?;
+
+        let log = logs.open(&build.derivation, None).await?.unwrap();
+        let range = log.stream(9, Some(4)).try_concat().await?;
+        assert_eq!(range, b"done");
+        let tail = log.stream(9, None).try_concat().await?;
+        assert_eq!(tail, b"done\n");
+
+        let slice = log
+            .read_range(LogRange::From(9, Some(12)), 16)
+            .await?
+            .unwrap();
+        assert_eq!(
+            (slice.start, &slice.content[..], slice.len),
+            (9, &b"done"[..], 14)
+        );
+        let slice = log.read_range(LogRange::Last(5), 16).await?.unwrap();
+        assert_eq!(
+            (slice.start, &slice.content[..], slice.len),
+            (9, &b"done\n"[..], 14)
+        );
+        let slice = log.read_range(LogRange::Last(20), 16).await?.unwrap();
+        assert_eq!((slice.start, slice.content.len(), slice.len), (0, 14, 14));
+        let slice = log.read_range(LogRange::From(20, None), 16).await?.unwrap();
+        assert_eq!((slice.start, slice.content.len(), slice.len), (20, 0, 14));
+        assert!(log.read_range(LogRange::From(0, None), 8).await?.is_none());
+        assert!(log.read_range(LogRange::Last(10), 8).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follow_running_build() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = LogStore::new(dir.path().to_owned(), NixStore::new(None, None)?);
+        let build = DrvBuildId {
+            derivation: dummy_drv_id(),
+            build_attempt: NonZeroU32::MIN,
+        };
+        assert!(logs.follow(&build.derivation, None).is_none());
+
+        let (mut stdout, builder_stdout) = tokio::io::duplex(64);
+        let capture = {
+            let logs = logs.clone();
+            let build = build.clone();
+            tokio::spawn(async move {
+                logs.capture(&build, builder_stdout, tokio::io::empty())
+                    .await
+            })
+        };
+
+        stdout.write_all(b"configuring\n").await?;
+        // wait until the capture picked the first line up
+        let (replay, mut receiver) = wait_for_lines(&logs, &build, 1).await?;
+        assert_eq!(replay, vec![Arc::from("configuring")]);
+
+        stdout.write_all(b"building\n").await?;
+        assert_eq!(receiver.recv().await?, Arc::from("building"));
+
+        drop(stdout);
+        capture.await??;
+        assert!(receiver.recv().await.is_err());
+        assert!(logs.follow(&build.derivation, None).is_none());
+
+        Ok(())
+    }
+
+    /// Follow `build` once at least `count` lines were logged.
+    async fn wait_for_lines(
+        logs: &LogStore,
+        build: &DrvBuildId,
+        count: usize,
+    ) -> anyhow::Result<(Vec<Arc<str>>, broadcast::Receiver<Arc<str>>)> {
+        use futures_util::TryStreamExt;
+
+        loop {
+            if let Some(follower) = logs.follow(&build.derivation, Some(build.build_attempt)) {
+                let replay: Vec<_> = follower.replay.try_collect().await?;
+                if replay.len() >= count {
+                    return Ok((replay, follower.receiver));
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn follow_long_log_per_attempt() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = LogStore::new(dir.path().to_owned(), NixStore::new(None, None)?);
+        let first = DrvBuildId {
+            derivation: dummy_drv_id(),
+            build_attempt: NonZeroU32::MIN,
+        };
+        let second = DrvBuildId {
+            build_attempt: NonZeroU32::new(2).unwrap(),
+            ..first.clone()
+        };
+
+        let mut captures = Vec::new();
+        let mut stdouts = Vec::new();
+        for build in [&first, &second] {
+            let (stdout, builder_stdout) = tokio::io::duplex(64);
+            let logs = logs.clone();
+            let build = build.clone();
+            captures.push(tokio::spawn(async move {
+                logs.capture(&build, builder_stdout, tokio::io::empty())
+                    .await
+            }));
+            stdouts.push(stdout);
+        }
+
+        // more lines than are kept in memory
+        let lines = (0..LIVE_LINES + 10)
+            .map(|i| Arc::from(format!("line {i}")))
+            .collect::<Vec<Arc<str>>>();
+        for line in &lines {
+            stdouts[0].write_all(format!("{line}\n").as_bytes()).await?;
+        }
+        stdouts[1].write_all(b"retrying\n").await?;
+
+        let (replay, _) = wait_for_lines(&logs, &first, lines.len()).await?;
+        assert_eq!(replay, lines);
+        let (replay, _) = wait_for_lines(&logs, &second, 1).await?;
+        assert_eq!(replay, vec![Arc::from("retrying")]);
+        // without an attempt, the latest one is followed
+        let latest = logs.follow(&first.derivation, None).unwrap();
+        assert_eq!(latest.replay.count().await, 1);
+
+        drop(stdouts);
+        for capture in captures {
+            capture.await??;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_nix_log() -> anyhow::Result<()> {
+        use futures_util::TryStreamExt;
+
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        let store = NixStore::new(Some(root.display().to_string()), None)?;
+        let logs = LogStore::new(dir.path().join("logs"), store.clone());
+        let drv = dummy_drv_id();
+
+        assert!(logs.open(&drv, None).await?.is_none());
+
+        let path = store.nix_log_path(&drv);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut encoder =
+            bzip2::write::BzEncoder::new(File::create(&path)?, bzip2::Compression::default());
+        encoder.write_all(b"built by nix\n")?;
+        encoder.finish()?;
+
+        let log = logs.open(&drv, None).await?.unwrap();
+        assert_eq!(log.source, LogSource::Nix);
+        assert_eq!(log.stream(0, None).try_concat().await?, b"built by nix\n");
+
+        Ok(())
+    }
+}
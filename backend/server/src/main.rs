@@ -1,14 +1,21 @@
 mod client;
 mod config;
 mod db;
+mod events;
 mod github;
+mod health;
+mod logs;
+mod metrics;
 mod nix;
 mod web;
 
 use crate::nix::EvalTask;
 use anyhow::Context;
 use client::UnixService;
-use config::Config;
+use config::{Config, ServerCommand};
+use health::Health;
+use logs::LogStore;
+use metrics::Metrics;
 use tokio::sync::mpsc::channel;
 use tracing::{debug, info, level_filters::LevelFilter, warn};
 use tracing_subscriber::EnvFilter;
@@ -30,27 +37,42 @@ async fn main() -> anyhow::Result<()> {
 
     let config = Config::from_env()?;
     debug!("Using configuration {config:?}");
+    info!("Evaluating against Nix store {}", config.store.dir());
 
     let db_service = db::DbService::new(&config.db_path)
         .await
         .context("attempted to create DB pool")?;
 
+    if let Some(ServerCommand::CheckDb { repair }) = config.command {
+        return check_db(&db_service, &config.db_path, repair).await;
+    }
+
+    // Held until the server exits, so `check-db --repair` cannot run next to live builds.
+    let _db_lock = db::DbLock::acquire(&config.db_path)?;
+
+    match db_service.abandon_evaluations().await {
+        Ok(0) => {}
+        Ok(abandoned) => warn!("Marked {} interrupted evaluations as failed", abandoned),
+        Err(e) => warn!("Failed to clean up interrupted evaluations: {:?}", e),
+    }
+
     let (eval_sender, eval_receiver) = channel::<EvalTask>(1000);
-    let eval_service = nix::EvalService::new(eval_receiver, db_service);
+    let metrics = Metrics::new(eval_sender.downgrade());
+    metrics.watch_builds(db_service.clone());
+    let eval_service = nix::EvalService::new(
+        eval_receiver,
+        db_service.clone(),
+        config.store.clone(),
+        metrics.clone(),
+    );
     eval_service.run();
 
-    let unix_service = UnixService::bind_to_path(&config.unix.socket_path, eval_sender)
-        .await
-        .context("failed to start unix service")?;
-    let web_service = WebService::bind_to_address(&config.web.address)
-        .await
-        .context("failed to start web service")?;
-
-    if let Err(e) = github::register_app().await {
+    let github = match github::register_app(&metrics).await {
+        Ok((app, _)) => Some(app),
         // In dev environments, there usually is no authentication, but the server should still be
         // runnable. If someone however tried to configure authentication, make sure to tell them
         // load and clear if there was a problem.
-        if matches!(e, github::AppRegistrationError::InvalidEnv(_)) {
+        Err(e @ github::AppRegistrationError::InvalidEnv(_)) => {
             warn!(
                 "Skipping GitHub app registration: {}",
                 anyhow::Chain::new(&e)
@@ -58,9 +80,44 @@ async fn main() -> anyhow::Result<()> {
                     .collect::<Vec<_>>()
                     .join(": ")
             );
-        } else {
-            Err(e).context("failed to register GitHub app")?;
+            None
         }
+        Err(e) => Err(e).context("failed to register GitHub app")?,
+    };
+
+    let mut data_dirs = vec![config.log_dir.clone()];
+    data_dirs.extend(config.db_path.parent().map(ToOwned::to_owned));
+    let health = Health::new(db_service.clone(), eval_sender.clone(), github, data_dirs);
+
+    let logs = LogStore::new(config.log_dir, config.store.clone());
+    let unix_service = UnixService::bind(
+        config.unix,
+        eval_sender,
+        db_service.clone(),
+        health.clone(),
+        logs.clone(),
+        config.web.public_url,
+    )
+    .await
+    .context("failed to start unix service")?;
+    let mut web_service = WebService::bind_to_address(
+        &config.web.address,
+        db_service,
+        config.store,
+        logs,
+        metrics,
+        health,
+    )
+    .await
+    .context("failed to start web service")?;
+    web_service =
+        web_service.remote_clients(unix_service.request_handler(), config.web.client_tokens);
+    if let Some(admin_token) = config.web.admin_token {
+        web_service = web_service.admin_token(admin_token);
+    }
+    if let Some(bundle_path) = config.web.bundle_path {
+        info!("Serving frontend bundle from {}", bundle_path.display());
+        web_service = web_service.serve_bundle(bundle_path);
     }
 
     // Use `bind_addr` instead of the `addr` + `port` given by the user, to ensure the printed
@@ -71,12 +128,7 @@ async fn main() -> anyhow::Result<()> {
     );
     info!(
         "Listening for client connection on {}",
-        unix_service
-            .bind_addr()
-            .as_pathname()
-            .map_or("<<unnamed socket>>".to_owned(), |path| path
-                .display()
-                .to_string())
+        unix_service.socket_path().display()
     );
 
     tokio::spawn(async { unix_service.run().await });
@@ -84,3 +136,32 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+async fn check_db(
+    db_service: &db::DbService,
+    db_path: &std::path::Path,
+    repair: bool,
+) -> anyhow::Result<()> {
+    let inconsistencies = db_service.check_consistency().await?;
+    if inconsistencies.is_empty() {
+        info!("Database is consistent");
+        return Ok(());
+    }
+
+    for inconsistency in &inconsistencies {
+        warn!("{inconsistency}");
+    }
+    if !repair {
+        anyhow::bail!(
+            "found {} inconsistencies, rerun with --repair to fix them",
+            inconsistencies.len()
+        );
+    }
+
+    // Repairing assumes that nothing is building, which only holds without a running server.
+    let _db_lock = db::DbLock::acquire(db_path).context("refusing to repair")?;
+    db_service.repair(&inconsistencies).await?;
+    info!("Repaired {} inconsistencies", inconsistencies.len());
+
+    Ok(())
+}
@@ -0,0 +1,323 @@
+//! Prometheus metrics of the server, served at `/metrics`.
+//!
+//! Counters and histograms are updated by the services as things happen. Gauges describing the
+//! current state of the server are computed when the metrics are scraped, so they can never drift
+//! from the database.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus_client::encoding::{text, EncodeLabelSet};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::{Registry, Unit};
+use shared::build::{DrvBuildResult, DrvBuildState};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::WeakSender;
+use tracing::warn;
+
+use crate::db::model::build::DrvBuildId;
+use crate::db::DbService;
+use crate::events::{BuildEvent, ServerEvent};
+use crate::nix::EvalTask;
+
+/// Label values of the derivation state gauge. Interruptions are not split up by their kind.
+const STATE_LABELS: [&str; 8] = [
+    "queued",
+    "buildable",
+    "building",
+    "succeeded",
+    "failed",
+    "interrupted",
+    "transitive_failure",
+    "blocked",
+];
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabels {
+    state: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SystemLabels {
+    system: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ResultLabels {
+    result: &'static str,
+}
+
+type HistogramFamily<S> = Family<S, Histogram, fn() -> Histogram>;
+
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+struct MetricsInner {
+    registry: Registry,
+
+    /// Does not keep the channel open, the evaluation service should still notice when all
+    /// senders are gone.
+    eval_sender: WeakSender<EvalTask>,
+
+    drvs: Family<StateLabels, Gauge>,
+    eval_queue_depth: Gauge,
+    eval_duration: Histogram,
+    traversal_duration: Histogram,
+    build_duration: HistogramFamily<SystemLabels>,
+    eval_errors: Counter,
+    github_requests: Family<ResultLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new(eval_sender: WeakSender<EvalTask>) -> Self {
+        let mut registry = Registry::with_prefix("ekaci");
+
+        let drvs = Family::default();
+        registry.register(
+            "drvs",
+            "Derivations by the state of their latest build attempt",
+            drvs.clone(),
+        );
+        let eval_queue_depth = Gauge::default();
+        registry.register(
+            "eval_queue_depth",
+            "Evaluation tasks waiting to be processed",
+            eval_queue_depth.clone(),
+        );
+        let eval_duration = Histogram::new(exponential_buckets(1.0, 2.0, 14));
+        registry.register_with_unit(
+            "eval_duration",
+            "Duration of a whole jobset evaluation",
+            Unit::Seconds,
+            eval_duration.clone(),
+        );
+        let traversal_duration = Histogram::new(exponential_buckets(0.01, 2.0, 14));
+        registry.register_with_unit(
+            "drv_traversal_duration",
+            "Duration of traversing the dependency graph of a new derivation",
+            Unit::Seconds,
+            traversal_duration.clone(),
+        );
+        let build_duration: HistogramFamily<SystemLabels> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(1.0, 2.0, 16)));
+        registry.register_with_unit(
+            "build_duration",
+            "Time completed builds spent building",
+            Unit::Seconds,
+            build_duration.clone(),
+        );
+        let eval_errors = Counter::default();
+        registry.register(
+            "eval_errors",
+            "Errors reported by nix-eval-jobs",
+            eval_errors.clone(),
+        );
+        let github_requests = Family::default();
+        registry.register(
+            "github_api_requests",
+            "Requests made to the GitHub API",
+            github_requests.clone(),
+        );
+
+        Self {
+            inner: Arc::new(MetricsInner {
+                registry,
+                eval_sender,
+                drvs,
+                eval_queue_depth,
+                eval_duration,
+                traversal_duration,
+                build_duration,
+                eval_errors,
+                github_requests,
+            }),
+        }
+    }
+
+    pub fn observe_eval(&self, duration: Duration) {
+        self.inner.eval_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn observe_traversal(&self, duration: Duration) {
+        self.inner
+            .traversal_duration
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn eval_error(&self) {
+        self.inner.eval_errors.inc();
+    }
+
+    pub fn github_request(&self, success: bool) {
+        let result = if success { "success" } else { "error" };
+        self.inner
+            .github_requests
+            .get_or_create(&ResultLabels { result })
+            .inc();
+    }
+
+    /// Record the build time of every build completing from now on.
+    ///
+    /// Build times are taken from the event history, so that they are correct no matter which
+    /// builder reported the build. If the subscription lags behind, the skipped builds are not
+    /// recorded.
+    pub fn watch_builds(&self, db_service: DbService) {
+        let metrics = self.clone();
+        let mut receiver = db_service.events().subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(ServerEvent::Build(event)) => {
+                        if let Err(e) = metrics.record_build(&db_service, &event).await {
+                            warn!("Failed to record build time of {}: {:?}", event.drv, e);
+                        }
+                    }
+                    Ok(ServerEvent::Eval(_)) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Build time metrics skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    async fn record_build(&self, db_service: &DbService, event: &BuildEvent) -> anyhow::Result<()> {
+        if !matches!(event.state, DrvBuildState::Completed(_)) {
+            return Ok(());
+        }
+        let build = DrvBuildId {
+            derivation: event.drv.clone(),
+            build_attempt: event.build_attempt.try_into()?,
+        };
+
+        if let Some((system, duration)) = db_service.build_time(&build).await? {
+            self.inner
+                .build_duration
+                .get_or_create(&SystemLabels { system })
+                .observe(duration.num_seconds() as f64);
+        }
+
+        Ok(())
+    }
+
+    /// Update the gauges and encode all metrics in the OpenMetrics text format.
+    pub async fn encode(&self, db_service: &DbService) -> anyhow::Result<String> {
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        for (state, count) in db_service.count_drvs_by_state().await? {
+            *counts.entry(state_label(state)).or_default() += count;
+        }
+        for state in STATE_LABELS {
+            self.inner
+                .drvs
+                .get_or_create(&StateLabels { state })
+                .set(counts.get(state).copied().unwrap_or_default());
+        }
+
+        let queue_depth = self
+            .inner
+            .eval_sender
+            .upgrade()
+            .map_or(0, |sender| sender.max_capacity() - sender.capacity());
+        self.inner.eval_queue_depth.set(queue_depth as i64);
+
+        let mut encoded = String::new();
+        text::encode(&mut encoded, &self.inner.registry)?;
+
+        Ok(encoded)
+    }
+}
+
+fn state_label(state: DrvBuildState) -> &'static str {
+    match state {
+        DrvBuildState::Queued => "queued",
+        DrvBuildState::Buildable => "buildable",
+        DrvBuildState::Building => "building",
+        DrvBuildState::Completed(DrvBuildResult::Success) => "succeeded",
+        DrvBuildState::Completed(DrvBuildResult::Failure) => "failed",
+        DrvBuildState::Interrupted(_) => "interrupted",
+        DrvBuildState::TransitiveFailure => "transitive_failure",
+        DrvBuildState::Blocked => "blocked",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::build::DrvBuildInterruptionKind;
+    use shared::store::DrvId;
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc::channel;
+
+    use crate::db::model::build::insert_raw_build_event;
+    use crate::db::model::drv::{insert_drv, Drv};
+
+    use super::*;
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn encode_metrics(pool: SqlitePool) -> anyhow::Result<()> {
+        let db_service = DbService::from_pool(pool.clone());
+        let (sender, _receiver) = channel(10);
+        sender
+            .send(EvalTask::TraverseDrv(DrvId::new(
+                "jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv",
+            )?))
+            .await?;
+        let metrics = Metrics::new(sender.downgrade());
+
+        let hello = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")?;
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        insert_drv(&pool, &Drv::new(hello.clone(), "x86_64-linux".to_owned())).await?;
+        insert_drv(&pool, &Drv::new(llvm.clone(), "x86_64-linux".to_owned())).await?;
+        insert_raw_build_event(&pool, &hello, 1, DrvBuildState::Building, Some(100)).await?;
+        insert_raw_build_event(
+            &pool,
+            &hello,
+            1,
+            DrvBuildState::Completed(DrvBuildResult::Success),
+            Some(130),
+        )
+        .await?;
+        insert_raw_build_event(
+            &pool,
+            &llvm,
+            1,
+            DrvBuildState::Interrupted(DrvBuildInterruptionKind::Timeout),
+            Some(100),
+        )
+        .await?;
+
+        metrics
+            .record_build(
+                &db_service,
+                &BuildEvent {
+                    id: 2,
+                    drv: hello,
+                    build_attempt: 1,
+                    state: DrvBuildState::Completed(DrvBuildResult::Success),
+                    timestamp: 130,
+                    jobsets: Default::default(),
+                },
+            )
+            .await?;
+        metrics.eval_error();
+
+        let encoded = metrics.encode(&db_service).await?;
+        assert!(encoded.contains("ekaci_drvs{state=\"succeeded\"} 1\n"));
+        assert!(encoded.contains("ekaci_drvs{state=\"interrupted\"} 1\n"));
+        assert!(encoded.contains("ekaci_drvs{state=\"queued\"} 0\n"));
+        assert!(encoded.contains("ekaci_eval_queue_depth 1\n"));
+        assert!(encoded.contains("ekaci_eval_errors_total 1\n"));
+        assert!(
+            encoded.contains("ekaci_build_duration_seconds_sum{system=\"x86_64-linux\"} 30.0\n")
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! Minimal reader for the ATerm format Nix uses to serialize `.drv` files.
+//!
+//! A derivation file looks like this (whitespace added for readability):
+//!
+//! ```text
+//! Derive(
+//!   [("out","/nix/store/<hash>-hello-2.12.1","","")],  // outputs
+//!   [("/nix/store/<hash>-bash-5.2p37.drv",["out"])],   // input derivations
+//!   ...
+//! )
+//! ```
+//!
+//! Only the parts EkaCI needs are parsed, everything after them is ignored.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use shared::store::{DrvId, StorePath};
+
+use super::NixStore;
+
+/// Read the derivation file of `drv` from `store` and return its outputs.
+pub fn drv_outputs(store: &NixStore, drv: &DrvId) -> Result<HashMap<String, StorePath>> {
+    let drv_path = store.real_path(drv.as_store_path());
+    let contents = std::fs::read_to_string(&drv_path)
+        .with_context(|| format!("failed to read derivation file {}", drv_path.display()))?;
+
+    parse_outputs(&contents)
+        .with_context(|| format!("failed to parse derivation {}", drv_path.display()))?
+        .into_iter()
+        .map(|(name, path)| Ok((name, store.parse_path(&path)?)))
+        .collect()
+}
+
+/// Parse the outputs of a serialized derivation into a mapping of output name to store path.
+///
+/// Floating content-addressed derivations do not know their output paths before they are built,
+/// such outputs are stored with an empty path by Nix and skipped here.
+pub fn parse_outputs(contents: &str) -> Result<HashMap<String, String>> {
+    let mut reader = Reader::new(contents);
+    let mut outputs = HashMap::new();
+
+    reader.expect("Derive(")?;
+    reader.expect("[")?;
+    if !reader.eat("]") {
+        loop {
+            reader.expect("(")?;
+            let name = reader.string()?;
+            reader.expect(",")?;
+            let path = reader.string()?;
+            // hash algorithm and hash, only set for fixed-output derivations
+            reader.expect(",")?;
+            reader.string()?;
+            reader.expect(",")?;
+            reader.string()?;
+            reader.expect(")")?;
+
+            if !path.is_empty() {
+                outputs.insert(name, path);
+            }
+
+            if reader.eat("]") {
+                break;
+            }
+            reader.expect(",")?;
+        }
+    }
+
+    Ok(outputs)
+}
+
+struct Reader<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { rest: input }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if !self.eat(token) {
+            bail!(
+                "expected `{token}`, found `{}`",
+                self.rest.chars().take(16).collect::<String>()
+            );
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+
+        let mut value = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[idx + 1..];
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c)) => value.push(c),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+
+        bail!("unterminated string literal")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multiple_outputs() {
+        let drv = r#"Derive([("dev","/nix/store/0a4c3hdapy9dnkgcgk6kn5m8lbk1h2cz-zlib-1.3.1-dev","",""),("out","/nix/store/4g0n8bjbb4ks5ahpsrdvzyi1mymvvfkp-zlib-1.3.1","","")],[("/nix/store/cfp8jh04f3jfdcjskw2p64ri3w6njndm-bash-5.2p37.drv",["out"])],[],"x86_64-linux","/bin/sh",["-c","echo \"hi\""],[("name","zlib-1.3.1")])"#;
+
+        let outputs = parse_outputs(drv).expect("Failed to parse derivation");
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(
+            outputs["dev"],
+            "/nix/store/0a4c3hdapy9dnkgcgk6kn5m8lbk1h2cz-zlib-1.3.1-dev"
+        );
+        assert_eq!(
+            outputs["out"],
+            "/nix/store/4g0n8bjbb4ks5ahpsrdvzyi1mymvvfkp-zlib-1.3.1"
+        );
+    }
+
+    #[test]
+    fn parse_fixed_output() {
+        let drv = r#"Derive([("out","/nix/store/x6k83rqd620y3zsb5cdj4p85d1ad6pql-source","r:sha256","0f4c1fd2e3b4c5a6")],[],[],"builtin","builtin:fetchurl",[],[])"#;
+
+        let outputs = parse_outputs(drv).expect("Failed to parse derivation");
+
+        assert_eq!(
+            outputs["out"],
+            "/nix/store/x6k83rqd620y3zsb5cdj4p85d1ad6pql-source"
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(parse_outputs("{\"not\": \"a derivation\"}").is_err());
+        assert!(parse_outputs(r#"Derive([("out","/nix/store/unterminated"#).is_err());
+    }
+}
@@ -1,6 +1,10 @@
-use crate::nix::nix_eval_jobs::NixEvalItem;
+use crate::events::{EvalEvent, EvalEventKind, ServerEvent};
+use crate::nix::nix_eval_jobs::{NixEvalDrv, NixEvalItem};
+use crate::nix::EvalJob;
+use anyhow::Context;
 use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::time::Instant;
 use tracing::{debug, warn};
 
 /// This file is meant to handle the evaluation of a "job" which is similar
@@ -11,37 +15,200 @@ use tracing::{debug, warn};
 /// - The file outputs an [deeply nested] attrset of attrset<attr_path, drv>
 
 impl super::EvalService {
-    pub async fn run_nix_eval_jobs(&mut self, file_path: String) -> anyhow::Result<()> {
-        let mut cmd = Command::new("nix-eval-jobs")
-            .arg(file_path)
-            .stdout(Stdio::piped())
-            .spawn()?;
+    /// Evaluate a job, publishing its progress on the event bus and recording its outcome.
+    pub async fn run_job(&mut self, job: &EvalJob) -> anyhow::Result<()> {
+        self.publish_eval(job, EvalEventKind::Started);
+        if let Err(e) = self.db_service.start_evaluation(job.evaluation).await {
+            warn!(
+                "Failed to record start of evaluation {}: {:?}",
+                job.evaluation, e
+            );
+        }
+
+        let start = Instant::now();
+        // The error is recorded and published with its whole chain of causes
+        let result = self
+            .run_nix_eval_jobs(job)
+            .await
+            .map_err(|e| format!("{e:#}"));
+        self.metrics.observe_eval(start.elapsed());
 
+        let outcome = result.as_ref().copied().map_err(String::as_str);
+        if let Err(e) = self
+            .db_service
+            .finish_evaluation(job.evaluation, outcome)
+            .await
         {
-            // TODO: handle failure case more nicely
-            let stdout = cmd.stdout.as_mut().unwrap();
-            // Create a stream, so that we can pass through values as they are produced
-            let stdout_reader = BufReader::new(stdout);
-            let stdout_lines = stdout_reader.lines();
-
-            for line in stdout_lines {
-                if let Ok(input) = line {
-                    let item = serde_json::from_str::<NixEvalItem>(&input)?;
-                    match item {
-                        NixEvalItem::Drv(drv) => {
-                            if let Err(e) = self.traverse_drvs(&drv.drv_path).await {
-                                warn!("Issue while traversing {} drv: {:?}", &drv.drv_path, e);
-                            };
-                        }
-                        NixEvalItem::Error(e) => {
-                            // TODO: Collect evaluation errors, these are still very useful
-                            debug!("error: {:?}", e);
-                        }
+            warn!(
+                "Failed to record outcome of evaluation {}: {:?}",
+                job.evaluation, e
+            );
+        }
+        let kind = match &result {
+            Ok(drvs) => EvalEventKind::Finished { drvs: *drvs },
+            Err(error) => EvalEventKind::Failed {
+                error: error.clone(),
+            },
+        };
+        self.publish_eval(job, kind);
+
+        result.map(|_| ()).map_err(anyhow::Error::msg)
+    }
+
+    fn publish_eval(&self, job: &EvalJob, kind: EvalEventKind) {
+        self.db_service
+            .events()
+            .publish(ServerEvent::Eval(EvalEvent {
+                evaluation: job.evaluation,
+                jobset: job.file_path.clone(),
+                pr: job.pr,
+                kind,
+            }));
+    }
+
+    /// Returns the number of derivations the evaluation produced.
+    ///
+    /// Derivations are recorded as they are produced, but the evaluation fails if any attribute
+    /// could not be evaluated or nix-eval-jobs did not exit successfully.
+    async fn run_nix_eval_jobs(&mut self, job: &EvalJob) -> anyhow::Result<usize> {
+        let mut cmd = Command::new(&self.nix_eval_jobs);
+        if let Some(uri) = self.store.uri() {
+            cmd.args(["--store", uri]);
+        }
+        let mut child = cmd
+            .arg(&job.file_path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {}", self.nix_eval_jobs.display()))?;
+
+        let output = self.read_eval_output(job, &mut child).await;
+        // Also reaped when reading failed, the output is closed by then
+        let status = child.wait()?;
+        let (drvs, errors) = output?;
+
+        if !errors.is_empty() {
+            anyhow::bail!("{}", errors.join("\n\n"));
+        }
+        if !status.success() {
+            anyhow::bail!("nix-eval-jobs failed with {status}");
+        }
+
+        Ok(drvs)
+    }
+
+    /// Record the derivations nix-eval-jobs outputs, returns their number and the evaluation
+    /// errors.
+    async fn read_eval_output(
+        &mut self,
+        job: &EvalJob,
+        child: &mut Child,
+    ) -> anyhow::Result<(usize, Vec<String>)> {
+        let stdout = child.stdout.take().context("nix-eval-jobs has no stdout")?;
+        // Create a stream, so that we can pass through values as they are produced
+        let stdout_lines = BufReader::new(stdout).lines();
+
+        let mut drvs = 0;
+        let mut errors = Vec::new();
+        for input in stdout_lines {
+            let item = serde_json::from_str::<NixEvalItem>(&input?)?;
+            match item {
+                NixEvalItem::Drv(drv) => {
+                    if let Err(e) = self.traverse_drvs(&drv.drv_path).await {
+                        warn!("Issue while traversing {} drv: {:?}", &drv.drv_path, e);
+                        continue;
+                    };
+                    drvs += 1;
+                    if let Err(e) = self.record_eval_drv(job, &drv).await {
+                        warn!("Issue while recording {} drv: {:?}", &drv.drv_path, e);
                     }
                 }
+                NixEvalItem::Error(e) => {
+                    debug!("error: {:?}", e);
+                    self.metrics.eval_error();
+                    errors.push(format!("{}: {}", e.attr, e.error));
+                }
             }
         }
 
+        Ok((drvs, errors))
+    }
+
+    /// Store what nix-eval-jobs told us about a derivation, beyond its dependency graph.
+    async fn record_eval_drv(&self, job: &EvalJob, drv: &NixEvalDrv) -> anyhow::Result<()> {
+        self.db_service
+            .insert_drv_outputs(&drv.drv_path, &drv.outputs)
+            .await?;
+        self.db_service
+            .insert_drv_attr(&drv.drv_path, &drv.attr)
+            .await?;
+        self.db_service
+            .insert_jobset_drv(job.evaluation, &drv.drv_path)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc::channel;
+
+    use crate::db::DbService;
+    use crate::metrics::Metrics;
+    use crate::nix::{EvalService, NixStore};
+
+    use super::*;
+
+    /// Replace nix-eval-jobs with a shell script.
+    fn fake_nix_eval_jobs(dir: &Path, script: &str) -> anyhow::Result<std::path::PathBuf> {
+        let path = dir.join("nix-eval-jobs");
+        std::fs::write(&path, format!("#!/bin/sh\n{script}\n"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(path)
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn failing_jobset(pool: SqlitePool) -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_service = DbService::from_pool(pool);
+        let (sender, receiver) = channel(10);
+        let mut service = EvalService::new(
+            receiver,
+            db_service.clone(),
+            NixStore::new(None, None)?,
+            Metrics::new(sender.downgrade()),
+        );
+        let mut run = async |script: &str| -> anyhow::Result<Option<String>> {
+            service.nix_eval_jobs = fake_nix_eval_jobs(dir.path(), script)?;
+            let evaluation = db_service.queue_evaluation("release.nix", None).await?;
+            let job = EvalJob {
+                evaluation,
+                file_path: "release.nix".to_owned(),
+                pr: None,
+            };
+            assert!(service.run_job(&job).await.is_err());
+            let evaluation = db_service.evaluation(evaluation).await?;
+            Ok(evaluation.and_then(|evaluation| evaluation.error))
+        };
+
+        // an attribute failing to evaluate fails the whole evaluation
+        let error = run(
+            r#"echo '{"attr":"broken","attrPath":["broken"],"error":"error: broken is marked as broken"}'"#,
+        )
+        .await?;
+        assert_eq!(
+            error.as_deref(),
+            Some("broken: error: broken is marked as broken")
+        );
+
+        // so does nix-eval-jobs itself failing, e.g. on a syntax error
+        let error = run("exit 1").await?;
+        assert!(error.is_some_and(|error| error.contains("exit status: 1")));
+
         Ok(())
     }
 }
@@ -1,36 +1,60 @@
+pub mod derivation;
 pub mod jobs;
 pub mod nix_eval_jobs;
+mod store;
 
 use crate::db::DbService;
-use anyhow::Result;
+use crate::metrics::Metrics;
+use anyhow::{Context, Result};
+use shared::store::{DrvId, StorePath};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::Command;
+use std::time::Instant;
 use tokio::sync::mpsc::Receiver;
 use tracing::{debug, warn};
 
+pub use store::NixStore;
+
 pub struct EvalJob {
+    /// Id of the recorded evaluation, see [`DbService::queue_evaluation`]
+    pub evaluation: i64,
     pub file_path: String,
+    /// Pull request the evaluation is done for, if any
+    pub pr: Option<u64>,
     // TODO: support arguments
 }
 
 pub enum EvalTask {
     Job(EvalJob),
-    TraverseDrv(String),
+    TraverseDrv(DrvId),
 }
 
 pub struct EvalService {
     db_service: DbService,
+    store: NixStore,
+    metrics: Metrics,
+    /// Program evaluating jobs, `nix-eval-jobs` from the `PATH` unless replaced in tests
+    nix_eval_jobs: PathBuf,
     drv_receiver: Receiver<EvalTask>,
     // TODO: Eventually this should be an LRU cache
     // This allows for us to memoize visited drvs so we don't have to revisit
     // common drvs (e.g. stdenv)
-    drv_map: HashMap<String, Vec<String>>,
+    drv_map: HashMap<DrvId, Vec<DrvId>>,
 }
 
 impl EvalService {
-    pub fn new(rcvr: Receiver<EvalTask>, db_service: DbService) -> EvalService {
+    pub fn new(
+        rcvr: Receiver<EvalTask>,
+        db_service: DbService,
+        store: NixStore,
+        metrics: Metrics,
+    ) -> EvalService {
         EvalService {
             db_service,
+            store,
+            metrics,
+            nix_eval_jobs: PathBuf::from("nix-eval-jobs"),
             drv_receiver: rcvr,
             drv_map: HashMap::new(),
         }
@@ -45,8 +69,8 @@ impl EvalService {
     async fn listen(mut self) {
         loop {
             match self.drv_receiver.recv().await {
-                Some(EvalTask::Job(drv)) => {
-                    if let Err(e) = self.run_nix_eval_jobs(drv.file_path).await {
+                Some(EvalTask::Job(job)) => {
+                    if let Err(e) = self.run_job(&job).await {
                         warn!("Ran into error when query eval job: {}", e);
                     };
                 }
@@ -63,7 +87,7 @@ impl EvalService {
     }
 
     /// Given a drv, traverse all direct drv dependencies
-    async fn traverse_drvs(&mut self, drv_path: &str) -> Result<()> {
+    async fn traverse_drvs(&mut self, drv_path: &DrvId) -> Result<()> {
         debug!("Entering traverse drvs");
         if self.drv_map.contains_key(drv_path) || self.db_service.has_drv(drv_path).await? {
             debug!("Already evaluated {}, skipping....", drv_path);
@@ -74,12 +98,20 @@ impl EvalService {
         // We must know all of the drvs before refrencing relationships
         // So we must complete the traversal, then attempt assertion of
         // drvs (which are the keys in this case), then can add the references
-        let mut new_drvs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut new_drvs: HashMap<DrvId, Vec<DrvId>> = HashMap::new();
+        let mut new_outputs: HashMap<DrvId, HashMap<String, StorePath>> = HashMap::new();
 
         debug!("traversing {}", drv_path);
-        self.inner_traverse_drvs(drv_path, &mut new_drvs)?;
+        let start = Instant::now();
+        self.inner_traverse_drvs(drv_path, &mut new_drvs, &mut new_outputs)?;
         self.db_service.insert_drv_graph(new_drvs).await?;
 
+        // Outputs reference the Drv rows, so they can only be inserted after the graph
+        for (drv, outputs) in new_outputs {
+            self.db_service.insert_drv_outputs(&drv, &outputs).await?;
+        }
+        self.metrics.observe_traversal(start.elapsed());
+
         Ok(())
     }
 
@@ -87,20 +119,24 @@ impl EvalService {
     /// instead of appending to an iterator and a loop :(
     fn inner_traverse_drvs(
         &mut self,
-        drv_path: &str,
-        new_drvs: &mut HashMap<String, Vec<String>>,
+        drv_path: &DrvId,
+        new_drvs: &mut HashMap<DrvId, Vec<DrvId>>,
+        new_outputs: &mut HashMap<DrvId, HashMap<String, StorePath>>,
     ) -> Result<()> {
-        let references = drv_references(drv_path)?;
+        let references = drv_references(&self.store, drv_path)?;
         debug!("new drv, traversing {}", &drv_path);
-        self.drv_map
-            .insert(drv_path.to_string(), references.clone());
-        new_drvs.insert(drv_path.to_string(), references.clone());
+        self.drv_map.insert(drv_path.clone(), references.clone());
+        new_drvs.insert(drv_path.clone(), references.clone());
+        new_outputs.insert(
+            drv_path.clone(),
+            derivation::drv_outputs(&self.store, drv_path)?,
+        );
 
         for drv in references.into_iter() {
             if self.drv_map.contains_key(&drv) {
                 continue;
             }
-            self.inner_traverse_drvs(&drv, new_drvs)?;
+            self.inner_traverse_drvs(&drv, new_drvs, new_outputs)?;
         }
 
         Ok(())
@@ -108,9 +144,14 @@ impl EvalService {
 }
 
 /// Retreive the direct dependencies of a drv
-fn drv_references(drv_path: &str) -> Result<Vec<String>> {
-    let output = Command::new("nix-store")
-        .args(["--query", "--references", drv_path])
+fn drv_references(store: &NixStore, drv_path: &DrvId) -> Result<Vec<DrvId>> {
+    let mut cmd = Command::new("nix-store");
+    if let Some(uri) = store.uri() {
+        cmd.args(["--store", uri]);
+    }
+    let output = cmd
+        .args(["--query", "--references"])
+        .arg(store.drv_path(drv_path))
         .output()?
         .stdout;
     let drv_str = String::from_utf8(output)?;
@@ -121,8 +162,12 @@ fn drv_references(drv_path: &str) -> Result<Vec<String>> {
         // but rather files which were added to the nix store through
         // path literals or `nix-store --add`
         .filter(|x| x.ends_with(".drv"))
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>();
+        .map(|x| {
+            store
+                .parse_drv(x)
+                .with_context(|| format!("nix-store returned invalid reference for {drv_path}"))
+        })
+        .collect::<Result<Vec<DrvId>>>()?;
 
     Ok(drvs)
 }
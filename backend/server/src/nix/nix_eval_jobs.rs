@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use shared::store::{DrvId, StorePath};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,20 +22,20 @@ pub struct NixEvalDrv {
 
     /// Store path to drv. E.g. "/nix/store/<hash>-<name>.drv"
     #[serde(rename = "drvPath")]
-    pub drv_path: String,
+    pub drv_path: DrvId,
 
     /// A mapping of drv dependencies to their realized outputs which will
     /// be introduced to a build.
     /// For EkaCI, we are less concerned about which outputs are used, and
     /// rather more sensitive to whether the dependencies build
     #[serde(rename = "inputDrvs")]
-    pub input_drvs: HashMap<String, Vec<String>>,
+    pub input_drvs: HashMap<DrvId, Vec<String>>,
 
     /// Name of drv. Usually includes "${pname}-${version}", but doesn't need to
     pub name: String,
 
     /// A mapping of the multiple outputs and their respective nix store paths
-    pub outputs: HashMap<String, String>,
+    pub outputs: HashMap<String, StorePath>,
 
     /// Build platform system
     pub system: String,
@@ -0,0 +1,265 @@
+//! Location of the Nix store the server evaluates against.
+//!
+//! All store paths handed to us by Nix start with the store directory of the evaluating store.
+//! That is `/nix/store` for a default installation, but can be anything for stores created with
+//! a different `store` setting. Chroot stores (e.g. `local?root=/var/lib/ci-root`) additionally
+//! keep the logical store directory, but place the files somewhere else on disk.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use shared::store::{DrvId, StorePath, StorePathError, DEFAULT_STORE_DIR};
+use tracing::{debug, warn};
+
+/// Log directory of a store using the default state directory.
+const DEFAULT_LOG_DIR: &str = "/nix/var/log/nix";
+
+#[derive(Clone, Debug)]
+pub struct NixStore {
+    /// Logical store directory, the prefix of every store path (e.g. `/nix/store`).
+    dir: String,
+    /// Where the store directory is actually located on disk. Only differs from `dir` for
+    /// chroot stores.
+    real_dir: PathBuf,
+    /// Where Nix keeps the build logs of this store on disk.
+    real_log_dir: PathBuf,
+    /// Store URI passed to Nix commands. `None` leaves the choice to Nix's own configuration.
+    uri: Option<String>,
+}
+
+impl NixStore {
+    /// Create a store from an explicitly configured URI and store directory.
+    ///
+    /// If the store directory is not set, it is taken from the URI's `store` parameter, falling
+    /// back to the default `/nix/store`.
+    pub fn new(uri: Option<String>, store_dir: Option<String>) -> Result<Self> {
+        let params = uri
+            .as_deref()
+            .map(StoreUriParams::parse)
+            .unwrap_or_default();
+
+        let dir = store_dir
+            .or(params.store)
+            .unwrap_or_else(|| DEFAULT_STORE_DIR.to_owned());
+        let dir = dir.trim_end_matches('/').to_owned();
+        if !dir.starts_with('/') {
+            bail!("store directory {dir} is not an absolute path");
+        }
+
+        let in_root = |path: &str| match &params.root {
+            Some(root) => Path::new(root).join(path.trim_start_matches('/')),
+            None => PathBuf::from(path),
+        };
+        let real_dir = in_root(&dir);
+        let real_log_dir = match params.log {
+            Some(log) => PathBuf::from(log),
+            None => in_root(DEFAULT_LOG_DIR),
+        };
+
+        Ok(Self {
+            dir,
+            real_dir,
+            real_log_dir,
+            uri,
+        })
+    }
+
+    /// Determine the store from the `store` setting of the local Nix configuration.
+    ///
+    /// Falls back to the default store if Nix cannot be queried, so that a server without Nix
+    /// in its `PATH` can still start up (e.g. for serving the web interface only).
+    pub fn detect(store_dir: Option<String>) -> Result<Self> {
+        let output = Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "config",
+                "show",
+                "store",
+            ])
+            .output();
+
+        let uri = match output {
+            Ok(output) if output.status.success() => {
+                let uri = String::from_utf8(output.stdout)
+                    .context("nix returned a non UTF-8 store setting")?
+                    .trim()
+                    .to_owned();
+                debug!("Detected Nix store setting {uri:?}");
+                // `auto` (the default) resolves to the daemon or the local store, both of which
+                // use the default locations.
+                Some(uri).filter(|uri| !uri.is_empty() && uri != "auto")
+            }
+            Ok(output) => {
+                warn!(
+                    "Failed to query Nix store setting, assuming default store: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Failed to run nix, assuming default store: {e}");
+                None
+            }
+        };
+
+        Self::new(uri, store_dir)
+    }
+
+    /// The logical store directory.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Store URI to pass to Nix commands via `--store`, if one is configured.
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_deref()
+    }
+
+    /// Full logical path of a store object, as understood by Nix commands.
+    pub fn path(&self, path: &StorePath) -> String {
+        format!("{}/{}", self.dir, path)
+    }
+
+    /// Full logical path of a derivation, as understood by Nix commands.
+    pub fn drv_path(&self, drv: &DrvId) -> String {
+        self.path(drv.as_store_path())
+    }
+
+    /// Location of a store object on disk, which is where files need to be read from.
+    pub fn real_path(&self, path: &StorePath) -> PathBuf {
+        self.real_dir.join(path.as_str())
+    }
+
+    /// Location of the log Nix itself stored when building `drv`, if the derivation was built
+    /// by this store and build log compression is enabled (the default).
+    pub fn nix_log_path(&self, drv: &DrvId) -> PathBuf {
+        // Nix shards the logs by the first two characters of the derivation's hash
+        let (shard, rest) = drv.as_str().split_at(2);
+        self.real_log_dir
+            .join("drvs")
+            .join(shard)
+            .join(format!("{rest}.bz2"))
+    }
+
+    /// Parse a full path that Nix returned for this store.
+    pub fn parse_path(&self, path: &str) -> Result<StorePath, StorePathError> {
+        StorePath::from_path_in(path, &self.dir)
+    }
+
+    /// Parse a full derivation path that Nix returned for this store.
+    pub fn parse_drv(&self, path: &str) -> Result<DrvId, StorePathError> {
+        self.parse_path(path)?.try_into()
+    }
+}
+
+/// The parameters of a store URI that influence where store paths are located.
+#[derive(Default)]
+struct StoreUriParams {
+    root: Option<String>,
+    store: Option<String>,
+    log: Option<String>,
+}
+
+impl StoreUriParams {
+    fn parse(uri: &str) -> Self {
+        // A plain path is shorthand for `local?root=<path>`
+        if uri.starts_with('/') {
+            return Self {
+                root: Some(uri.to_owned()),
+                ..Default::default()
+            };
+        }
+
+        let mut params = Self::default();
+        let Some((_, query)) = uri.split_once('?') else {
+            return params;
+        };
+        for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            match key {
+                "root" => params.root = Some(value.to_owned()),
+                "store" => params.store = Some(value.to_owned()),
+                "log" => params.log = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_store() {
+        let store = NixStore::new(None, None).unwrap();
+
+        assert_eq!(store.dir(), "/nix/store");
+        assert_eq!(store.uri(), None);
+
+        let path = StorePath::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1").unwrap();
+        assert_eq!(
+            store.path(&path),
+            "/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1"
+        );
+        assert_eq!(store.real_path(&path), Path::new(&store.path(&path)));
+    }
+
+    #[test]
+    fn chroot_store() {
+        let store = NixStore::new(Some("local?root=/var/lib/ci-root".to_owned()), None).unwrap();
+        let path = StorePath::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1").unwrap();
+
+        // Nix still reports paths in the logical store directory
+        assert_eq!(store.dir(), "/nix/store");
+        assert_eq!(
+            store.real_path(&path),
+            Path::new("/var/lib/ci-root/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1")
+        );
+
+        let drv = DrvId::new("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv").unwrap();
+        assert_eq!(
+            store.nix_log_path(&drv),
+            Path::new(
+                "/var/lib/ci-root/nix/var/log/nix/drvs/jd/83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv.bz2"
+            )
+        );
+
+        // a plain path is a chroot store as well
+        let store = NixStore::new(Some("/var/lib/ci-root".to_owned()), None).unwrap();
+        assert_eq!(
+            store.real_path(&path),
+            Path::new("/var/lib/ci-root/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1")
+        );
+    }
+
+    #[test]
+    fn custom_store_dir() {
+        let store = NixStore::new(Some("local?store=/ci/store&root=/ci".to_owned()), None).unwrap();
+        assert_eq!(store.dir(), "/ci/store");
+
+        let drv = store
+            .parse_drv("/ci/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")
+            .unwrap();
+        assert_eq!(
+            store.drv_path(&drv),
+            "/ci/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"
+        );
+
+        // paths of other stores are rejected
+        assert!(store
+            .parse_drv("/nix/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv")
+            .is_err());
+
+        // an explicit store directory takes precedence over the URI
+        let store = NixStore::new(
+            Some("local?store=/ci/store".to_owned()),
+            Some("/other/store/".to_owned()),
+        )
+        .unwrap();
+        assert_eq!(store.dir(), "/other/store");
+    }
+}
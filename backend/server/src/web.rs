@@ -1,20 +1,130 @@
+mod admin;
+mod requests;
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
 use std::net::{SocketAddr, SocketAddrV4};
+use std::num::NonZeroU32;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::LazyLock;
 
 use anyhow::{Context, Result};
-use axum::{routing::get, Router};
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures_util::{stream, Stream, StreamExt};
+use http_range_header::{EndPosition, StartPosition};
+use serde::{Deserialize, Serialize};
+use shared::{
+    build::{DrvBuildInterruptionKind, DrvBuildResult, DrvBuildState},
+    store::DrvId,
+    types::{InfoResponse, ServerStatus},
+};
 use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::warn;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::client::RequestHandler;
+use crate::config::{BearerToken, ClientToken};
+use crate::db::model::build::{DrvBuildCommand, StoredBuildEvent};
+use crate::db::{
+    drvs,
+    history::DrvBuildDurations,
+    jobset::{BuildEventFilter, Evaluation},
+    DbService,
+};
+use crate::events::ServerEvent;
+use crate::health::Health;
+use crate::logs::{LogRange, LogStore};
+use crate::metrics::Metrics;
+use crate::nix::NixStore;
+use admin::AdminTokenScheme;
+use requests::{ClientTokenScheme, RemoteClients};
 
 pub struct WebService {
     listener: TcpListener,
+    state: AppState,
+    /// Directory of the compiled frontend, if it should be served.
+    bundle_path: Option<PathBuf>,
+}
+
+/// State shared by all request handlers.
+#[derive(Clone)]
+struct AppState {
+    db_service: DbService,
+    store: NixStore,
+    logs: LogStore,
+    metrics: Metrics,
+    health: Health,
+    /// The admin API is disabled without a token.
+    admin_token: Option<BearerToken>,
+    /// Socket requests can not be made through the web API without a handler.
+    remote: Option<RemoteClients>,
 }
 
 impl WebService {
-    pub async fn bind_to_address(socket: &SocketAddrV4) -> Result<Self> {
+    pub async fn bind_to_address(
+        socket: &SocketAddrV4,
+        db_service: DbService,
+        store: NixStore,
+        logs: LogStore,
+        metrics: Metrics,
+        health: Health,
+    ) -> Result<Self> {
         let listener = tokio::net::TcpListener::bind(socket)
             .await
             .context(format!("failed to bind to tcp socket at {socket}"))?;
 
-        Ok(Self { listener })
+        Ok(Self {
+            listener,
+            state: AppState {
+                db_service,
+                store,
+                logs,
+                metrics,
+                health,
+                admin_token: None,
+                remote: None,
+            },
+            bundle_path: None,
+        })
+    }
+
+    /// Serve the compiled frontend from `path` for all requests outside of the API.
+    pub fn serve_bundle(mut self, path: PathBuf) -> Self {
+        if !path.join("index.html").is_file() {
+            warn!("Frontend bundle {} has no index.html", path.display());
+        }
+        self.bundle_path = Some(path);
+        self
+    }
+
+    /// Enable the administrative API, guarded by `token`.
+    pub fn admin_token(mut self, token: BearerToken) -> Self {
+        self.state.admin_token = Some(token);
+        self
+    }
+
+    /// Accept socket requests from remote ekaci clients authenticating with one of `tokens`, or
+    /// the admin token.
+    pub fn remote_clients(mut self, handler: RequestHandler, tokens: Vec<ClientToken>) -> Self {
+        self.state.remote = Some(RemoteClients {
+            handler,
+            tokens: tokens.into(),
+        });
+        self
     }
 
     pub fn bind_addr(&self) -> SocketAddr {
@@ -26,7 +136,7 @@ impl WebService {
     }
 
     pub async fn run(self) {
-        let app = Router::new().nest("/v1", api_routes());
+        let app = app(self.state, self.bundle_path.as_deref());
 
         axum::serve(self.listener, app)
             .await
@@ -34,10 +144,1375 @@ impl WebService {
     }
 }
 
-fn api_routes() -> Router {
-    Router::new().route("/logs/{drv}", get(get_derivation_log))
+fn app(state: AppState, bundle_path: Option<&FsPath>) -> Router {
+    let (api, _) = api().split_for_parts();
+    let mut app = api
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_liveness))
+        .route("/readyz", get(get_readiness));
+    if let Some(bundle_path) = bundle_path {
+        app = app.fallback_service(bundle_service(bundle_path));
+    }
+    app.with_state(state)
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Eka CI",
+        description = "Derivations, build logs and events of an Eka CI server.",
+        license(name = "GNU Affero General Public License v3.0")
+    ),
+    modifiers(&AdminTokenScheme, &ClientTokenScheme)
+)]
+struct ApiDoc;
+
+/// All API routes, nested under their version prefix.
+///
+/// Routes are registered through their `#[utoipa::path]` annotation, so the router and the
+/// OpenAPI document are built from the same source.
+fn api() -> OpenApiRouter<AppState> {
+    OpenApiRouter::with_openapi(ApiDoc::openapi()).nest("/v1", api_routes())
+}
+
+fn api_routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(list_drvs))
+        .routes(routes!(get_drv))
+        .routes(routes!(get_evaluation))
+        .routes(routes!(get_derivation_log))
+        .routes(routes!(follow_derivation_log))
+        .routes(routes!(get_output_origin))
+        .routes(routes!(get_build_timeline))
+        .routes(routes!(get_build_time_stats))
+        .routes(routes!(get_events))
+        .routes(routes!(get_openapi))
+        .merge(admin::routes())
+        .merge(requests::routes())
+        // Otherwise unknown API paths would be answered by the frontend
+        .fallback(|| async { StatusCode::NOT_FOUND })
+}
+
+/// The OpenAPI document describing the web API.
+fn openapi() -> utoipa::openapi::OpenApi {
+    api().into_openapi()
+}
+
+/// OpenAPI document of this API.
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "OpenAPI 3.1 document", content_type = "application/json"))
+)]
+async fn get_openapi() -> Response {
+    static DOCUMENT: LazyLock<String> = LazyLock::new(|| {
+        openapi()
+            .to_pretty_json()
+            .expect("the OpenAPI document always serializes to JSON")
+    });
+
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        DOCUMENT.as_str(),
+    )
+        .into_response()
+}
+
+/// Serves the frontend bundle, answering unknown paths with `index.html` so that the client side
+/// routing of the single page application works when reloading or following links.
+fn bundle_service(path: &FsPath) -> Router {
+    let serve_dir = ServeDir::new(path)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(
+            ServeFile::new(path.join("index.html"))
+                .precompressed_br()
+                .precompressed_gzip(),
+        );
+
+    Router::new()
+        .fallback_service(serve_dir)
+        .layer(middleware::from_fn(set_bundle_cache_control))
+}
+
+/// `index.html` has to be revalidated on every load to pick up new deployments. Assets with a
+/// content hash in their name never change and are cached for good, other assets only for a short
+/// time.
+async fn set_bundle_cache_control(request: Request, next: Next) -> Response {
+    let content_addressed = is_content_addressed(request.uri().path());
+    let mut response = next.run(request).await;
+
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/html"));
+    let cache_control = if is_html {
+        "no-cache"
+    } else if content_addressed {
+        "public, max-age=31536000, immutable"
+    } else {
+        "public, max-age=3600"
+    };
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+
+    response
+}
+
+/// Whether the file name of `path` contains a hex content hash of at least 8 characters as its
+/// last part before the extension, like `main.3f2a9c1b.js` or `main-3f2a9c1b.js`.
+fn is_content_addressed(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let Some((stem, _extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    stem.rsplit_once(['.', '-'])
+        .is_some_and(|(_, hash)| hash.len() >= 8 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Prometheus metrics, outside of the versioned API as scrapers expect them at `/metrics`.
+async fn get_metrics(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let metrics = state.metrics.encode(&state.db_service).await?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics,
+    )
+        .into_response())
+}
+
+/// Liveness probe, only fails if the server has to be restarted.
+async fn get_liveness(State(state): State<AppState>) -> Response {
+    health_response(state.health.liveness())
+}
+
+/// Readiness probe, checks all subsystems the server depends on.
+async fn get_readiness(State(state): State<AppState>) -> Response {
+    health_response(state.health.readiness().await)
+}
+
+/// Degraded servers still answer with 200, they can serve most requests.
+fn health_response(info: InfoResponse) -> Response {
+    let status = match info.status {
+        ServerStatus::Active | ServerStatus::Degraded => StatusCode::OK,
+        ServerStatus::Dead => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status, Json(info)).into_response()
+}
+
+/// Errors a request handler can answer with.
+enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound,
+    Conflict(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(value: anyhow::Error) -> Self {
+        Self::Internal(value)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+            )
+                .into_response(),
+            ApiError::Forbidden(reason) => (StatusCode::FORBIDDEN, reason).into_response(),
+            ApiError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ApiError::Conflict(reason) => (StatusCode::CONFLICT, reason).into_response(),
+            ApiError::Internal(e) => {
+                // Do not leak internals to the client, the log has all the details
+                warn!("Failed to handle web request: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// Upper bound for the page size of list endpoints.
+const MAX_PAGE_SIZE: u32 = 1000;
+
+#[derive(Serialize, ToSchema)]
+struct DrvListEntry {
+    drv: DrvId,
+    system: String,
+    /// `None` if the derivation was never scheduled for building
+    state: Option<DrvBuildState>,
+    /// Unix timestamp in seconds of when the current state was reached
+    updated: Option<i64>,
+}
+
+impl From<drvs::DrvSummary> for DrvListEntry {
+    fn from(value: drvs::DrvSummary) -> Self {
+        Self {
+            drv: value.drv.drv_path,
+            system: value.drv.system,
+            state: value.state,
+            updated: value.updated.map(|updated| updated.timestamp()),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct DrvDetails {
+    #[serde(flatten)]
+    drv: DrvListEntry,
+    /// All build attempts, oldest first
+    attempts: Vec<BuildAttempt>,
+    /// Direct dependencies
+    dependencies: Vec<DrvListEntry>,
+    /// Derivations directly depending on this one
+    referrers: Vec<DrvListEntry>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BuildAttempt {
+    build_attempt: u32,
+    git_repo: String,
+    git_commit: String,
+    build_command: DrvBuildCommand,
+    state: Option<DrvBuildState>,
+    /// Why a maintainer marked the result of this attempt as invalid
+    invalidated: Option<String>,
+}
+
+/// Details of a single derivation, including its build attempts and direct dependencies.
+#[utoipa::path(
+    get,
+    path = "/drvs/{drv}",
+    params(("drv" = DrvId, Path, description = "Derivation to look up")),
+    responses(
+        (status = 200, body = DrvDetails),
+        (status = 404, description = "Unknown derivation"),
+    )
+)]
+async fn get_drv(
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+) -> Result<Json<DrvDetails>, ApiError> {
+    let details = state
+        .db_service
+        .drv_details(&drv)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(DrvDetails {
+        drv: details.drv.into(),
+        attempts: details
+            .attempts
+            .into_iter()
+            .map(|attempt| BuildAttempt {
+                build_attempt: attempt.metadata.build.build_attempt.get(),
+                git_repo: attempt.metadata.git_repo.0.to_bstring().to_string(),
+                git_commit: attempt.metadata.git_commit.0.to_hex().to_string(),
+                build_command: attempt.metadata.build_command,
+                state: attempt.state,
+                invalidated: attempt.invalidated,
+            })
+            .collect(),
+        dependencies: details.dependencies.into_iter().map(Into::into).collect(),
+        referrers: details.referrers.into_iter().map(Into::into).collect(),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct EvaluationView {
+    id: i64,
+    /// The evaluated Nix file
+    jobset: String,
+    pr: Option<u64>,
+    /// Unix timestamps in seconds, `started` and `finished` are `None` until they happened
+    queued: i64,
+    started: Option<i64>,
+    finished: Option<i64>,
+    /// Number of derivations found, if the evaluation succeeded
+    drvs: Option<u64>,
+    /// Why the evaluation failed, if it did
+    error: Option<String>,
+}
+
+impl From<Evaluation> for EvaluationView {
+    fn from(value: Evaluation) -> Self {
+        Self {
+            id: value.id,
+            jobset: value.jobset,
+            pr: value.pr.map(|pr| pr as u64),
+            queued: value.queued.timestamp(),
+            started: value.started.map(|started| started.timestamp()),
+            finished: value.finished.map(|finished| finished.timestamp()),
+            drvs: value.drvs.map(|drvs| drvs as u64),
+            error: value.error,
+        }
+    }
+}
+
+/// A single evaluation of a jobset, as returned when the job was submitted.
+#[utoipa::path(
+    get,
+    path = "/evaluations/{id}",
+    params(("id" = i64, Path, description = "Evaluation to look up")),
+    responses(
+        (status = 200, body = EvaluationView),
+        (status = 404, description = "Unknown evaluation"),
+    )
+)]
+async fn get_evaluation(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<EvaluationView>, ApiError> {
+    let evaluation = state
+        .db_service
+        .evaluation(id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(evaluation.into()))
+}
+
+/// Groups of build states that can be filtered for.
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum StateFilter {
+    Queued,
+    Buildable,
+    Building,
+    /// Completed, regardless of the result
+    Completed,
+    Succeeded,
+    Failed,
+    /// Interrupted, regardless of the reason
+    Interrupted,
+    TransitiveFailure,
+    Blocked,
+}
+
+impl StateFilter {
+    fn states(self) -> Vec<DrvBuildState> {
+        use DrvBuildInterruptionKind::*;
+
+        match self {
+            Self::Queued => vec![DrvBuildState::Queued],
+            Self::Buildable => vec![DrvBuildState::Buildable],
+            Self::Building => vec![DrvBuildState::Building],
+            Self::Completed => vec![
+                DrvBuildState::Completed(DrvBuildResult::Success),
+                DrvBuildState::Completed(DrvBuildResult::Failure),
+            ],
+            Self::Succeeded => vec![DrvBuildState::Completed(DrvBuildResult::Success)],
+            Self::Failed => vec![DrvBuildState::Completed(DrvBuildResult::Failure)],
+            Self::Interrupted => [
+                OutOfMemory,
+                Timeout,
+                Cancelled,
+                ProcessDeath,
+                SchedulerDeath,
+            ]
+            .into_iter()
+            .map(DrvBuildState::Interrupted)
+            .collect(),
+            Self::TransitiveFailure => vec![DrvBuildState::TransitiveFailure],
+            Self::Blocked => vec![DrvBuildState::Blocked],
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DrvListQuery {
+    state: Option<StateFilter>,
+    system: Option<String>,
+    /// Substring of the derivation name
+    name: Option<String>,
+    /// Unix timestamps in seconds limiting when the current state was reached, `until` is
+    /// exclusive
+    since: Option<i64>,
+    until: Option<i64>,
+    /// `next_cursor` of the previous page
+    cursor: Option<DrvId>,
+    /// Page size, defaults to 100
+    limit: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct DrvList {
+    drvs: Vec<DrvListEntry>,
+    /// Pass as `cursor` to get the next page, `None` on the last page
+    next_cursor: Option<DrvId>,
+}
+
+/// List derivations together with their current build state.
+#[utoipa::path(
+    get,
+    path = "/drvs",
+    params(DrvListQuery),
+    responses(
+        (status = 200, body = DrvList),
+        (status = 400, description = "Invalid query parameters", body = String, content_type = "text/plain"),
+    )
+)]
+async fn list_drvs(
+    State(state): State<AppState>,
+    Query(query): Query<DrvListQuery>,
+) -> Result<Json<DrvList>, ApiError> {
+    let timestamp = |secs: Option<i64>| -> Result<_, ApiError> {
+        secs.map(|secs| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .ok_or_else(|| ApiError::BadRequest(format!("invalid timestamp {secs}")))
+        })
+        .transpose()
+    };
+    let filter = drvs::DrvFilter {
+        states: query.state.map(StateFilter::states).unwrap_or_default(),
+        system: query.system,
+        name: query.name,
+        since: timestamp(query.since)?,
+        until: timestamp(query.until)?,
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_PAGE_SIZE);
+
+    let page = state
+        .db_service
+        .list_drvs(&filter, query.cursor.as_ref(), limit)
+        .await?;
+
+    Ok(Json(DrvList {
+        drvs: page.drvs.into_iter().map(Into::into).collect(),
+        next_cursor: page.next,
+    }))
+}
+
+/// Longest range of a log kept in memory to answer a range request.
+const MAX_LOG_RANGE: usize = 8 * 1024 * 1024;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LogQuery {
+    /// Build attempt to return the log of, defaults to the latest attempt
+    #[param(value_type = Option<u32>, minimum = 1)]
+    attempt: Option<NonZeroU32>,
+}
+
+/// Serve the build log of a derivation. Supports single range requests, so that clients can
+/// fetch the tail of a large log or resume a download.
+#[utoipa::path(
+    get,
+    path = "/logs/{drv}",
+    params(("drv" = DrvId, Path, description = "Derivation to return the log of"), LogQuery),
+    responses(
+        (status = 200, description = "The whole build log", body = String, content_type = "text/plain"),
+        (status = 206, description = "The requested range of the build log", body = String, content_type = "text/plain"),
+        (status = 404, description = "No log exists for the derivation"),
+        (status = 416, description = "The requested range is outside of the log"),
+    )
+)]
+async fn get_derivation_log(
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+    Query(query): Query<LogQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let log = state
+        .logs
+        .open(&drv, query.attempt)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| http_range_header::parse_range_header(range).ok());
+    let range = match range.as_ref().map(|range| &range.ranges[..]) {
+        Some([range]) => match (range.start, range.end) {
+            (StartPosition::Index(start), EndPosition::Index(end)) if start <= end => {
+                Some(LogRange::From(start, Some(end)))
+            }
+            (StartPosition::Index(start), EndPosition::LastByte) => {
+                Some(LogRange::From(start, None))
+            }
+            (StartPosition::FromLast(last), _) => Some(LogRange::Last(last)),
+            _ => None,
+        },
+        // Multipart responses are not worth the effort for logs, ignoring invalid or multiple
+        // ranges is allowed
+        _ => None,
+    };
+
+    let slice = match range {
+        Some(range) => log.read_range(range, MAX_LOG_RANGE).await?,
+        None => None,
+    };
+    let (status, body, content_range) = match slice {
+        Some(slice) if slice.content.is_empty() => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", slice.len))],
+            )
+                .into_response());
+        }
+        Some(slice) => {
+            let end = slice.start + slice.content.len() as u64 - 1;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Body::from(slice.content),
+                Some(format!("bytes {}-{end}/{}", slice.start, slice.len)),
+            )
+        }
+        // Ranges too long to keep in memory are answered with the whole log instead
+        None => (StatusCode::OK, Body::from_stream(log.stream(0, None)), None),
+    };
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (header::ACCEPT_RANGES, "bytes"),
+        ],
+        body,
+    )
+        .into_response();
+    if let Some(content_range) = content_range {
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            content_range
+                .parse()
+                .expect("content range is a valid header value"),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Stream the log of a derivation as server-sent events.
+///
+/// Every log line is sent as a `line` event, starting with all lines logged so far. For a running
+/// build, new lines are sent as the builder produces them. Once the build finished (or if it was
+/// not running in the first place), a final `end` event is sent and the stream is closed. Clients
+/// should stop on `end`, instead of letting `EventSource` reconnect.
+#[utoipa::path(
+    get,
+    path = "/logs/{drv}/follow",
+    params(("drv" = DrvId, Path, description = "Derivation to follow the log of")),
+    responses(
+        (status = 200, description = "`line` events followed by a single `end` event", body = String, content_type = "text/event-stream"),
+        (status = 404, description = "The derivation is not building and no log exists"),
+    )
+)]
+async fn follow_derivation_log(
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let (replay, receiver) = match state.logs.follow(&drv, None) {
+        Some(follower) => (follower.replay, Some(follower.receiver)),
+        None => {
+            let log = state
+                .logs
+                .open(&drv, None)
+                .await?
+                .ok_or(ApiError::NotFound)?;
+            (log.lines().boxed(), None)
+        }
+    };
+
+    let live = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        let event = match receiver.recv().await {
+            Ok(line) => log_line_event(&line),
+            Err(RecvError::Lagged(skipped)) => {
+                Event::default().comment(format!("skipped {skipped} lines"))
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, Some(receiver)))
+    });
+    let replay = replay.map(move |line| match line {
+        Ok(line) => log_line_event(&line),
+        Err(e) => {
+            warn!("Failed to read log of {drv}: {e:?}");
+            Event::default().comment("failed to read the log")
+        }
+    });
+    let events = replay
+        .chain(live)
+        .chain(stream::once(async {
+            Event::default().event("end").data("")
+        }))
+        .map(Ok);
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
 
-async fn get_derivation_log(axum::extract::Path(drv): axum::extract::Path<String>) -> String {
-    format!("Dummy log data for {drv}")
+fn log_line_event(line: &str) -> Event {
+    // Carriage returns can not be transmitted, a terminal would only show the text after the last
+    // one anyways (think progress bars).
+    let line = line.rsplit('\r').next().unwrap_or_default();
+    Event::default().event("line").data(line)
+}
+
+/// Build events are replayed from the database in batches of this size.
+const EVENT_REPLAY_BATCH: u32 = 500;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EventQuery {
+    drv: Option<DrvId>,
+    /// Only events relevant to this jobset (the evaluated Nix file)
+    jobset: Option<String>,
+    /// Only events relevant to this pull request
+    pr: Option<u64>,
+}
+
+/// Stream build and evaluation events as server-sent events.
+///
+/// Build events carry their database ROWID as event id. A client reconnecting with
+/// `Last-Event-ID` first receives every build event it missed, then the live feed. Evaluation
+/// events are not stored and therefore not replayed.
+///
+/// Build events are filtered by the derivations an evaluation of the jobset or pull request
+/// exposed, events of their dependencies are not included.
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(
+        EventQuery,
+        ("Last-Event-ID" = Option<i64>, Header, description = "Id of the last received build event"),
+    ),
+    responses(
+        (status = 200, description = "`build` and `eval` events, the data of each is a JSON encoded `ServerEvent`", body = ServerEvent, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid `Last-Event-ID`", body = String, content_type = "text/plain"),
+    )
+)]
+async fn get_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .map(|id| {
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse::<i64>().ok())
+                .ok_or_else(|| ApiError::BadRequest("invalid Last-Event-ID".to_owned()))
+        })
+        .transpose()?;
+    let filter = BuildEventFilter {
+        drv: query.drv,
+        jobset: query.jobset,
+        pr: query.pr,
+    };
+
+    // Subscribe before replaying, so that no event falls into the gap between both
+    let receiver = state.db_service.events().subscribe();
+
+    // The first batch is fetched right away, so that a broken database fails the request
+    let mut feed = EventFeed::new(state.db_service, filter, receiver, last_event_id);
+    feed.fetch_replay().await?;
+
+    let events = stream::unfold(feed, |mut feed| async move {
+        let event = match feed.next().await? {
+            FeedItem::Event(event) => server_event(&event),
+            FeedItem::Skipped(skipped) => {
+                Event::default().comment(format!("skipped {skipped} events"))
+            }
+        };
+        Some((Ok(event), feed))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// The state of a stream of [`get_events`].
+///
+/// Stored build events are replayed from the database batch by batch, both after `Last-Event-ID`
+/// and after falling behind the live feed. Otherwise events come from the live feed.
+struct EventFeed {
+    db_service: DbService,
+    filter: BuildEventFilter,
+    receiver: broadcast::Receiver<ServerEvent>,
+    /// Fetched, but not yet sent build events
+    replay: VecDeque<StoredBuildEvent>,
+    /// ROWID of the last build event the client received, `None` if that is unknown
+    sent_up_to: Option<i64>,
+    /// Whether there may be more stored build events to replay
+    replaying: bool,
+}
+
+/// What an [`EventFeed`] sends next.
+#[derive(Debug)]
+enum FeedItem {
+    Event(ServerEvent),
+    /// The feed fell behind and missed this many live events.
+    Skipped(u64),
+}
+
+impl EventFeed {
+    /// Build events after `last_event_id` are replayed before the events of `receiver`.
+    fn new(
+        db_service: DbService,
+        filter: BuildEventFilter,
+        receiver: broadcast::Receiver<ServerEvent>,
+        last_event_id: Option<i64>,
+    ) -> Self {
+        Self {
+            db_service,
+            filter,
+            receiver,
+            replay: VecDeque::new(),
+            sent_up_to: last_event_id,
+            replaying: last_event_id.is_some(),
+        }
+    }
+
+    async fn fetch_replay(&mut self) -> anyhow::Result<()> {
+        let Some(after) = self.sent_up_to.filter(|_| self.replaying) else {
+            return Ok(());
+        };
+
+        let batch = self
+            .db_service
+            .build_events_after(&self.filter, after, EVENT_REPLAY_BATCH)
+            .await?;
+        self.replaying = batch.len() == EVENT_REPLAY_BATCH as usize;
+        self.replay.extend(batch);
+
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Option<FeedItem> {
+        loop {
+            if let Some(stored) = self.replay.pop_front() {
+                self.sent_up_to = Some(stored.rowid);
+                return Some(FeedItem::Event(ServerEvent::Build(stored.into())));
+            }
+            if self.replaying {
+                if let Err(e) = self.fetch_replay().await {
+                    // Clients reconnect with the last event they got
+                    warn!("Failed to replay build events: {:?}", e);
+                    return None;
+                }
+                continue;
+            }
+
+            let event = match self.receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    // Missed build events are stored, evaluation events are gone though
+                    self.replaying = self.sent_up_to.is_some();
+                    return Some(FeedItem::Skipped(skipped));
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            let matches = match &event {
+                ServerEvent::Build(build) => {
+                    // Already part of the replay
+                    self.sent_up_to.is_none_or(|sent| build.id > sent) && self.filter.matches(build)
+                }
+                ServerEvent::Eval(eval) => {
+                    self.filter.drv.is_none()
+                        && self
+                            .filter
+                            .jobset
+                            .as_ref()
+                            .is_none_or(|jobset| *jobset == eval.jobset)
+                        && self.filter.pr.is_none_or(|pr| eval.pr == Some(pr))
+                }
+            };
+            if matches {
+                if let ServerEvent::Build(build) = &event {
+                    self.sent_up_to = Some(build.id);
+                }
+                return Some(FeedItem::Event(event));
+            }
+        }
+    }
+}
+
+fn server_event(event: &ServerEvent) -> Event {
+    let sse = match event {
+        ServerEvent::Build(build) => Event::default().event("build").id(build.id.to_string()),
+        ServerEvent::Eval(_) => Event::default().event("eval"),
+    };
+    sse.json_data(event)
+        .expect("server events always serialize to JSON")
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OutputQuery {
+    /// Store path to look up, may point to a file inside of the output
+    path: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OutputOrigin {
+    /// Derivation which produced the output
+    drv: DrvId,
+    /// Name of the output, e.g. "out"
+    output: String,
+    /// Attribute paths under which the derivation was evaluated
+    attrs: Vec<String>,
+}
+
+/// Find the derivation which produced a store path.
+#[utoipa::path(
+    get,
+    path = "/outputs",
+    params(OutputQuery),
+    responses(
+        (status = 200, body = OutputOrigin),
+        (status = 400, description = "Not a store path", body = String, content_type = "text/plain"),
+        (status = 404, description = "No known derivation produces the path"),
+    )
+)]
+async fn get_output_origin(
+    State(state): State<AppState>,
+    Query(query): Query<OutputQuery>,
+) -> Result<Json<OutputOrigin>, ApiError> {
+    let output_path = state
+        .store
+        .parse_path(&query.path)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let origin = state
+        .db_service
+        .find_output_origin(&output_path)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(OutputOrigin {
+        drv: origin.output.derivation,
+        output: origin.output.name,
+        attrs: origin.attrs,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct BuildAttemptTimeline {
+    build_attempt: u32,
+    events: Vec<TimelineEvent>,
+    /// Seconds spent in each of the non-terminal states
+    durations: BuildDurations,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TimelineEvent {
+    state: DrvBuildState,
+    /// Unix timestamp in seconds
+    timestamp: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BuildDurations {
+    queued: i64,
+    buildable: i64,
+    building: i64,
+}
+
+impl From<DrvBuildDurations> for BuildDurations {
+    fn from(value: DrvBuildDurations) -> Self {
+        Self {
+            queued: value.queued.num_seconds(),
+            buildable: value.buildable.num_seconds(),
+            building: value.building.num_seconds(),
+        }
+    }
+}
+
+/// State changes of every build attempt of a derivation, oldest attempt first.
+#[utoipa::path(
+    get,
+    path = "/drvs/{drv}/timeline",
+    params(("drv" = DrvId, Path, description = "Derivation to return the timeline of")),
+    responses(
+        (status = 200, body = Vec<BuildAttemptTimeline>),
+        (status = 404, description = "The derivation is unknown"),
+    )
+)]
+async fn get_build_timeline(
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+) -> Result<Json<Vec<BuildAttemptTimeline>>, ApiError> {
+    let timeline = state
+        .db_service
+        .build_timeline(&drv)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(
+        timeline
+            .into_iter()
+            .map(|attempt| BuildAttemptTimeline {
+                build_attempt: attempt.build_attempt.get(),
+                events: attempt
+                    .events
+                    .into_iter()
+                    .map(|event| TimelineEvent {
+                        state: event.state,
+                        timestamp: event.timestamp.timestamp(),
+                    })
+                    .collect(),
+                durations: attempt.durations.into(),
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BuildTimeStatsQuery {
+    /// Only return the slowest `limit` derivation names
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BuildTimeStats {
+    name: String,
+    builds: usize,
+    /// Build time percentiles in seconds
+    build_p50: i64,
+    build_p95: i64,
+    /// Time spent waiting to be built, percentiles in seconds
+    wait_p50: i64,
+    wait_p95: i64,
+}
+
+/// Build and wait time percentiles per derivation name, slowest builds first.
+///
+/// Based on the 100 most recent builds per name that completed within the last 30 days.
+#[utoipa::path(
+    get,
+    path = "/stats/build-times",
+    params(BuildTimeStatsQuery),
+    responses((status = 200, body = Vec<BuildTimeStats>))
+)]
+async fn get_build_time_stats(
+    State(state): State<AppState>,
+    Query(query): Query<BuildTimeStatsQuery>,
+) -> Result<Json<Vec<BuildTimeStats>>, ApiError> {
+    let stats = state.db_service.build_time_stats().await?;
+
+    Ok(Json(
+        stats
+            .into_iter()
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|stats| BuildTimeStats {
+                name: stats.name,
+                builds: stats.builds,
+                build_p50: stats.build_p50.num_seconds(),
+                build_p95: stats.build_p95.num_seconds(),
+                wait_p50: stats.wait_p50.num_seconds(),
+                wait_p95: stats.wait_p95.num_seconds(),
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sqlx::SqlitePool;
+    use tower::ServiceExt;
+
+    use std::time::Duration;
+
+    use crate::db::jobset::drv_jobsets;
+    use crate::db::model::build::{dummy_drv_id, insert_raw_build_event, DrvBuildId};
+    use crate::db::model::drv::{insert_drv, Drv};
+    use crate::events::{BuildEvent, EvalEvent, EvalEventKind};
+
+    use super::*;
+
+    fn state(pool: SqlitePool, log_dir: &FsPath) -> anyhow::Result<AppState> {
+        let db_service = DbService::from_pool(pool);
+        let store = NixStore::new(None, None)?;
+        let (eval_sender, _) = tokio::sync::mpsc::channel(1);
+
+        Ok(AppState {
+            logs: LogStore::new(log_dir.to_owned(), store.clone()),
+            metrics: Metrics::new(eval_sender.downgrade()),
+            health: Health::new(db_service.clone(), eval_sender, None, Vec::new()),
+            db_service,
+            store,
+            admin_token: None,
+            remote: None,
+        })
+    }
+
+    /// Send a GET request for `uri` with the given headers, returning the response with its
+    /// collected body.
+    async fn get(
+        app: &Router,
+        uri: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> anyhow::Result<(Response<()>, Vec<u8>)> {
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let response = app.clone().oneshot(request.body(Body::empty())?).await?;
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await?;
+
+        Ok((Response::from_parts(parts, ()), body.to_vec()))
+    }
+
+    /// Record a build event of `drv` and return it the way it is published.
+    async fn record(db_service: &DbService, drv: &DrvId) -> anyhow::Result<ServerEvent> {
+        insert_raw_build_event(&db_service.pool, drv, 1, DrvBuildState::Queued, None).await?;
+        let filter = BuildEventFilter {
+            drv: Some(drv.clone()),
+            ..Default::default()
+        };
+        let stored = db_service
+            .build_events_after(&filter, 0, u32::MAX)
+            .await?
+            .pop()
+            .expect("the event was just recorded");
+        let mut event = BuildEvent::from(stored);
+        event.jobsets = drv_jobsets(&db_service.pool, drv).await?.into();
+
+        Ok(ServerEvent::Build(event))
+    }
+
+    fn eval_event(jobset: &str, pr: Option<u64>) -> ServerEvent {
+        ServerEvent::Eval(EvalEvent {
+            evaluation: 1,
+            jobset: jobset.to_owned(),
+            pr,
+            kind: EvalEventKind::Started,
+        })
+    }
+
+    /// The next item of `feed` in short, e.g. `build 3` or `skipped 2`, `None` if nothing arrives
+    /// in time.
+    async fn next_item(feed: &mut EventFeed) -> Option<String> {
+        let item = tokio::time::timeout(Duration::from_millis(200), feed.next())
+            .await
+            .ok()??;
+        Some(match item {
+            FeedItem::Event(ServerEvent::Build(build)) => format!("build {}", build.id),
+            FeedItem::Event(ServerEvent::Eval(eval)) => {
+                format!("eval {} {:?}", eval.jobset, eval.pr)
+            }
+            FeedItem::Skipped(skipped) => format!("skipped {skipped}"),
+        })
+    }
+
+    async fn insert_drvs(db_service: &DbService, drvs: &[&DrvId]) -> anyhow::Result<()> {
+        for drv in drvs {
+            let drv = Drv::new((*drv).clone(), "x86_64-linux".to_owned());
+            insert_drv(&db_service.pool, &drv).await?;
+        }
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn events_resume_without_gaps(pool: SqlitePool) -> anyhow::Result<()> {
+        let db_service = DbService::from_pool(pool);
+        let drv = dummy_drv_id();
+        insert_drvs(&db_service, &[&drv]).await?;
+        // Enough events to replay them in more than one batch
+        let stored = EVENT_REPLAY_BATCH as i64 + 2;
+        for _ in 0..stored {
+            record(&db_service, &drv).await?;
+        }
+
+        let (sender, receiver) = broadcast::channel(16);
+        let mut feed = EventFeed::new(
+            db_service.clone(),
+            BuildEventFilter::default(),
+            receiver,
+            Some(1),
+        );
+        feed.fetch_replay().await?;
+        // Recorded after the feed subscribed, so it is both replayed and received live
+        sender.send(record(&db_service, &drv).await?)?;
+
+        for id in 2..=stored + 1 {
+            assert_eq!(next_item(&mut feed).await, Some(format!("build {id}")));
+        }
+        sender.send(record(&db_service, &drv).await?)?;
+        assert_eq!(
+            next_item(&mut feed).await,
+            Some(format!("build {}", stored + 2))
+        );
+        assert_eq!(next_item(&mut feed).await, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn events_replay_after_lagging(pool: SqlitePool) -> anyhow::Result<()> {
+        let db_service = DbService::from_pool(pool);
+        let drv = dummy_drv_id();
+        insert_drvs(&db_service, &[&drv]).await?;
+        record(&db_service, &drv).await?;
+
+        let (sender, receiver) = broadcast::channel(2);
+        let mut feed = EventFeed::new(
+            db_service.clone(),
+            BuildEventFilter::default(),
+            receiver,
+            Some(1),
+        );
+        feed.fetch_replay().await?;
+        for _ in 0..4 {
+            sender.send(record(&db_service, &drv).await?)?;
+        }
+
+        // The missed events are replayed, the ones still received live are not sent twice
+        assert_eq!(next_item(&mut feed).await, Some("skipped 2".to_owned()));
+        for id in 2..=5 {
+            assert_eq!(next_item(&mut feed).await, Some(format!("build {id}")));
+        }
+        assert_eq!(next_item(&mut feed).await, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn events_filtered(pool: SqlitePool) -> anyhow::Result<()> {
+        let db_service = DbService::from_pool(pool);
+        let hello = dummy_drv_id();
+        let llvm = DrvId::new("1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-llvm-19.1.7.drv")?;
+        insert_drvs(&db_service, &[&hello, &llvm]).await?;
+        let release = db_service.queue_evaluation("release.nix", None).await?;
+        let pr_42 = db_service.queue_evaluation("release.nix", Some(42)).await?;
+        db_service.insert_jobset_drv(release, &hello).await?;
+        db_service.insert_jobset_drv(pr_42, &llvm).await?;
+        record(&db_service, &hello).await?;
+        record(&db_service, &llvm).await?;
+
+        let feed = |filter| {
+            let (sender, receiver) = broadcast::channel(16);
+            (
+                sender,
+                EventFeed::new(db_service.clone(), filter, receiver, Some(0)),
+            )
+        };
+        let live = [
+            record(&db_service, &hello).await?,
+            record(&db_service, &llvm).await?,
+            eval_event("release.nix", None),
+            eval_event("release.nix", Some(42)),
+            eval_event("nixos.nix", None),
+        ];
+        let expectations = [
+            (
+                BuildEventFilter {
+                    jobset: Some("release.nix".to_owned()),
+                    pr: Some(42),
+                    ..Default::default()
+                },
+                vec!["build 2", "build 4", "eval release.nix Some(42)"],
+            ),
+            (
+                BuildEventFilter {
+                    jobset: Some("release.nix".to_owned()),
+                    ..Default::default()
+                },
+                vec![
+                    "build 1",
+                    "build 2",
+                    "build 3",
+                    "build 4",
+                    "eval release.nix None",
+                    "eval release.nix Some(42)",
+                ],
+            ),
+            (
+                BuildEventFilter {
+                    pr: Some(42),
+                    ..Default::default()
+                },
+                vec!["build 2", "build 4", "eval release.nix Some(42)"],
+            ),
+            (
+                BuildEventFilter {
+                    drv: Some(hello.clone()),
+                    ..Default::default()
+                },
+                vec!["build 1", "build 3"],
+            ),
+        ];
+
+        for (filter, expected) in expectations {
+            let description = format!("{filter:?}");
+            let (sender, mut feed) = feed(filter);
+            feed.fetch_replay().await?;
+            // The build events were recorded before and are replayed, receiving them live as
+            // well must not duplicate them
+            for event in &live {
+                sender.send(event.clone())?;
+            }
+
+            let mut items = Vec::new();
+            while let Some(item) = next_item(&mut feed).await {
+                items.push(item);
+            }
+            assert_eq!(items, expected, "{description}");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn bundle(pool: SqlitePool) -> anyhow::Result<()> {
+        let logs = tempfile::tempdir()?;
+        let bundle = tempfile::tempdir()?;
+        for (name, content) in [
+            ("index.html", &b"<html>index</html>"[..]),
+            ("index.html.br", b"brotli index"),
+            ("main.js", b"main"),
+            ("main.js.gz", b"gzip main"),
+            ("main.3f2a9c1b.js", b"hashed main"),
+        ] {
+            std::fs::write(bundle.path().join(name), content)?;
+        }
+        let app = app(state(pool, logs.path())?, Some(bundle.path()));
+
+        // Client side routes are answered with the index
+        for uri in ["/", "/drvs/some-drv"] {
+            let (response, body) = get(&app, uri, &[]).await?;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert_eq!(
+                response.headers()[header::CACHE_CONTROL],
+                "no-cache",
+                "{uri}"
+            );
+            assert_eq!(body, b"<html>index</html>", "{uri}");
+        }
+
+        let (response, body) = get(&app, "/main.js", &[]).await?;
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+        assert_eq!(body, b"main");
+        let (response, body) = get(&app, "/main.3f2a9c1b.js", &[]).await?;
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        assert_eq!(body, b"hashed main");
+
+        let (response, body) = get(&app, "/main.js", &[(header::ACCEPT_ENCODING, "gzip")]).await?;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(body, b"gzip main");
+        let (response, body) =
+            get(&app, "/drvs/some-drv", &[(header::ACCEPT_ENCODING, "br")]).await?;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(body, b"brotli index");
+
+        // The API does not fall back to the frontend
+        let (response, _) = get(&app, "/v1/unknown", &[]).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_addressed_assets() {
+        assert!(is_content_addressed("/main.3f2a9c1b.js"));
+        assert!(is_content_addressed("/assets/main-3F2A9C1B0d.css"));
+        assert!(!is_content_addressed("/main.js"));
+        assert!(!is_content_addressed("/main.3f2a9c.js"));
+        assert!(!is_content_addressed("/main-component.js"));
+        assert!(!is_content_addressed("/3f2a9c1b"));
+    }
+
+    #[sqlx::test(migrations = "./sql/migrations")]
+    async fn log_attempts_and_ranges(pool: SqlitePool) -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let state = state(pool, dir.path())?;
+        let drv = dummy_drv_id();
+        for (attempt, log) in [(1, &b"first attempt\n"[..]), (2, b"second attempt\n")] {
+            let build = DrvBuildId {
+                derivation: drv.clone(),
+                build_attempt: NonZeroU32::new(attempt).unwrap(),
+            };
+            state.logs.capture(&build, log, tokio::io::empty()).await?;
+        }
+        let app = app(state, None);
+        let uri = format!("/v1/logs/{drv}");
+
+        let (response, body) = get(&app, &uri, &[]).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, b"second attempt\n");
+        let (response, body) = get(&app, &format!("{uri}?attempt=1"), &[]).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, b"first attempt\n");
+        let (response, _) = get(&app, &format!("{uri}?attempt=3"), &[]).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let (response, body) = get(&app, &uri, &[(header::RANGE, "bytes=-8")]).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 7-14/15");
+        assert_eq!(body, b"attempt\n");
+        let (response, body) = get(
+            &app,
+            &format!("{uri}?attempt=1"),
+            &[(header::RANGE, "bytes=0-4")],
+        )
+        .await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-4/14");
+        assert_eq!(body, b"first");
+        // A suffix longer than the log selects all of it
+        let (response, body) = get(&app, &uri, &[(header::RANGE, "bytes=-100")]).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-14/15");
+        assert_eq!(body, b"second attempt\n");
+
+        let (response, body) = get(&app, &uri, &[(header::RANGE, "bytes=15-")]).await?;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */15");
+        assert!(body.is_empty());
+
+        // Multiple ranges are ignored
+        let (response, body) = get(&app, &uri, &[(header::RANGE, "bytes=0-1,4-5")]).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, b"second attempt\n");
+
+        Ok(())
+    }
+
+    /// The checked in `openapi.json` is what clients are generated from, so it has to match the
+    /// routes. Run with `UPDATE_OPENAPI=1` to regenerate it after changing the API.
+    #[test]
+    fn openapi_document_is_up_to_date() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("openapi.json");
+        let generated = openapi()
+            .to_pretty_json()
+            .expect("the OpenAPI document always serializes to JSON")
+            + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(&path, &generated).expect("failed to write openapi.json");
+            return;
+        }
+
+        let checked_in = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            checked_in == generated,
+            "{} is out of date, run the tests with UPDATE_OPENAPI=1 to regenerate it",
+            path.display()
+        );
+    }
 }
@@ -0,0 +1,180 @@
+//! Administrative build control, for when a builder misbehaves.
+//!
+//! Every request has to carry the configured admin token as bearer token. Without a configured
+//! token, the endpoints are disabled.
+
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{header, request::Parts},
+    Json,
+};
+use serde::Deserialize;
+use shared::store::DrvId;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, ToSchema};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{ApiError, AppState};
+use crate::db::admin::AdminError;
+use crate::events::BuildEvent;
+
+pub(super) fn routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(restart_build))
+        .routes(routes!(cancel_build))
+        .routes(routes!(invalidate_build))
+        .routes(routes!(restart_failures))
+}
+
+/// Documents the bearer token the admin endpoints require.
+pub(super) struct AdminTokenScheme;
+
+impl Modify for AdminTokenScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+/// Proof that the request carries the admin token.
+struct AdminAuth;
+
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let Some(admin_token) = &state.admin_token else {
+            return Err(ApiError::Forbidden(
+                "the admin API is disabled, no admin token is configured".to_owned(),
+            ));
+        };
+
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if admin_token.matches(token) => Ok(AdminAuth),
+            _ => Err(ApiError::Unauthorized),
+        }
+    }
+}
+
+impl From<AdminError> for ApiError {
+    fn from(value: AdminError) -> Self {
+        match value {
+            AdminError::NotScheduled(_) | AdminError::InvalidState { .. } => {
+                ApiError::Conflict(value.to_string())
+            }
+            AdminError::UnknownEvaluation(_) => ApiError::NotFound,
+            AdminError::Event(_) | AdminError::Database(_) => ApiError::Internal(value.into()),
+        }
+    }
+}
+
+/// Start a new build attempt of a derivation whose latest attempt failed or was interrupted.
+#[utoipa::path(
+    post,
+    path = "/admin/drvs/{drv}/restart",
+    params(("drv" = DrvId, Path, description = "Derivation to restart")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "First event of the new build attempt", body = BuildEvent),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 403, description = "The admin API is disabled", body = String, content_type = "text/plain"),
+        (status = 409, description = "The latest build attempt did not fail", body = String, content_type = "text/plain"),
+    )
+)]
+async fn restart_build(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+) -> Result<Json<BuildEvent>, ApiError> {
+    let event = state.db_service.restart_build(&drv).await?;
+    Ok(Json(event.into()))
+}
+
+/// Cancel the latest build attempt of a derivation, whether it is running or not.
+#[utoipa::path(
+    post,
+    path = "/admin/drvs/{drv}/cancel",
+    params(("drv" = DrvId, Path, description = "Derivation to cancel")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The cancellation event", body = BuildEvent),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 403, description = "The admin API is disabled", body = String, content_type = "text/plain"),
+        (status = 409, description = "The latest build attempt already finished", body = String, content_type = "text/plain"),
+    )
+)]
+async fn cancel_build(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+) -> Result<Json<BuildEvent>, ApiError> {
+    let event = state.db_service.cancel_build(&drv).await?;
+    Ok(Json(event.into()))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InvalidateBody {
+    /// Why the result can not be trusted
+    reason: String,
+}
+
+/// Mark the result of the latest, completed build attempt as invalid and build the derivation
+/// again.
+#[utoipa::path(
+    post,
+    path = "/admin/drvs/{drv}/invalidate",
+    params(("drv" = DrvId, Path, description = "Derivation to invalidate")),
+    request_body = InvalidateBody,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "First event of the new build attempt", body = BuildEvent),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 403, description = "The admin API is disabled", body = String, content_type = "text/plain"),
+        (status = 409, description = "The latest build attempt did not complete", body = String, content_type = "text/plain"),
+    )
+)]
+async fn invalidate_build(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(drv): Path<DrvId>,
+    Json(body): Json<InvalidateBody>,
+) -> Result<Json<BuildEvent>, ApiError> {
+    let event = state
+        .db_service
+        .invalidate_build(&drv, &body.reason)
+        .await?;
+    Ok(Json(event.into()))
+}
+
+/// Restart every failed or interrupted build of an evaluation, including the dependencies of the
+/// derivations the evaluation exposed.
+#[utoipa::path(
+    post,
+    path = "/admin/evaluations/{id}/restart-failures",
+    params(("id" = i64, Path, description = "Evaluation to restart the failures of")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "First event of every new build attempt", body = Vec<BuildEvent>),
+        (status = 401, description = "Missing or wrong admin token"),
+        (status = 403, description = "The admin API is disabled", body = String, content_type = "text/plain"),
+        (status = 404, description = "Unknown evaluation"),
+    )
+)]
+async fn restart_failures(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<BuildEvent>>, ApiError> {
+    let events = state.db_service.restart_failures(id).await?;
+    Ok(Json(events.into_iter().map(Into::into).collect()))
+}
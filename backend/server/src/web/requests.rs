@@ -0,0 +1,144 @@
+//! Socket requests made through the web API, for ekaci clients on other machines.
+//!
+//! Clients authenticate with one of the configured client tokens, or the admin token, which
+//! decides what they may request like the peer credentials of a socket client do.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::stream;
+use shared::types::RequestFrame;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::Modify;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{ApiError, AppState};
+use crate::client::{Permission, RequestHandler};
+use crate::config::ClientToken;
+
+pub(super) fn routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(routes!(post_request))
+}
+
+/// Serves the requests of remote clients.
+#[derive(Clone)]
+pub(super) struct RemoteClients {
+    pub handler: RequestHandler,
+    pub tokens: Arc<[ClientToken]>,
+}
+
+/// Documents the bearer token remote clients authenticate with.
+pub(super) struct ClientTokenScheme;
+
+impl Modify for ClientTokenScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "client_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+/// A client that presented a known token.
+struct ClientAuth {
+    /// How the client is referred to in logs and error messages
+    name: String,
+    permission: Permission,
+}
+
+impl FromRequestParts<AppState> for ClientAuth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let tokens = state
+            .remote
+            .as_ref()
+            .map_or(&[][..], |remote| &remote.tokens);
+        if tokens.is_empty() && state.admin_token.is_none() {
+            return Err(ApiError::Forbidden(
+                "remote clients are disabled, no client tokens are configured".to_owned(),
+            ));
+        }
+
+        let Some(token) = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return Err(ApiError::Unauthorized);
+        };
+        if state
+            .admin_token
+            .as_ref()
+            .is_some_and(|admin_token| admin_token.matches(token))
+        {
+            return Ok(ClientAuth {
+                name: "the admin token".to_owned(),
+                permission: Permission::Admin,
+            });
+        }
+        // Every token is compared, to not leak through the timing which one was close
+        let client = tokens.iter().fold(None, |found, client| {
+            found.or(client.token.matches(token).then_some(client))
+        });
+        match client {
+            Some(client) => Ok(ClientAuth {
+                name: format!("token {}", client.name),
+                permission: client.permission,
+            }),
+            None => Err(ApiError::Unauthorized),
+        }
+    }
+}
+
+/// Make a request like an ekaci client connected to the unix socket would.
+///
+/// The body is a single request frame of the socket protocol. It is answered with the response
+/// frames, one JSON object per line, the last of which does not have `more` set. Streamed
+/// responses, like followed logs, keep the connection open until they end.
+#[utoipa::path(
+    post,
+    path = "/requests",
+    request_body(content = String, description = "A request frame of the socket protocol", content_type = "application/json"),
+    security(("client_token" = []), ("admin_token" = [])),
+    responses(
+        (status = 200, description = "Response frames, one per line", body = String, content_type = "application/x-ndjson"),
+        (status = 401, description = "Missing or unknown token"),
+        (status = 403, description = "Remote clients are disabled", body = String, content_type = "text/plain"),
+    )
+)]
+async fn post_request(
+    auth: ClientAuth,
+    State(state): State<AppState>,
+    Json(frame): Json<RequestFrame>,
+) -> Result<Response, ApiError> {
+    let remote = state.remote.ok_or_else(|| {
+        ApiError::Forbidden("remote clients are disabled on this server".to_owned())
+    })?;
+    let frames = remote.handler.handle(frame, auth.name, auth.permission);
+
+    // Dropping the receiver once the client disconnects ends streamed responses
+    let body = stream::unfold(frames, |mut frames| async move {
+        let frame = frames.recv().await?;
+        let mut line = serde_json::to_vec(&frame).expect("Our types should always be serializable");
+        line.push(b'\n');
+        Some((Ok::<_, Infallible>(line), frames))
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
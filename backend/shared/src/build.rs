@@ -0,0 +1,319 @@
+//! The lifecycle of a derivation build.
+//!
+//! These states are stored in the server's database and reported to clients, so they are shared
+//! between both.
+
+use serde::{Deserialize, Serialize};
+
+/// Describes the possible states a derivation build can be in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DrvBuildState {
+    /// Derivation is waiting to be scheduled for building.
+    ///
+    /// The evaluator has determined that this derivation needs be built and has sent it to the
+    /// scheduler. The derivation stays in this state until the scheduler decides that it is ready
+    /// to be built, which mostly means until all its dependencies have been built.
+    Queued,
+    /// Derivation is waiting to be built.
+    ///
+    /// The scheduler has determined that this derivation is ready to be built. The derivation
+    /// stays in this state until a builder picks it up to perform the actual build step.
+    Buildable,
+    /// Derivation is building.
+    ///
+    /// A builder has picked this derivation up and is now realizing the derivation. The derivation
+    /// build stays in this state until the build completes or is interrupted.
+    Building,
+    /// Derivation has been built, either successfully or not.
+    ///
+    /// This is a terminal state, a derivation build will never leave this state. Depending on the
+    /// outcome of the built, the state of other derivation builds may be changed. If the build
+    /// completed successfully, all direct dependants will be marked as buildable. If the build
+    /// failed, all transitive dependants will be marked as transitive failure.
+    Completed(DrvBuildResult),
+    /// Build was interrupted before it could complete.
+    ///
+    /// For some interruption kinds, the build will be retried automatically. In those cases, the
+    /// build will be immediately marked as buildable again. Dependants are not affected.
+    ///
+    /// For most interruption kinds however, an automatic retry makes no sense. A new attempt at
+    /// building the derivation may be queued manually or when the job configuration changed. All
+    /// transitive dependants of this derivation will be marked as blocked, until the next build
+    /// attempt. This derivation build will never leave this state in that case.
+    Interrupted(DrvBuildInterruptionKind),
+    /// At least one transitive dependency of this build has failed.
+    ///
+    /// This is a terminal state, a derivation build will never leave this state.
+    TransitiveFailure,
+    /// At least one transitive dependency of this build has been interrupted.
+    ///
+    /// A failing build of another transitive dependency has a higher precedence than this. The
+    /// transitive failure state therefore takes priority over this state and overwrite it.
+    ///
+    /// Otherwise, the derivation build stays in this state until a later build attempt of the
+    /// dependency completes. Every time a build attempt completes, the scheduler checks if a
+    /// previous build attempt has been interrupted, and if so, unblocks all transitive dependants
+    /// again. Once a derivation build is unblocked, it will be queued again.
+    Blocked,
+}
+
+impl DrvBuildState {
+    /// Whether a build attempt may move from state `from` to state `to`.
+    ///
+    /// `from` is `None` for the first event of a build attempt. This is the state machine
+    /// described on the variants above, written out as a transition table.
+    pub fn is_valid_transition(from: Option<Self>, to: Self) -> bool {
+        use DrvBuildState::*;
+
+        matches!(
+            (from, to),
+            (None, Queued)
+                | (Some(Queued), Buildable | Blocked | TransitiveFailure)
+                | (Some(Buildable), Building)
+                | (Some(Building), Completed(_) | Interrupted(_))
+                | (Some(Interrupted(_)), Buildable)
+                | (Some(Blocked), Queued | TransitiveFailure)
+                | (
+                    Some(Queued | Buildable | Blocked),
+                    Interrupted(DrvBuildInterruptionKind::Cancelled)
+                )
+        )
+    }
+
+    /// Whether the build attempt can never leave this state.
+    ///
+    /// Interrupted builds are not terminal, as some of them are retried automatically.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Completed(_) | Self::TransitiveFailure)
+    }
+}
+
+/// The result of building a derivation.
+///
+/// In essence, this enum captures whether the status code returned by the build command was `0`
+/// or not.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DrvBuildResult {
+    /// The derivation built successfully.
+    Success,
+    /// The derivation failed to build.
+    Failure,
+}
+
+impl DrvBuildResult {
+    /// Handy helper that allows processing the build result in a more functional style using
+    /// [map][Result::map], [map_err][Result::map_err], [map_or_else][Result::map_or_else] and
+    /// the like.
+    #[allow(
+        clippy::result_unit_err,
+        reason = "there is no information to carry on failure"
+    )]
+    pub fn as_result(&self) -> Result<(), ()> {
+        match self {
+            DrvBuildResult::Success => Ok(()),
+            DrvBuildResult::Failure => Err(()),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Success)
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Self::Failure)
+    }
+}
+
+/// Possible causes for why the derivation build was interrupted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DrvBuildInterruptionKind {
+    /// Build process ran out of memory and was killed by the system.
+    OutOfMemory,
+    /// Build process timed out and was killed by the build scheduler.
+    Timeout,
+    /// Scheduler process performed a graceful shutdown and cancelled the derivation build in the
+    /// process, or a maintainer cancelled the build. Maintainers may also cancel builds which did
+    /// not start building yet.
+    Cancelled,
+    /// Build process died for unknown reasons, most likely a fault in the build command.
+    ProcessDeath,
+    /// Scheduler process died. The scheduler can infer that this happend by checking for
+    /// derivation builds which do not have the status [`DrvBuildState::Completed`] whilst
+    /// starting.
+    SchedulerDeath,
+}
+
+#[cfg(feature = "sqlx")]
+mod state {
+    use sqlx::{Decode, Encode, Sqlite, Type};
+
+    use super::{DrvBuildInterruptionKind, DrvBuildResult, DrvBuildState};
+
+    #[derive(sqlx::Type)]
+    #[repr(i8)]
+    enum DrvBuildStateRepr {
+        Queued = 0,
+        Buildable = 1,
+        Building = 7,
+        CompletedSuccess = 42,
+        CompletedFailure = -1,
+        TransitiveFailure = -2,
+        InterruptedOutOfMemory = -104,
+        InterruptedTimeout = -120,
+        InterruptedCancelled = -86,
+        InterruptedProcessDeath = -66,
+        InterruptedSchedulerDeath = -13,
+        Blocked = 100,
+    }
+
+    impl From<&DrvBuildState> for DrvBuildStateRepr {
+        fn from(value: &DrvBuildState) -> Self {
+            match value {
+                DrvBuildState::Queued => Self::Queued,
+                DrvBuildState::Buildable => Self::Buildable,
+                DrvBuildState::Building => Self::Building,
+                DrvBuildState::Completed(DrvBuildResult::Success) => Self::CompletedSuccess,
+                DrvBuildState::Completed(DrvBuildResult::Failure) => Self::CompletedFailure,
+                DrvBuildState::TransitiveFailure => Self::TransitiveFailure,
+                DrvBuildState::Interrupted(DrvBuildInterruptionKind::OutOfMemory) => {
+                    Self::InterruptedOutOfMemory
+                }
+                DrvBuildState::Interrupted(DrvBuildInterruptionKind::Timeout) => {
+                    Self::InterruptedTimeout
+                }
+                DrvBuildState::Interrupted(DrvBuildInterruptionKind::Cancelled) => {
+                    Self::InterruptedCancelled
+                }
+                DrvBuildState::Interrupted(DrvBuildInterruptionKind::ProcessDeath) => {
+                    Self::InterruptedProcessDeath
+                }
+                DrvBuildState::Interrupted(DrvBuildInterruptionKind::SchedulerDeath) => {
+                    Self::InterruptedSchedulerDeath
+                }
+                DrvBuildState::Blocked => Self::Blocked,
+            }
+        }
+    }
+
+    impl From<DrvBuildStateRepr> for DrvBuildState {
+        fn from(value: DrvBuildStateRepr) -> Self {
+            match value {
+                DrvBuildStateRepr::Queued => Self::Queued,
+                DrvBuildStateRepr::Buildable => Self::Buildable,
+                DrvBuildStateRepr::Building => Self::Building,
+                DrvBuildStateRepr::CompletedSuccess => Self::Completed(DrvBuildResult::Success),
+                DrvBuildStateRepr::CompletedFailure => Self::Completed(DrvBuildResult::Failure),
+                DrvBuildStateRepr::TransitiveFailure => Self::TransitiveFailure,
+                DrvBuildStateRepr::InterruptedOutOfMemory => {
+                    Self::Interrupted(DrvBuildInterruptionKind::OutOfMemory)
+                }
+                DrvBuildStateRepr::InterruptedTimeout => {
+                    Self::Interrupted(DrvBuildInterruptionKind::Timeout)
+                }
+                DrvBuildStateRepr::InterruptedCancelled => {
+                    Self::Interrupted(DrvBuildInterruptionKind::Cancelled)
+                }
+                DrvBuildStateRepr::InterruptedProcessDeath => {
+                    Self::Interrupted(DrvBuildInterruptionKind::ProcessDeath)
+                }
+                DrvBuildStateRepr::InterruptedSchedulerDeath => {
+                    Self::Interrupted(DrvBuildInterruptionKind::SchedulerDeath)
+                }
+                DrvBuildStateRepr::Blocked => Self::Blocked,
+            }
+        }
+    }
+
+    impl<'q> Encode<'q, Sqlite> for DrvBuildState {
+        fn encode_by_ref(
+            &self,
+            buf: &mut <Sqlite as sqlx::Database>::ArgumentBuffer<'q>,
+        ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+            <DrvBuildStateRepr as Encode<'q, Sqlite>>::encode_by_ref(&self.into(), buf)
+        }
+
+        fn size_hint(&self) -> usize {
+            <DrvBuildStateRepr as Encode<'q, Sqlite>>::size_hint(&self.into())
+        }
+    }
+
+    impl<'r> Decode<'r, Sqlite> for DrvBuildState {
+        fn decode(
+            value: <Sqlite as sqlx::Database>::ValueRef<'r>,
+        ) -> Result<Self, sqlx::error::BoxDynError> {
+            Ok(<DrvBuildStateRepr as Decode<Sqlite>>::decode(value)?.into())
+        }
+    }
+
+    impl Type<Sqlite> for DrvBuildState {
+        fn type_info() -> <Sqlite as sqlx::Database>::TypeInfo {
+            <DrvBuildStateRepr as Type<Sqlite>>::type_info()
+        }
+
+        fn compatible(ty: &<Sqlite as sqlx::Database>::TypeInfo) -> bool {
+            <DrvBuildStateRepr as Type<Sqlite>>::compatible(ty)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        use DrvBuildState::*;
+
+        let path = [
+            Queued,
+            Blocked,
+            Queued,
+            Buildable,
+            Building,
+            Interrupted(DrvBuildInterruptionKind::OutOfMemory),
+            Buildable,
+            Building,
+            Completed(DrvBuildResult::Success),
+        ];
+        assert!(DrvBuildState::is_valid_transition(None, path[0]));
+        for step in path.windows(2) {
+            assert!(
+                DrvBuildState::is_valid_transition(Some(step[0]), step[1]),
+                "{:?} -> {:?}",
+                step[0],
+                step[1]
+            );
+        }
+
+        // builds have to be queued first and terminal states are never left
+        assert!(!DrvBuildState::is_valid_transition(None, Building));
+        assert!(!DrvBuildState::is_valid_transition(
+            Some(Completed(DrvBuildResult::Failure)),
+            Building
+        ));
+        assert!(!DrvBuildState::is_valid_transition(
+            Some(TransitiveFailure),
+            Queued
+        ));
+        // a dependency failing takes priority over being blocked
+        assert!(!DrvBuildState::is_valid_transition(
+            Some(TransitiveFailure),
+            Blocked
+        ));
+        // only cancellations can interrupt builds before they started
+        assert!(DrvBuildState::is_valid_transition(
+            Some(Queued),
+            Interrupted(DrvBuildInterruptionKind::Cancelled)
+        ));
+        assert!(!DrvBuildState::is_valid_transition(
+            Some(Buildable),
+            Interrupted(DrvBuildInterruptionKind::Timeout)
+        ));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod build;
 pub mod dirs;
+pub mod store;
 pub mod types;
@@ -0,0 +1,352 @@
+//! Validated identifiers for objects in a Nix store.
+//!
+//! Store paths are only passed around without their store directory (see [`DrvId`] for why).
+//! Input coming from users, the socket or Nix itself should be parsed into these types as early
+//! as possible, so that malformed paths never reach the database.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The store directory used by a default Nix installation.
+pub const DEFAULT_STORE_DIR: &str = "/nix/store";
+
+/// Length of the hash part of a store path, in nixbase32 characters.
+const HASH_LEN: usize = 32;
+
+/// Nix limits the name part of a store path to this many bytes.
+const MAX_NAME_LEN: usize = 211;
+
+/// The nixbase32 alphabet, which omits `e`, `o`, `u` and `t` to avoid accidental words.
+const NIXBASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum StorePathError {
+    #[error("`{0}` is too short to be a store path")]
+    TooShort(String),
+    #[error("`{0}` does not start with a 32 character nixbase32 hash")]
+    InvalidHash(String),
+    #[error("`{0}` does not separate hash and name with a `-`")]
+    MissingSeparator(String),
+    #[error("`{0}` has a name longer than {MAX_NAME_LEN} characters")]
+    NameTooLong(String),
+    #[error("`{0}` contains the forbidden character {1:?} in its name")]
+    InvalidNameChar(String, char),
+    #[error("`{0}` has a name starting with a `.`")]
+    HiddenName(String),
+    #[error("`{0}` is not located in the store directory `{1}`")]
+    NotInStore(String, String),
+    #[error("`{0}` is not a derivation, its name does not end in `.drv`")]
+    NotADerivation(String),
+}
+
+/// The base name of a store object of the form `hash-name`.
+///
+/// The hash is 32 characters of nixbase32 and the name is limited to ASCII letters, digits and
+/// `+-._?=`, the same rules Nix itself enforces.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct StorePath(String);
+
+impl StorePath {
+    /// Parse the base name of a store object, i.e. a path without any store directory.
+    pub fn new(base_name: impl Into<String>) -> Result<Self, StorePathError> {
+        let base_name = base_name.into();
+
+        if base_name.len() < HASH_LEN + 2 {
+            return Err(StorePathError::TooShort(base_name));
+        }
+
+        // Check the hash on bytes first, slicing the string could otherwise split a multi-byte char
+        let hash = &base_name.as_bytes()[..HASH_LEN];
+        if !hash.iter().all(|c| NIXBASE32_CHARS.contains(c)) {
+            return Err(StorePathError::InvalidHash(base_name));
+        }
+
+        let Some(name) = base_name[HASH_LEN..].strip_prefix('-') else {
+            return Err(StorePathError::MissingSeparator(base_name));
+        };
+        if name.len() > MAX_NAME_LEN {
+            return Err(StorePathError::NameTooLong(base_name));
+        }
+        if name.starts_with('.') {
+            return Err(StorePathError::HiddenName(base_name));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !"+-._?=".contains(*c))
+        {
+            return Err(StorePathError::InvalidNameChar(base_name, c));
+        }
+
+        Ok(Self(base_name))
+    }
+
+    /// Parse the store object an absolute path inside of `store_dir` belongs to.
+    ///
+    /// The path may point inside of the store object, e.g.
+    /// `/nix/store/<hash>-hello-2.12.1/bin/hello` resolves to `<hash>-hello-2.12.1`.
+    pub fn from_path_in(path: &str, store_dir: &str) -> Result<Self, StorePathError> {
+        let relative = path
+            .strip_prefix(store_dir.trim_end_matches('/'))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(|| StorePathError::NotInStore(path.to_owned(), store_dir.to_owned()))?;
+        let base_name = relative.split('/').next().unwrap_or_default();
+
+        Self::new(base_name)
+    }
+
+    /// The nixbase32 hash part of the store path.
+    pub fn hash_part(&self) -> &str {
+        &self.0[..HASH_LEN]
+    }
+
+    /// The name part of the store path, everything after the hash and the separating `-`.
+    pub fn name(&self) -> &str {
+        &self.0[HASH_LEN + 1..]
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Accepts either a bare `hash-name` or a full path like `/nix/store/hash-name`.
+///
+/// The store directory of a full path is discarded, callers that need to ensure a path belongs
+/// to a specific store have to check that before parsing.
+impl FromStr for StorePath {
+    type Err = StorePathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let base_name = s.rsplit_once('/').map_or(s, |(_, base_name)| base_name);
+        Self::new(base_name)
+    }
+}
+
+impl TryFrom<String> for StorePath {
+    type Error = StorePathError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<StorePath> for String {
+    fn from(value: StorePath) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A derivation identifier of the form `hash-name.drv`.
+///
+/// Many derivations that describe a package (binaries, libraries, ...) additionally include a
+/// version identifier in the name component. For these derivations, the identifier often looks
+/// like `hash-name-version.drv`. This is however only a convention. Many intermediate build
+/// artifacts for example do not have a version.
+///
+/// Each derivation identifier corresponds to a file with the same name located in a nix store. The
+/// filesystem path of the store depends on the evaluator that produced the derivation and is part
+/// of the identifier's hash component[^nix-by-hand]. It is not possible to determine the store
+/// path given only a derivation identifier.
+///
+/// # Examples
+///
+/// Derivation for the hello package, version 2.12.1:
+/// `jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv`
+///
+/// Derivation for the source of an unknown other derivation:
+/// `0aykaqxhbby7mx7lgb217m9b3gkl52fn-source.drv`
+///
+/// [^nix-by-hand]: <https://bernsteinbear.com/blog/nix-by-hand/>
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
+pub struct DrvId(StorePath);
+
+impl DrvId {
+    /// Parse the base name of a derivation, i.e. a path without any store directory.
+    pub fn new(base_name: impl Into<String>) -> Result<Self, StorePathError> {
+        StorePath::new(base_name)?.try_into()
+    }
+
+    /// The derivation name without the `.drv` extension.
+    pub fn name(&self) -> &str {
+        self.0
+            .name()
+            .strip_suffix(".drv")
+            .expect("derivation ids are checked to end in .drv")
+    }
+
+    pub fn as_store_path(&self) -> &StorePath {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl TryFrom<StorePath> for DrvId {
+    type Error = StorePathError;
+
+    fn try_from(value: StorePath) -> Result<Self, Self::Error> {
+        if !value.name().ends_with(".drv") {
+            return Err(StorePathError::NotADerivation(value.0));
+        }
+        Ok(Self(value))
+    }
+}
+
+/// Accepts either a bare `hash-name.drv` or a full path like `/nix/store/hash-name.drv`.
+impl FromStr for DrvId {
+    type Err = StorePathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<StorePath>()?.try_into()
+    }
+}
+
+impl TryFrom<String> for DrvId {
+    type Error = StorePathError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<DrvId> for String {
+    fn from(value: DrvId) -> Self {
+        value.0.into()
+    }
+}
+
+impl fmt::Display for DrvId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Both path types are plain strings on the wire, see their [`Serialize`] implementations.
+#[cfg(feature = "openapi")]
+mod openapi {
+    use utoipa::openapi::{schema::Type, ObjectBuilder, RefOr, Schema};
+    use utoipa::{PartialSchema, ToSchema};
+
+    use super::{DrvId, StorePath};
+
+    impl PartialSchema for StorePath {
+        fn schema() -> RefOr<Schema> {
+            ObjectBuilder::new()
+                .schema_type(Type::String)
+                .description(Some("Base name of a Nix store object, `hash-name`"))
+                .examples(["0aykaqxhbby7mx7lgb217m9b3gkl52fn-hello-2.12.1.tar.gz"])
+                .into()
+        }
+    }
+
+    impl ToSchema for StorePath {}
+
+    impl PartialSchema for DrvId {
+        fn schema() -> RefOr<Schema> {
+            ObjectBuilder::new()
+                .schema_type(Type::String)
+                .description(Some("Base name of a Nix derivation, `hash-name.drv`"))
+                .examples(["jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"])
+                .into()
+        }
+    }
+
+    impl ToSchema for DrvId {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid() {
+        let drv: DrvId = "/nix/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            drv.as_str(),
+            "jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv"
+        );
+        assert_eq!(drv.name(), "hello-2.12.1");
+        assert_eq!(
+            drv.as_store_path().hash_part(),
+            "jd83l3jn2mkn530lgcg0y523jq5qji85"
+        );
+
+        let path = StorePath::new("0aykaqxhbby7mx7lgb217m9b3gkl52fn-source").unwrap();
+        assert_eq!(path.name(), "source");
+
+        let inner = StorePath::from_path_in(
+            "/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1/bin/hello",
+            DEFAULT_STORE_DIR,
+        )
+        .unwrap();
+        assert_eq!(
+            inner.as_str(),
+            "1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1"
+        );
+    }
+
+    #[test]
+    fn reject_invalid() {
+        use StorePathError as E;
+
+        let err = |s: &str| s.parse::<DrvId>().unwrap_err();
+
+        assert!(matches!(err("hello.drv"), E::TooShort(_)));
+        // `e` is not part of the nixbase32 alphabet
+        assert!(matches!(
+            err("ed83l3jn2mkn530lgcg0y523jq5qji85-hello.drv"),
+            E::InvalidHash(_)
+        ));
+        assert!(matches!(
+            err("jd83l3jn2mkn530lgcg0y523jq5qji85_hello.drv"),
+            E::MissingSeparator(_)
+        ));
+        assert!(matches!(
+            err("jd83l3jn2mkn530lgcg0y523jq5qji85-.hello.drv"),
+            E::HiddenName(_)
+        ));
+        assert!(matches!(
+            err("jd83l3jn2mkn530lgcg0y523jq5qji85-hello world.drv"),
+            E::InvalidNameChar(_, ' ')
+        ));
+        assert!(matches!(
+            err("jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1"),
+            E::NotADerivation(_)
+        ));
+        assert!(matches!(
+            StorePath::from_path_in("/tmp/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello", "/nix/store"),
+            Err(E::NotInStore(_, _))
+        ));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let drv: DrvId = serde_json::from_str(
+            r#""/nix/store/jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv""#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&drv).unwrap(),
+            r#""jd83l3jn2mkn530lgcg0y523jq5qji85-hello-2.12.1.drv""#
+        );
+
+        assert!(serde_json::from_str::<DrvId>(r#""; DROP TABLE Drv""#).is_err());
+    }
+}
@@ -2,18 +2,127 @@ use clap::Parser;
 use serde;
 use serde::{Deserialize, Serialize};
 
+use crate::build::DrvBuildState;
+use crate::store::DrvId;
+
+/// Version of the socket protocol spoken by this crate.
+///
+/// * 0: a single bare request per connection, answered once the client shut down writing
+/// * 1: [`RequestFrame`]s and [`ResponseFrame`]s
+/// * 2: the [`ClientRequest::Hello`] handshake
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version that is still spoken. Servers additionally answer clients speaking
+/// protocol 0.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional functionality, a client should only make requests of a feature the server announced
+/// in its [`HelloResponse`].
+pub mod features {
+    /// Restarting, cancelling and invalidating builds
+    pub const ADMIN: &str = "admin";
+    /// Streaming build logs
+    pub const LOG: &str = "log";
+    /// Summary of builds and evaluations
+    pub const STATUS: &str = "status";
+    /// Following builds and evaluations
+    pub const WATCH: &str = "watch";
+    /// Explaining transitive failures
+    pub const WHY_FAILED: &str = "why-failed";
+
+    /// Everything this crate version implements.
+    pub const ALL: &[&str] = &[ADMIN, LOG, STATUS, WATCH, WHY_FAILED];
+}
+
+/// A request sent over the unix socket, or on its own to `POST /v1/requests` of the web API.
+///
+/// Each frame is a single line of JSON. A client may send further requests before the responses
+/// to earlier ones arrived, the responses carry the `id` of the request they belong to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestFrame {
+    /// Chosen by the client, should be unique among its requests in flight on a connection.
+    pub id: u64,
+    pub request: ClientRequest,
+}
+
+/// A response sent over the unix socket, framed like [`RequestFrame`].
+///
+/// Most requests are answered by a single frame. Streamed responses (e.g. a followed log) consist
+/// of several frames, all but the last of which have `more` set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseFrame {
+    /// The request this frame answers. Missing if the request was too malformed to tell.
+    pub id: Option<u64>,
+    /// Further frames for the same request follow.
+    #[serde(default)]
+    pub more: bool,
+    pub response: ClientResponse,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum ClientRequest {
+    /// Exchange versions, should be the first request on a connection.
+    Hello(HelloRequest),
     Info,
+    Status,
     Build(BuildRequest),
     Job(JobRequest),
+    Restart(DrvRequest),
+    Cancel(DrvRequest),
+    Invalidate(InvalidateRequest),
+    RestartFailures(RestartFailuresRequest),
+    Log(LogRequest),
+    Watch(WatchRequest),
+    WhyFailed(WhyFailedRequest),
 }
 
+impl ClientRequest {
+    /// The feature the server has to support for this request, see [`features`].
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            ClientRequest::Hello(_)
+            | ClientRequest::Info
+            | ClientRequest::Build(_)
+            | ClientRequest::Job(_) => None,
+            ClientRequest::Restart(_)
+            | ClientRequest::Cancel(_)
+            | ClientRequest::Invalidate(_)
+            | ClientRequest::RestartFailures(_) => Some(features::ADMIN),
+            ClientRequest::Log(_) => Some(features::LOG),
+            ClientRequest::Status => Some(features::STATUS),
+            ClientRequest::Watch(_) => Some(features::WATCH),
+            ClientRequest::WhyFailed(_) => Some(features::WHY_FAILED),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloRequest {
+    /// Newest protocol version the client speaks
+    pub protocol: u32,
+    /// Crate version of the client
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+/// Accepts the client, which should then speak the lower of both protocol versions.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct HelloResponse {
+    /// Newest protocol version the server speaks
+    pub protocol: u32,
+    /// Crate version of the server
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+/// Ordered from best to worst, so that the overall status is the maximum of all components.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ServerStatus {
     Active,
+    /// Still serving requests, but some functionality is impaired.
     Degraded,
+    /// A subsystem the server can not work without has failed.
     Dead,
 }
 
@@ -21,33 +130,299 @@ pub enum ServerStatus {
 pub struct InfoResponse {
     pub status: ServerStatus,
     pub version: String,
+    /// Status of each subsystem, `status` is the worst of them.
+    #[serde(default)]
+    pub components: Vec<ComponentStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ComponentStatus {
+    pub name: String,
+    pub status: ServerStatus,
+    /// Human readable explanation of the status.
+    pub detail: String,
+}
+
+/// What the server is busy with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusResponse {
+    /// Derivations by the state of their latest build attempt, never scheduled ones are left out.
+    pub states: Vec<StateCount>,
+    /// Longest running first.
+    pub running: Vec<RunningBuild>,
+    /// Evaluation tasks waiting for the evaluator.
+    pub eval_queue: usize,
+    /// Most recently started first.
+    pub evaluations: Vec<EvaluationSummary>,
+    /// Builders do not register with the server, so their capacity is inferred from the builds.
+    pub builders: ComponentStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateCount {
+    pub state: DrvBuildState,
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunningBuild {
+    pub drv_path: DrvId,
+    pub build_attempt: u32,
+    pub system: Option<String>,
+    /// Seconds since the build started.
+    pub elapsed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EvaluationSummary {
+    pub id: i64,
+    pub jobset: String,
+    pub pr: Option<u64>,
+    /// Unix timestamp in seconds
+    pub queued: i64,
+    /// Seconds the evaluation took, `None` until it finished.
+    pub duration: Option<u64>,
+    pub outcome: EvaluationOutcome,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status")]
+pub enum EvaluationOutcome {
+    /// Waiting for the evaluator.
+    Queued,
+    Running,
+    Succeeded {
+        drvs: u64,
+    },
+    Failed {
+        error: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum ClientResponse {
+    Hello(HelloResponse),
     Info(InfoResponse),
+    Status(StatusResponse),
     Build(BuildResponse),
     Job(JobResponse),
+    Admin(AdminResponse),
+    Log(LogResponse),
+    Watch(WatchEvent),
+    WhyFailed(WhyFailedResponse),
+    /// The server could not process the request.
+    Error(ErrorResponse),
 }
 
 #[derive(Serialize, Parser, Deserialize, Debug)]
 pub struct BuildRequest {
-    pub drv_path: String,
+    /// Derivation to build, either as a store path or as a bare `hash-name.drv`
+    pub drv_path: DrvId,
 }
 
-#[derive(Serialize, Parser, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct BuildResponse {
-    pub enqueued: bool,
+    pub drv_path: DrvId,
+    /// Latest build attempt, `None` if the derivation was never scheduled for building.
+    pub build_attempt: Option<u32>,
+    /// State of the latest build attempt. A final state means the derivation will not be built
+    /// again, unless a maintainer restarts it.
+    pub state: Option<DrvBuildState>,
+    /// Web page of the derivation
+    pub url: String,
 }
 
 #[derive(Serialize, Parser, Deserialize, Debug)]
 pub struct JobRequest {
     pub file_path: String,
+
+    /// Pull request the job is evaluated for
+    #[arg(long)]
+    #[serde(default)]
+    pub pr: Option<u64>,
 }
 
-// TODO: We should probably just have a generic async "event received" response
-#[derive(Serialize, Parser, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct JobResponse {
-    pub enqueued: bool,
+    /// Id of the queued evaluation, can be passed to [`WatchRequest::evaluation`].
+    pub evaluation: i64,
+    pub file_path: String,
+    pub pr: Option<u64>,
+    /// Web page of the evaluation
+    pub url: String,
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct DrvRequest {
+    /// Derivation, either as a store path or as a bare `hash-name.drv`
+    pub drv_path: DrvId,
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct InvalidateRequest {
+    /// Derivation, either as a store path or as a bare `hash-name.drv`
+    pub drv_path: DrvId,
+
+    /// Why the result of the latest build can not be trusted
+    #[arg(long)]
+    pub reason: String,
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct RestartFailuresRequest {
+    /// Id of the evaluation whose failed builds should be restarted
+    pub evaluation: i64,
+}
+
+/// Build attempts changed by an administrative request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminResponse {
+    pub builds: Vec<BuildStateChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BuildStateChange {
+    pub drv_path: DrvId,
+    pub build_attempt: u32,
+    /// The state the build attempt is in now
+    pub state: DrvBuildState,
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct LogRequest {
+    /// Derivation, either as a store path or as a bare `hash-name.drv`
+    pub drv_path: DrvId,
+
+    /// Keep printing new lines until the running build finishes
+    #[arg(short, long)]
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// A chunk of log lines, logs are streamed in several of them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogResponse {
+    pub lines: Vec<String>,
+    /// Lines that were dropped before this chunk, because the client did not keep up.
+    #[serde(default)]
+    pub skipped: u64,
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct WatchRequest {
+    /// Derivation to watch, either as a store path or as a bare `hash-name.drv`
+    #[arg(required_unless_present = "job")]
+    pub drv_path: Option<DrvId>,
+
+    /// Watch the evaluation of this jobset and the builds of the derivations it exposes instead
+    #[arg(long, conflicts_with = "drv_path")]
+    #[serde(default)]
+    pub job: Option<String>,
+
+    /// Pull request the job is evaluated for
+    #[arg(long, requires = "job")]
+    #[serde(default)]
+    pub pr: Option<u64>,
+
+    /// Follow this evaluation of the job, instead of the latest one
+    #[arg(long, requires = "job")]
+    #[serde(default)]
+    pub evaluation: Option<i64>,
+
+    /// Also print the build log of the derivation while it builds
+    #[arg(long, requires = "drv_path")]
+    #[serde(default)]
+    pub log: bool,
+}
+
+/// Something happened to a watched derivation or job. The last event of a watch is always
+/// [`WatchEvent::Done`].
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event")]
+pub enum WatchEvent {
+    /// A build changed its state. Sent first with the current state, if there is one.
+    Build(BuildStateChange),
+    /// The watched job was evaluated. Sent first with the latest evaluation, if there is one.
+    Evaluation(EvaluationOutcome),
+    /// Lines of the build log, if requested.
+    Log(LogResponse),
+    Done(BuildOutcome),
+}
+
+/// How a build, or all builds of a job, ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "outcome")]
+pub enum BuildOutcome {
+    Succeeded,
+    /// A build, or the evaluation of the job, failed.
+    Failed,
+    /// A build was interrupted, and may be retried automatically or manually.
+    Interrupted,
+}
+
+impl BuildOutcome {
+    /// The outcome of a build attempt in `state`, `None` while it is still in progress.
+    ///
+    /// Interrupted attempts count as ended, although they may become buildable again: they are
+    /// only retried when restarted, which watchers do not wait for.
+    pub fn of(state: DrvBuildState) -> Option<Self> {
+        match state {
+            DrvBuildState::Completed(result) if result.is_success() => Some(Self::Succeeded),
+            DrvBuildState::Completed(_) | DrvBuildState::TransitiveFailure => Some(Self::Failed),
+            DrvBuildState::Interrupted(_) => Some(Self::Interrupted),
+            DrvBuildState::Queued
+            | DrvBuildState::Buildable
+            | DrvBuildState::Building
+            | DrvBuildState::Blocked => None,
+        }
+    }
+}
+
+#[derive(Serialize, Parser, Deserialize, Debug)]
+pub struct WhyFailedRequest {
+    /// Derivation to explain, either as a store path or as a bare `hash-name.drv`
+    #[arg(required_unless_present = "attr")]
+    pub drv_path: Option<DrvId>,
+
+    /// Explain the derivation exposed as this attribute of a jobset instead
+    #[arg(long, conflicts_with = "drv_path")]
+    #[serde(default)]
+    pub attr: Option<String>,
+
+    /// Number of log lines shown for each failed dependency
+    #[arg(short = 'n', long, default_value_t = DEFAULT_LOG_TAIL)]
+    #[serde(default = "default_log_tail")]
+    pub lines: usize,
+}
+
+/// Log lines shown for each root failure, unless requested otherwise.
+pub const DEFAULT_LOG_TAIL: usize = 20;
+
+fn default_log_tail() -> usize {
+    DEFAULT_LOG_TAIL
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WhyFailedResponse {
+    pub drv_path: DrvId,
+    /// `None` if the derivation was never scheduled for building.
+    pub state: Option<DrvBuildState>,
+    /// The dependencies that failed or were interrupted on their own. Empty unless the
+    /// derivation failed, either directly or transitively, or is blocked.
+    pub root_failures: Vec<RootFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RootFailure {
+    /// Derivations from the explained one down to the failed one, both included.
+    pub chain: Vec<DrvId>,
+    pub state: DrvBuildState,
+    /// The last lines of the build log, empty if there is none.
+    pub log_tail: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub message: String,
 }